@@ -0,0 +1,176 @@
+use std::mem;
+use std::ops::BitOr;
+use std::os::unix::io::AsRawFd;
+use std::slice;
+
+use drm::control::{connector, crtc, framebuffer, Mode};
+use drm::ffi;
+
+use device::Gpu;
+use error::{Error, Result};
+use mode;
+use plane::PlaneType;
+use property;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitFlags(u32);
+
+impl CommitFlags {
+    pub const NONE: CommitFlags = CommitFlags(0);
+    pub const PAGE_FLIP_EVENT: CommitFlags = CommitFlags(ffi::DRM_MODE_PAGE_FLIP_EVENT);
+    pub const NONBLOCK: CommitFlags = CommitFlags(ffi::DRM_MODE_ATOMIC_NONBLOCK);
+    pub const ALLOW_MODESET: CommitFlags = CommitFlags(ffi::DRM_MODE_ATOMIC_ALLOW_MODESET);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl BitOr for CommitFlags {
+    type Output = CommitFlags;
+
+    fn bitor(self, other: CommitFlags) -> CommitFlags {
+        CommitFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AtomicRequest {
+    objects: Vec<(u32, Vec<(u32, u64)>)>,
+}
+
+impl AtomicRequest {
+    pub fn new() -> AtomicRequest {
+        AtomicRequest::default()
+    }
+
+    pub fn add_property<H: Into<u32>>(&mut self, object: H, property: u32, value: u64) {
+        let object = object.into();
+
+        let index = match self.objects.iter().position(|&(obj, _)| obj == object) {
+            Some(index) => index,
+            None => {
+                self.objects.push((object, Vec::new()));
+                self.objects.len() - 1
+            }
+        };
+
+        let properties = &mut self.objects[index].1;
+        match properties.iter_mut().find(|&&mut (prop, _)| prop == property) {
+            Some(entry) => entry.1 = value,
+            None => properties.push((property, value)),
+        }
+    }
+
+    // (object, property, value) in the order they were added
+    pub fn properties<'a>(&'a self) -> impl Iterator<Item = (u32, u32, u64)> + 'a {
+        self.objects.iter().flat_map(|&(obj, ref props)| props.iter().map(move |&(prop, value)| (obj, prop, value)))
+    }
+
+    pub fn commit<D: AsRawFd>(&self, device: &D, flags: CommitFlags, user_data: u64) -> Result<()> {
+        let objects = self.objects.iter().map(|&(obj, _)| obj).collect::<Vec<_>>();
+        let counts = self.objects.iter().map(|(_, props)| props.len() as u32).collect::<Vec<_>>();
+        let properties = self.properties().map(|(_, prop, _)| prop).collect::<Vec<_>>();
+        let values = self.properties().map(|(_, _, value)| value).collect::<Vec<_>>();
+
+        let mut raw = ffi::drm_mode_atomic {
+            flags: flags.bits(),
+            count_objs: objects.len() as u32,
+            objs_ptr: objects.as_ptr() as u64,
+            count_props_ptr: counts.as_ptr() as u64,
+            props_ptr: properties.as_ptr() as u64,
+            prop_values_ptr: values.as_ptr() as u64,
+            user_data,
+            ..Default::default()
+        };
+
+        unsafe { ffi::ioctl_mode_atomic(device.as_raw_fd(), &mut raw) }
+            .map_err(|err| Error::ioctl("atomic commit", objects.first().cloned().unwrap_or(0), err))?;
+
+        Ok(())
+    }
+}
+
+pub fn create_mode_blob<D: AsRawFd>(device: &D, mode: &Mode) -> Result<u32> {
+    let raw = mode::as_raw(mode);
+    let data = unsafe {
+        slice::from_raw_parts(&raw as *const ffi::drm_mode_modeinfo as *const u8, mem::size_of::<ffi::drm_mode_modeinfo>())
+    };
+
+    property::create_blob(device, data)
+}
+
+pub fn modeset_request(gpu: &Gpu, crtc: crtc::Handle, connectors: &[connector::Handle], fb: framebuffer::Handle, mode: &Mode, mode_blob: u32) -> Result<AtomicRequest> {
+    let mut request = AtomicRequest::new();
+
+    for &connector in connectors {
+        let crtc_id = required_property(gpu, connector.into(), ffi::DRM_MODE_OBJECT_CONNECTOR, "CRTC_ID")?;
+        request.add_property(connector, crtc_id, u32::from(crtc) as u64);
+    }
+
+    let mode_id = required_property(gpu, crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "MODE_ID")?;
+    let active = required_property(gpu, crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "ACTIVE")?;
+    request.add_property(crtc, mode_id, mode_blob as u64);
+    request.add_property(crtc, active, 1);
+
+    let (width, height) = mode.size();
+    add_plane_properties(gpu, &mut request, crtc, fb, (width as u32, height as u32))?;
+
+    Ok(request)
+}
+
+pub fn page_flip_request(gpu: &Gpu, crtc: crtc::Handle, fb: framebuffer::Handle) -> Result<AtomicRequest> {
+    let plane = primary_plane(gpu, crtc)?;
+    let fb_id = required_property(gpu, plane, ffi::DRM_MODE_OBJECT_PLANE, "FB_ID")?;
+
+    let mut request = AtomicRequest::new();
+    request.add_property(plane, fb_id, u32::from(fb) as u64);
+    Ok(request)
+}
+
+fn add_plane_properties(gpu: &Gpu, request: &mut AtomicRequest, crtc: crtc::Handle, fb: framebuffer::Handle, size: (u32, u32)) -> Result<()> {
+    let plane = primary_plane(gpu, crtc)?;
+    let (width, height) = size;
+    add_plane_state(gpu, request, plane, Some((crtc, fb)), size, (0, 0, width, height))
+}
+
+// Points the plane at fb on crtc, or detaches it when binding is None. Source
+// size is in pixels and destination is (x, y, width, height) on the crtc.
+pub fn add_plane_state(gpu: &Gpu, request: &mut AtomicRequest, plane: u32, binding: Option<(crtc::Handle, framebuffer::Handle)>, src: (u32, u32), dst: (i32, i32, u32, u32)) -> Result<()> {
+    let (crtc_id, fb_id) = match binding {
+        Some((crtc, fb)) => (u32::from(crtc) as u64, u32::from(fb) as u64),
+        None => (0, 0),
+    };
+
+    // source coordinates are in 16.16 fixed point
+    let values = [
+        ("FB_ID", fb_id),
+        ("CRTC_ID", crtc_id),
+        ("SRC_X", 0),
+        ("SRC_Y", 0),
+        ("SRC_W", (src.0 as u64) << 16),
+        ("SRC_H", (src.1 as u64) << 16),
+        ("CRTC_X", dst.0 as i64 as u64),
+        ("CRTC_Y", dst.1 as i64 as u64),
+        ("CRTC_W", dst.2 as u64),
+        ("CRTC_H", dst.3 as u64),
+    ];
+
+    for &(name, value) in values.iter() {
+        let prop = required_property(gpu, plane, ffi::DRM_MODE_OBJECT_PLANE, name)?;
+        request.add_property(plane, prop, value);
+    }
+
+    Ok(())
+}
+
+pub fn required_property(gpu: &Gpu, object: u32, object_type: u32, name: &'static str) -> Result<u32> {
+    gpu.property_id(object, object_type, name)?
+        .ok_or_else(|| Error::MissingProperty { object, name: name.to_owned() })
+}
+
+fn primary_plane(gpu: &Gpu, crtc: crtc::Handle) -> Result<u32> {
+    gpu.find_plane(crtc, PlaneType::Primary)
+        .map(|plane| plane.handle.into())
+        .ok_or(Error::MissingPlane { crtc: crtc.into() })
+}
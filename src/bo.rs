@@ -0,0 +1,162 @@
+use std::io;
+
+use gbm::{self, AsRaw};
+use libc::{c_int, c_uint, c_void};
+
+use device::Gpu;
+use error::{Error, Result};
+
+// DRM_FORMAT_MOD_INVALID, what gbm reports for buffers without an explicit modifier
+pub const MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+pub const MOD_LINEAR: u64 = 0;
+
+// The gbm crate wraps neither surfaces allocated from a modifier list nor the
+// per-plane layout of buffer objects
+#[link(name = "gbm")]
+extern "C" {
+    fn gbm_surface_create(device: *mut c_void, width: u32, height: u32, format: u32, flags: u32) -> *mut c_void;
+    fn gbm_surface_create_with_modifiers(device: *mut c_void, width: u32, height: u32, format: u32, modifiers: *const u64, count: c_uint) -> *mut c_void;
+    fn gbm_surface_destroy(surface: *mut c_void);
+    fn gbm_surface_lock_front_buffer(surface: *mut c_void) -> *mut c_void;
+    fn gbm_surface_release_buffer(surface: *mut c_void, bo: *mut c_void);
+    fn gbm_bo_get_width(bo: *mut c_void) -> u32;
+    fn gbm_bo_get_height(bo: *mut c_void) -> u32;
+    fn gbm_bo_get_format(bo: *mut c_void) -> u32;
+    fn gbm_bo_get_modifier(bo: *mut c_void) -> u64;
+    fn gbm_bo_get_plane_count(bo: *mut c_void) -> c_int;
+    fn gbm_bo_get_handle_for_plane(bo: *mut c_void, plane: c_int) -> u64;
+    fn gbm_bo_get_stride_for_plane(bo: *mut c_void, plane: c_int) -> u32;
+    fn gbm_bo_get_offset(bo: *mut c_void, plane: c_int) -> u32;
+}
+
+// Everything ADDFB2 needs to know about a buffer, planes past planes() are zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferLayout {
+    pub width: u32,
+    pub height: u32,
+    pub format: u32,
+    pub modifier: u64,
+    pub handles: [u32; 4],
+    pub pitches: [u32; 4],
+    pub offsets: [u32; 4],
+}
+
+impl BufferLayout {
+    pub fn planes(&self) -> usize {
+        self.handles.iter().take_while(|&&handle| handle != 0).count()
+    }
+
+    pub fn has_modifier(&self) -> bool {
+        self.modifier != MOD_INVALID
+    }
+}
+
+// gbm_surface the EGL window surface renders into. Has to go before the gbm
+// device it was made from.
+pub struct GbmSurface {
+    raw: *mut c_void,
+    pub format: gbm::Format,
+}
+
+impl GbmSurface {
+    // gbm picks the modifier it renders best to out of the list, an empty list
+    // or a driver without ADDFB2 modifiers gets the implicit layout
+    pub fn new(gpu: &Gpu, width: u32, height: u32, format: gbm::Format, modifiers: &[u64]) -> Result<GbmSurface> {
+        let device = gpu.gbm_device()?.as_raw() as *mut c_void;
+        let modifiers = if gpu.caps.addfb2_modifiers { modifiers } else { &[] };
+
+        if !modifiers.is_empty() {
+            let raw = unsafe {
+                gbm_surface_create_with_modifiers(device, width, height, format.as_ffi(), modifiers.as_ptr(), modifiers.len() as c_uint)
+            };
+            if !raw.is_null() {
+                return Ok(GbmSurface { raw, format });
+            }
+            eprintln!("{}, using the implicit layout", Error::gbm("create surface with modifiers", io::Error::last_os_error()));
+        }
+
+        let flags = gbm::BufferObjectFlags::SCANOUT | gbm::BufferObjectFlags::RENDERING;
+        let raw = unsafe { gbm_surface_create(device, width, height, format.as_ffi(), flags.bits()) };
+        if raw.is_null() {
+            return Err(Error::gbm("create surface", io::Error::last_os_error()));
+        }
+
+        Ok(GbmSurface { raw, format })
+    }
+
+    pub fn as_raw(&self) -> *mut c_void {
+        self.raw
+    }
+
+    // Only valid after eglSwapBuffers, at most a few can be locked at once
+    pub fn lock_front_buffer(&self) -> Result<LockedBuffer> {
+        let bo = unsafe { gbm_surface_lock_front_buffer(self.raw) };
+        if bo.is_null() {
+            return Err(Error::LockFrontBuffer);
+        }
+
+        Ok(LockedBuffer { surface: self.raw, bo })
+    }
+}
+
+impl Drop for GbmSurface {
+    fn drop(&mut self) {
+        unsafe { gbm_surface_destroy(self.raw) };
+    }
+}
+
+// Goes back to the surface for reuse when dropped, has to be dropped before it
+pub struct LockedBuffer {
+    surface: *mut c_void,
+    bo: *mut c_void,
+}
+
+impl LockedBuffer {
+    // The surface cycles through the same few buffers, this tells them apart
+    pub fn id(&self) -> usize {
+        self.bo as usize
+    }
+
+    pub fn layout(&self) -> BufferLayout {
+        layout(self.bo)
+    }
+}
+
+impl Drop for LockedBuffer {
+    fn drop(&mut self) {
+        unsafe { gbm_surface_release_buffer(self.surface, self.bo) };
+    }
+}
+
+// Handles in the layout belong to the device the buffer object was made on
+pub fn bo_layout<T: 'static>(bo: &gbm::BufferObject<T>) -> BufferLayout {
+    layout(bo.as_raw() as *mut c_void)
+}
+
+fn layout(bo: *mut c_void) -> BufferLayout {
+    let mut layout = unsafe {
+        BufferLayout {
+            width: gbm_bo_get_width(bo),
+            height: gbm_bo_get_height(bo),
+            format: gbm_bo_get_format(bo),
+            modifier: gbm_bo_get_modifier(bo),
+            handles: [0; 4],
+            pitches: [0; 4],
+            offsets: [0; 4],
+        }
+    };
+
+    let planes = unsafe { gbm_bo_get_plane_count(bo) }.clamp(1, 4);
+    for plane in 0..planes {
+        let index = plane as usize;
+        unsafe {
+            // gbm_bo_handle is a union, the GEM handle sits in its low 32 bits
+            layout.handles[index] = gbm_bo_get_handle_for_plane(bo, plane) as u32;
+            layout.pitches[index] = gbm_bo_get_stride_for_plane(bo, plane);
+            layout.offsets[index] = gbm_bo_get_offset(bo, plane);
+        }
+    }
+
+    layout
+}
+
@@ -0,0 +1,129 @@
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use drm::ffi;
+use libc;
+
+use error::{Error, Result};
+
+// Not in drm-sys yet, see include/uapi/drm/drm.h
+const DRM_CAP_CRTC_IN_VBLANK_EVENT: u32 = 0x12;
+
+#[derive(Debug, Clone, Default)]
+pub struct DriverVersion {
+    pub name: String,
+    pub major: i32,
+    pub minor: i32,
+    pub patchlevel: i32,
+    pub date: String,
+    pub description: String,
+}
+
+// What the driver reported when the device was opened. Anything the kernel
+// refused to answer counts as unsupported.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub dumb_buffer: bool,
+    pub prime_import: bool,
+    pub prime_export: bool,
+    pub async_page_flip: bool,
+    pub addfb2_modifiers: bool,
+    pub cursor_size: (u32, u32),
+    // flip events carry the crtc id, older kernels leave it 0
+    pub crtc_in_vblank_event: bool,
+    // flip timestamps are CLOCK_MONOTONIC rather than wall clock time
+    pub timestamp_monotonic: bool,
+    pub driver: DriverVersion,
+}
+
+impl Capabilities {
+    pub fn query<D: AsRawFd>(device: &D) -> Capabilities {
+        let flag = |capability| get_cap(device, capability).map(|value| value != 0).unwrap_or(false);
+        let prime = get_cap(device, ffi::DRM_CAP_PRIME).unwrap_or(0);
+
+        // drivers that don't report a size take the classic 64x64 cursor
+        let cursor_width = get_cap(device, ffi::DRM_CAP_CURSOR_WIDTH).unwrap_or(64);
+        let cursor_height = get_cap(device, ffi::DRM_CAP_CURSOR_HEIGHT).unwrap_or(64);
+
+        let driver = driver_version(device).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            DriverVersion::default()
+        });
+
+        Capabilities {
+            dumb_buffer: flag(ffi::DRM_CAP_DUMB_BUFFER),
+            prime_import: prime & u64::from(ffi::DRM_PRIME_CAP_IMPORT) != 0,
+            prime_export: prime & u64::from(ffi::DRM_PRIME_CAP_EXPORT) != 0,
+            async_page_flip: flag(ffi::DRM_CAP_ASYNC_PAGE_FLIP),
+            addfb2_modifiers: flag(ffi::DRM_CAP_ADDFB2_MODIFIERS),
+            cursor_size: (cursor_width as u32, cursor_height as u32),
+            crtc_in_vblank_event: flag(DRM_CAP_CRTC_IN_VBLANK_EVENT),
+            timestamp_monotonic: flag(ffi::DRM_CAP_TIMESTAMP_MONOTONIC),
+            driver,
+        }
+    }
+}
+
+pub fn get_cap<D: AsRawFd>(device: &D, capability: u32) -> Result<u64> {
+    let mut raw = ffi::drm_get_cap { capability: capability as u64, ..Default::default() };
+
+    unsafe { ffi::ioctl_get_cap(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get cap", capability, err))?;
+
+    Ok(raw.value)
+}
+
+// First call gets the string lengths, the second one fills the buffers
+pub fn driver_version<D: AsRawFd>(device: &D) -> Result<DriverVersion> {
+    let mut raw: ffi::drm_version = Default::default();
+    unsafe { ffi::ioctl_version(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get version", 0u32, err))?;
+
+    let mut name = vec![0u8; raw.name_len as usize];
+    let mut date = vec![0u8; raw.date_len as usize];
+    let mut description = vec![0u8; raw.desc_len as usize];
+    raw.name = buffer_ptr(&mut name);
+    raw.date = buffer_ptr(&mut date);
+    raw.desc = buffer_ptr(&mut description);
+
+    unsafe { ffi::ioctl_version(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get version", 0u32, err))?;
+
+    Ok(DriverVersion {
+        name: to_string(name, raw.name_len as usize),
+        major: raw.version_major,
+        minor: raw.version_minor,
+        patchlevel: raw.version_patchlevel,
+        date: to_string(date, raw.date_len as usize),
+        description: to_string(description, raw.desc_len as usize),
+    })
+}
+
+fn buffer_ptr(buffer: &mut [u8]) -> *mut libc::c_char {
+    if buffer.is_empty() { ptr::null_mut() } else { buffer.as_mut_ptr() as *mut _ }
+}
+
+// The kernel reports the full length even when it had to cut the string short
+fn to_string(mut buffer: Vec<u8>, len: usize) -> String {
+    buffer.truncate(len);
+    if let Some(nul) = buffer.iter().position(|&b| b == 0) {
+        buffer.truncate(nul);
+    }
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_string;
+
+    #[test]
+    fn version_strings_stop_at_the_reported_length_or_nul() {
+        assert_eq!(to_string(b"i915".to_vec(), 4), "i915");
+        // the kernel grew the name between the two calls
+        assert_eq!(to_string(b"amdg".to_vec(), 6), "amdg");
+        assert_eq!(to_string(b"nouveau".to_vec(), 3), "nou");
+        assert_eq!(to_string(b"vc4\0\0\0".to_vec(), 6), "vc4");
+        assert_eq!(to_string(Vec::new(), 0), "");
+        assert_eq!(to_string(vec![b'v', 0xff], 2), "v\u{fffd}");
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use drm::control::Mode;
+
+use mode;
+
+// Flip timing of one crtc. Fed with the CLOCK_MONOTONIC time a flip was
+// queued at and the timestamp its event came back with.
+#[derive(Debug, Clone)]
+pub struct FrameClock {
+    refresh_interval: Duration,
+    vrr: bool,
+    last_flip: Option<Duration>,
+    frame_interval: Option<Duration>,
+    // where the pending flip should land
+    target: Option<Duration>,
+    missed: bool,
+}
+
+impl FrameClock {
+    pub fn new(mode: &Mode) -> FrameClock {
+        let refresh = u64::from(mode::refresh_mhz(mode).max(1));
+        FrameClock {
+            refresh_interval: Duration::from_nanos(1_000_000_000_000 / refresh),
+            vrr: false,
+            last_flip: None,
+            frame_interval: None,
+            target: None,
+            missed: false,
+        }
+    }
+
+    pub fn set_vrr(&mut self, enabled: bool) {
+        self.vrr = enabled;
+    }
+
+    pub fn vrr_enabled(&self) -> bool {
+        self.vrr
+    }
+
+    // Scanout period of the mode. With VRR on it's only the shortest period
+    // the panel can do, frames may come further apart.
+    pub fn refresh_interval(&self) -> Duration {
+        self.refresh_interval
+    }
+
+    // Time between the last two flips, what the display really ran at
+    pub fn frame_interval(&self) -> Option<Duration> {
+        self.frame_interval
+    }
+
+    // Whether the last flip landed a vblank or more after the one it was queued for
+    pub fn missed_vblank(&self) -> bool {
+        self.missed
+    }
+
+    // Earliest time a flip queued now can hit the screen. Fixed refresh
+    // waits for the next vblank on the mode's grid, VRR starts scanning out
+    // right away unless that would exceed the maximum refresh.
+    pub fn next_flip_time(&self, now: Duration) -> Duration {
+        let last = match self.last_flip {
+            Some(last) => last,
+            None => return now,
+        };
+        let period = self.refresh_interval;
+        let earliest = last + period;
+
+        if self.vrr || now <= earliest {
+            return earliest.max(now);
+        }
+
+        let periods = ((now - last).as_nanos() / period.as_nanos().max(1) + 1) as u32;
+        last + period * periods
+    }
+
+    pub fn flip_queued(&mut self, now: Duration) {
+        self.target = Some(self.next_flip_time(now));
+    }
+
+    pub fn flip_complete(&mut self, timestamp: Duration) {
+        // vblank timestamps jitter a bit, half a period late is a whole one
+        self.missed = match self.target.take() {
+            Some(target) => timestamp > target + self.refresh_interval / 2,
+            None => false,
+        };
+
+        self.frame_interval = self.last_flip.and_then(|last| timestamp.checked_sub(last));
+        self.last_flip = Some(timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use modeline;
+    use super::FrameClock;
+
+    fn clock() -> FrameClock {
+        FrameClock::new(&modeline::cvt_reduced(1920, 1080, 60.0).unwrap())
+    }
+
+    #[test]
+    fn first_flip_goes_out_right_away() {
+        let clock = clock();
+        assert_eq!(clock.next_flip_time(Duration::from_secs(5)), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn fixed_refresh_flips_stay_on_the_vblank_grid() {
+        let mut clock = clock();
+        let period = clock.refresh_interval();
+        let start = Duration::from_secs(1);
+        clock.flip_complete(start);
+
+        // queued early, the next vblank is a period after the last flip
+        assert_eq!(clock.next_flip_time(start + period / 4), start + period);
+        // queued late, it waits for the vblank after now
+        assert_eq!(clock.next_flip_time(start + period * 5 / 2), start + period * 3);
+    }
+
+    #[test]
+    fn vrr_flips_go_out_when_queued() {
+        let mut clock = clock();
+        clock.set_vrr(true);
+        let period = clock.refresh_interval();
+        let start = Duration::from_secs(1);
+        clock.flip_complete(start);
+
+        // no faster than the panel's maximum refresh though
+        assert_eq!(clock.next_flip_time(start + period / 4), start + period);
+        assert_eq!(clock.next_flip_time(start + period * 5 / 2), start + period * 5 / 2);
+    }
+
+    #[test]
+    fn late_flips_count_as_missed() {
+        let mut clock = clock();
+        let period = clock.refresh_interval();
+        let start = Duration::from_secs(1);
+        clock.flip_complete(start);
+
+        clock.flip_queued(start + period / 2);
+        clock.flip_complete(start + period * 2);
+        assert!(clock.missed_vblank());
+        assert_eq!(clock.frame_interval(), Some(period * 2));
+
+        clock.flip_queued(start + period * 2);
+        clock.flip_complete(start + period * 3);
+        assert!(!clock.missed_vblank());
+    }
+}
@@ -0,0 +1,196 @@
+use std::mem;
+use std::slice;
+
+use drm::control::crtc::GammaRamp;
+use drm::ffi;
+
+// Identity at 6500K, brightness 1.0 and gamma 1.0
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lut {
+    pub red: Vec<u16>,
+    pub green: Vec<u16>,
+    pub blue: Vec<u16>,
+}
+
+impl Lut {
+    pub fn identity(size: usize) -> Lut {
+        Lut::from_curve(size, NEUTRAL_TEMPERATURE, 1.0, 1.0)
+    }
+
+    // Night light style ramp: each channel scaled by the white point of the
+    // color temperature and the brightness, then raised to 1/gamma
+    pub fn from_curve(size: usize, temperature: u32, brightness: f64, gamma: f64) -> Lut {
+        let (r, g, b) = temperature_to_rgb(temperature);
+        let brightness = brightness.clamp(0.0, 1.0);
+        let exponent = if gamma > 0.0 { 1.0 / gamma } else { 1.0 };
+
+        let channel = |scale: f64| -> Vec<u16> {
+            (0..size).map(|i| {
+                let x = if size > 1 { i as f64 / (size - 1) as f64 } else { 1.0 };
+                let value = (x * scale * brightness).powf(exponent);
+                (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+            }).collect()
+        };
+
+        Lut { red: channel(r), green: channel(g), blue: channel(b) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.red.len()
+    }
+
+    // Linear interpolation onto another size, legacy and atomic LUTs rarely
+    // have the same number of entries
+    pub fn resample(&self, size: usize) -> Lut {
+        if size == self.len() {
+            return self.clone();
+        }
+
+        let sample = |channel: &[u16]| -> Vec<u16> {
+            (0..size).map(|i| {
+                if channel.is_empty() {
+                    return 0;
+                }
+                let pos = if size > 1 { i as f64 * (channel.len() - 1) as f64 / (size - 1) as f64 } else { 0.0 };
+                let (low, frac) = (pos.floor() as usize, pos.fract());
+                let high = (low + 1).min(channel.len() - 1);
+                (f64::from(channel[low]) * (1.0 - frac) + f64::from(channel[high]) * frac).round() as u16
+            }).collect()
+        };
+
+        Lut { red: sample(&self.red), green: sample(&self.green), blue: sample(&self.blue) }
+    }
+
+    pub fn to_gamma_ramp(&self) -> GammaRamp {
+        GammaRamp {
+            red: self.red.clone().into_boxed_slice(),
+            green: self.green.clone().into_boxed_slice(),
+            blue: self.blue.clone().into_boxed_slice(),
+        }
+    }
+
+    // Contents of a GAMMA_LUT / DEGAMMA_LUT blob, an array of struct drm_color_lut
+    pub fn to_blob(&self) -> Vec<u8> {
+        let entries = (0..self.len())
+            .map(|i| ffi::drm_color_lut { red: self.red[i], green: self.green[i], blue: self.blue[i], reserved: 0 })
+            .collect::<Vec<_>>();
+
+        as_bytes(&entries).to_vec()
+    }
+}
+
+// Row major 3x3 matrix applied to linear RGB before the gamma LUT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ctm(pub [f64; 9]);
+
+impl Ctm {
+    pub fn scale(r: f64, g: f64, b: f64) -> Ctm {
+        Ctm([r, 0.0, 0.0, 0.0, g, 0.0, 0.0, 0.0, b])
+    }
+
+    // The kernel wants S31.32 sign-magnitude, not two's complement
+    pub fn to_blob(self) -> Vec<u8> {
+        let mut raw = ffi::drm_color_ctm { matrix: [0; 9] };
+        for (dst, &value) in raw.matrix.iter_mut().zip(self.0.iter()) {
+            let magnitude = (value.abs() * (1u64 << 32) as f64) as u64 & !(1 << 63);
+            *dst = (magnitude | if value < 0.0 { 1 << 63 } else { 0 }) as i64;
+        }
+
+        as_bytes(slice::from_ref(&raw)).to_vec()
+    }
+}
+
+// Tanner Helland's fit of the black body curve, good enough between 1000K
+// and 40000K. Channels are relative to 6500K, so that one comes out white.
+pub fn temperature_to_rgb(kelvin: u32) -> (f64, f64, f64) {
+    let t = f64::from(kelvin.clamp(1000, 40000)) / 100.0;
+
+    let red = if t <= 66.0 { 255.0 } else { 329.698_727_446 * (t - 60.0).powf(-0.133_204_759_2) };
+    let green = if t <= 66.0 {
+        99.470_802_586_1 * t.ln() - 161.119_568_166_1
+    } else {
+        288.122_169_528_3 * (t - 60.0).powf(-0.075_514_849_2)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_731_223_1 * (t - 10.0).ln() - 305.044_792_730_7
+    };
+
+    let clamp = |c: f64| c.clamp(0.0, 255.0) / 255.0;
+    let (r, g, b) = (clamp(red), clamp(green), clamp(blue));
+
+    // the fit isn't exactly white at 6500K, make sure the neutral point is identity
+    let (nr, ng, nb) = if kelvin == NEUTRAL_TEMPERATURE { (r, g, b) } else { temperature_to_rgb(NEUTRAL_TEMPERATURE) };
+    (r / nr, g / ng, b / nb)
+}
+
+fn as_bytes<T>(data: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, mem::size_of_val(data)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{temperature_to_rgb, Ctm, Lut};
+
+    fn words(blob: &[u8]) -> Vec<u64> {
+        blob.chunks(8).map(|b| u64::from_ne_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])).collect()
+    }
+
+    #[test]
+    fn identity_is_a_straight_ramp() {
+        let lut = Lut::identity(5);
+        assert_eq!(lut.red, vec![0, 16384, 32768, 49151, 65535]);
+        assert_eq!(lut.green, lut.red);
+        assert_eq!(lut.blue, lut.red);
+        assert_eq!(temperature_to_rgb(6500), (1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn warm_temperatures_dim_blue_first() {
+        let lut = Lut::from_curve(256, 3000, 1.0, 1.0);
+        assert_eq!(lut.red[255], 65535);
+        assert!(lut.blue[255] < lut.green[255]);
+        assert!(lut.green[255] < lut.red[255]);
+
+        let dimmed = Lut::from_curve(256, 6500, 0.5, 1.0);
+        assert_eq!(dimmed.red[255], 32768);
+    }
+
+    #[test]
+    fn resample_keeps_the_endpoints() {
+        let lut = Lut::from_curve(1024, 4500, 0.8, 2.2);
+        let small = lut.resample(256);
+
+        assert_eq!(small.len(), 256);
+        assert_eq!((small.red[0], small.red[255]), (lut.red[0], lut.red[1023]));
+        assert_eq!((small.blue[0], small.blue[255]), (lut.blue[0], lut.blue[1023]));
+        assert_eq!(Lut::identity(2).resample(3).green, vec![0, 32768, 65535]);
+    }
+
+    #[test]
+    fn lut_blob_holds_drm_color_lut_entries() {
+        let lut = Lut { red: vec![1, 2], green: vec![3, 4], blue: vec![5, 6] };
+        let blob = lut.to_blob();
+
+        assert_eq!(blob.len(), 16);
+        let entry = |i: usize| (0..4).map(|c| u16::from_ne_bytes([blob[i * 8 + c * 2], blob[i * 8 + c * 2 + 1]])).collect::<Vec<_>>();
+        assert_eq!(entry(0), vec![1, 3, 5, 0]);
+        assert_eq!(entry(1), vec![2, 4, 6, 0]);
+        assert_eq!(lut.to_gamma_ramp().blue.to_vec(), vec![5, 6]);
+    }
+
+    #[test]
+    fn ctm_blob_is_sign_magnitude() {
+        assert_eq!(words(&Ctm::scale(1.0, 1.0, 1.0).to_blob()), vec![1 << 32, 0, 0, 0, 1 << 32, 0, 0, 0, 1 << 32]);
+
+        let blob = words(&Ctm::scale(-0.5, 0.25, 1.5).to_blob());
+        assert_eq!(blob[0], 1 << 63 | 1 << 31);
+        assert_eq!(blob[4], 1 << 30);
+        assert_eq!(blob[8], 3 << 31);
+    }
+}
@@ -0,0 +1,73 @@
+use std::os::unix::io::AsRawFd;
+
+use drm::control::connector::{Handle, Type};
+use drm::ffi;
+
+use edid::Edid;
+use error::{Error, Result};
+use property;
+
+// Same spelling the kernel uses for the sysfs card0-HDMI-A-1 entries
+pub fn interface_name(interface: Type) -> &'static str {
+    match interface {
+        Type::Unknown => "Unknown",
+        Type::VGA => "VGA",
+        Type::DVII => "DVI-I",
+        Type::DVID => "DVI-D",
+        Type::DVIA => "DVI-A",
+        Type::Composite => "Composite",
+        Type::SVideo => "SVIDEO",
+        Type::LVDS => "LVDS",
+        Type::Component => "Component",
+        Type::NinePinDIN => "DIN",
+        Type::DisplayPort => "DP",
+        Type::HDMIA => "HDMI-A",
+        Type::HDMIB => "HDMI-B",
+        Type::TV => "TV",
+        Type::EmbeddedDisplayPort => "eDP",
+        Type::Virtual => "Virtual",
+        Type::DSI => "DSI",
+        Type::DPI => "DPI",
+    }
+}
+
+pub fn name(interface: Type, interface_id: u32) -> String {
+    format!("{}-{}", interface_name(interface), interface_id)
+}
+
+// drm-rs drops connector_type_id, ask the kernel again. Asking for zero modes
+// would make it probe the connector, so hand it room for one.
+pub fn interface_id<D: AsRawFd>(device: &D, connector: Handle) -> Result<u32> {
+    let mut mode: ffi::drm_mode_modeinfo = Default::default();
+    let mut raw = ffi::drm_mode_get_connector {
+        connector_id: connector.into(),
+        modes_ptr: &mut mode as *mut _ as u64,
+        count_modes: 1,
+        ..Default::default()
+    };
+
+    unsafe { ffi::ioctl_mode_getconnector(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get connector", connector, err))?;
+
+    Ok(raw.connector_type_id)
+}
+
+// None for connectors without a sink or with an EDID we can't make sense of
+pub fn edid<D: AsRawFd>(device: &D, connector: Handle) -> Option<Edid> {
+    let blob = match property::find_property(device, connector.into(), ffi::DRM_MODE_OBJECT_CONNECTOR, "EDID") {
+        Ok(Some((_, blob))) if blob != 0 => blob,
+        Ok(_) => return None,
+        Err(err) => {
+            eprintln!("{}", err);
+            return None;
+        }
+    };
+
+    match property::get_blob(device, blob as u32) {
+        Ok(data) => Edid::parse(&data),
+        Err(err) => {
+            eprintln!("{}", err);
+            None
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use std::cell::{Cell, RefCell};
+
+use drm::buffer::Buffer;
+use drm::control::crtc;
+use gbm;
+use libc;
+
+use atomic::{self, AtomicRequest, CommitFlags};
+use bo;
+use device::{Gpu, ModesetBackend};
+use dumb::DumbBuffer;
+use error::{Error, Result};
+use framebuffer::Framebuffer;
+use plane::PlaneType;
+
+// Pixels are ARGB8888, row by row without padding
+#[derive(Debug, Clone)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    pub hotspot: (i32, i32),
+    pub pixels: Vec<u32>,
+}
+
+impl CursorImage {
+    // Plain pointer for when nobody brought a theme: white triangle, black
+    // outline, pointing at the top left corner
+    pub fn arrow() -> CursorImage {
+        let size = 16;
+        let pixels = (0..size * size).map(|i| {
+            let (x, y) = (i % size, i / size);
+            match () {
+                _ if x > y => 0,
+                _ if x == 0 || x == y || y == size - 1 => 0xff00_0000,
+                _ => 0xffff_ffff,
+            }
+        }).collect();
+
+        CursorImage { width: size, height: size, hotspot: (0, 0), pixels }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorBackend {
+    Plane(u32),
+    Legacy,
+    // No hardware cursor, the renderer has to draw it into the frame itself
+    Composited,
+}
+
+// The image padded to the hardware cursor size. gbm allocates it when we
+// render with gbm, the software outputs get a dumb buffer.
+enum CursorBuffer {
+    // the framebuffer is only made for cursor planes
+    Gbm(gbm::BufferObject<()>, Option<Framebuffer>),
+    Dumb(DumbBuffer),
+}
+
+impl CursorBuffer {
+    fn handle(&self) -> u32 {
+        match *self {
+            CursorBuffer::Gbm(ref bo, _) => bo::bo_layout(bo).handles[0],
+            CursorBuffer::Dumb(ref dumb) => dumb.handle().as_raw(),
+        }
+    }
+
+    fn framebuffer(&self) -> Option<&Framebuffer> {
+        match *self {
+            CursorBuffer::Gbm(_, ref fb) => fb.as_ref(),
+            CursorBuffer::Dumb(ref dumb) => dumb.framebuffer(),
+        }
+    }
+}
+
+pub struct Cursor {
+    crtc: crtc::Handle,
+    backend: CursorBackend,
+    size: (u32, u32),
+    image: Option<CursorImage>,
+    buffer: Option<CursorBuffer>,
+    // what the plane shows until a commit with buffer goes through
+    retired: RefCell<Option<CursorBuffer>>,
+    position: (i32, i32),
+    visible: bool,
+    dirty: Cell<bool>,
+}
+
+impl Cursor {
+    // Cursor planes need the atomic backend and have to take ARGB8888.
+    // Drivers that don't report a cursor size get a composited cursor.
+    pub fn new(gpu: &Gpu, crtc: crtc::Handle) -> Cursor {
+        let (width, height) = gpu.cursor_size();
+        let plane = gpu.find_plane(crtc, PlaneType::Cursor)
+            .filter(|plane| plane.supports_format(gbm::Format::ARGB8888.as_ffi()));
+
+        let backend = match (gpu.backend, plane) {
+            _ if width == 0 || height == 0 => CursorBackend::Composited,
+            (ModesetBackend::Atomic, Some(plane)) => CursorBackend::Plane(plane.handle.into()),
+            _ => CursorBackend::Legacy,
+        };
+
+        Cursor {
+            crtc,
+            backend,
+            size: (width, height),
+            image: None,
+            buffer: None,
+            retired: RefCell::new(None),
+            position: (0, 0),
+            visible: false,
+            dirty: Cell::new(false),
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    // Falls back to compositing when the hardware refuses the image
+    pub fn set_image(&mut self, gpu: &Gpu, image: CursorImage) -> Result<()> {
+        if image.pixels.len() < image.width as usize * image.height as usize {
+            return Err(Error::InvalidCursorImage { width: image.width, height: image.height, pixels: image.pixels.len() });
+        }
+
+        let (max_width, max_height) = self.size;
+        if self.backend != CursorBackend::Composited && (image.width > max_width || image.height > max_height) {
+            return Err(Error::CursorTooLarge { width: image.width, height: image.height, max_width, max_height });
+        }
+
+        self.visible = true;
+        self.image = Some(image);
+        if self.backend == CursorBackend::Composited {
+            return Ok(());
+        }
+
+        gpu.ensure_master()?;
+        if let Err(err) = self.replace_buffer(gpu) {
+            eprintln!("[drm] hardware cursor is not available, compositing it instead: {}", err);
+            self.fall_back(gpu);
+        }
+        Ok(())
+    }
+
+    pub fn move_to(&mut self, gpu: &Gpu, x: i32, y: i32) -> Result<()> {
+        self.position = (x, y);
+
+        let (hot_x, hot_y) = self.hotspot();
+        match self.backend {
+            CursorBackend::Legacy if self.visible => {
+                gpu.ensure_master()?;
+                gpu.kms().move_cursor(self.crtc, (x - hot_x, y - hot_y))
+            }
+            CursorBackend::Plane(_) => {
+                self.dirty.set(true);
+                self.commit(gpu)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn hide(&mut self, gpu: &Gpu) -> Result<()> {
+        self.visible = false;
+
+        match self.backend {
+            CursorBackend::Legacy => {
+                gpu.ensure_master()?;
+                gpu.kms().set_cursor(self.crtc, 0, (0, 0), (0, 0))
+            }
+            CursorBackend::Plane(_) => {
+                self.dirty.set(true);
+                self.commit(gpu)
+            }
+            CursorBackend::Composited => Ok(()),
+        }
+    }
+
+    // What the renderer has to draw itself when there is no hardware cursor,
+    // with the top left corner of the image already offset by the hotspot
+    pub fn composited(&self) -> Option<(&CursorImage, (i32, i32))> {
+        if self.backend != CursorBackend::Composited || !self.visible {
+            return None;
+        }
+
+        let (hot_x, hot_y) = self.hotspot();
+        let (x, y) = self.position;
+        self.image.as_ref().map(|image| (image, (x - hot_x, y - hot_y)))
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.get()
+    }
+
+    // Lets a page flip carry the cursor update that couldn't be committed on its own
+    pub fn add_to_request(&self, gpu: &Gpu, request: &mut AtomicRequest) -> Result<()> {
+        let plane = match self.backend {
+            CursorBackend::Plane(plane) => plane,
+            _ => return Ok(()),
+        };
+
+        let (width, height) = self.size;
+        let (hot_x, hot_y) = self.hotspot();
+        let (x, y) = self.position;
+
+        let binding = match self.buffer.as_ref().and_then(CursorBuffer::framebuffer) {
+            Some(fb) if self.visible => Some((self.crtc, fb.handle())),
+            _ => None,
+        };
+
+        atomic::add_plane_state(gpu, request, plane, binding, (width, height), (x - hot_x, y - hot_y, width, height))
+    }
+
+    pub fn mark_committed(&self) {
+        self.dirty.set(false);
+        self.retired.borrow_mut().take();
+    }
+
+    fn replace_buffer(&mut self, gpu: &Gpu) -> Result<()> {
+        let buffer = match self.image {
+            Some(ref image) => self.upload(gpu, image)?,
+            None => return Ok(()),
+        };
+
+        match self.backend {
+            CursorBackend::Legacy => {
+                let (hot_x, hot_y) = self.hotspot();
+                let (x, y) = self.position;
+                gpu.kms().set_cursor(self.crtc, buffer.handle(), self.size, (hot_x, hot_y))?;
+                self.buffer = Some(buffer);
+                gpu.kms().move_cursor(self.crtc, (x - hot_x, y - hot_y))
+            }
+            CursorBackend::Plane(_) => {
+                // a replaced buffer that never got committed was never on screen
+                let previous = self.buffer.replace(buffer);
+                {
+                    let mut retired = self.retired.borrow_mut();
+                    if retired.is_none() {
+                        *retired = previous;
+                    }
+                }
+
+                self.dirty.set(true);
+                self.commit(gpu)
+            }
+            CursorBackend::Composited => Ok(()),
+        }
+    }
+
+    // Removing the framebuffers takes them off the plane, the legacy cursor
+    // holds on to its buffer until it's cleared
+    fn fall_back(&mut self, gpu: &Gpu) {
+        if self.backend == CursorBackend::Legacy && self.buffer.is_some() {
+            if let Err(err) = gpu.kms().set_cursor(self.crtc, 0, (0, 0), (0, 0)) {
+                eprintln!("{}", err);
+            }
+        }
+
+        self.backend = CursorBackend::Composited;
+        self.buffer = None;
+        self.retired.borrow_mut().take();
+        self.dirty.set(false);
+    }
+
+    fn commit(&self, gpu: &Gpu) -> Result<()> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        gpu.ensure_master()?;
+
+        let mut request = AtomicRequest::new();
+        self.add_to_request(gpu, &mut request)?;
+
+        match gpu.atomic_commit(&request, CommitFlags::NONBLOCK, 0) {
+            Ok(_) => {
+                self.mark_committed();
+                Ok(())
+            }
+            // a flip is still in flight, the next one picks the cursor up
+            Err(ref err) if err.errno() == Some(libc::EBUSY) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn hotspot(&self) -> (i32, i32) {
+        self.image.as_ref().map(|image| image.hotspot).unwrap_or((0, 0))
+    }
+
+    fn upload(&self, gpu: &Gpu, image: &CursorImage) -> Result<CursorBuffer> {
+        let (width, height) = self.size;
+        // cursor buffers always have the full hardware size, pad with transparency
+        let pixel = |x: u32, y: u32| {
+            if x < image.width && y < image.height {
+                image.pixels[(y * image.width + x) as usize]
+            } else {
+                0
+            }
+        };
+
+        let device = match gpu.gbm_device() {
+            Ok(device) => device,
+            Err(_) => {
+                let mut dumb = DumbBuffer::with_format(gpu, width, height, gbm::Format::ARGB8888)?;
+                let stride = dumb.stride();
+                let pixels = dumb.pixels_mut();
+                for y in 0..height {
+                    for x in 0..width {
+                        pixels[y as usize * stride + x as usize] = pixel(x, y);
+                    }
+                }
+                return Ok(CursorBuffer::Dumb(dumb));
+            }
+        };
+
+        let mut bo = device.create_buffer_object::<()>(width, height, gbm::Format::ARGB8888, gbm::BufferObjectFlags::CURSOR | gbm::BufferObjectFlags::WRITE)
+            .map_err(|err| Error::gbm("create cursor buffer", err))?;
+
+        let mut bytes = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                bytes.extend_from_slice(&pixel(x, y).to_ne_bytes());
+            }
+        }
+
+        bo.write(&bytes)
+            .map_err(|_| Error::DeviceDestroyed)?
+            .map_err(|err| Error::gbm("write cursor buffer", err))?;
+
+        let fb = match self.backend {
+            CursorBackend::Plane(_) => Some(Framebuffer::create(gpu, &bo::bo_layout(&bo))?),
+            _ => None,
+        };
+        Ok(CursorBuffer::Gbm(bo, fb))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use drm::control::{crtc, plane};
+    use gbm;
+
+    use device::{Gpu, ModesetBackend};
+    use error::Error;
+    use fake::{FakeCursor, FakeDevice};
+    use plane::{Plane, PlaneType};
+    use property::PropertyKind;
+    use super::{Cursor, CursorBackend, CursorImage};
+
+    const CURSOR_PLANE: u32 = 100;
+
+    fn gpu(backend: ModesetBackend) -> (Rc<FakeDevice>, Gpu, crtc::Handle) {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.backend = backend;
+        gpu.caps.dumb_buffer = true;
+        gpu.caps.cursor_size = (64, 64);
+        (fake, gpu, crtc)
+    }
+
+    fn add_cursor_plane(fake: &FakeDevice, gpu: &mut Gpu, format: gbm::Format) {
+        for name in &["FB_ID", "CRTC_ID", "SRC_X", "SRC_Y", "SRC_W", "SRC_H", "CRTC_X", "CRTC_Y", "CRTC_W", "CRTC_H"] {
+            fake.add_property(CURSOR_PLANE, name, PropertyKind::Range { min: 0, max: u64::MAX }, 0);
+        }
+
+        gpu.planes.push(Plane {
+            handle: plane::Handle::from(CURSOR_PLANE),
+            plane_type: PlaneType::Cursor,
+            possible_crtcs: 0b1,
+            crtc: None,
+            formats: vec![format.as_ffi()],
+            modifiers: Vec::new(),
+            zpos: None,
+        });
+    }
+
+    fn image(width: u32, height: u32) -> CursorImage {
+        CursorImage { width, height, hotspot: (2, 3), pixels: vec![0xffff_ffff; (width * height) as usize] }
+    }
+
+    #[test]
+    fn plane_cursor_commits_its_own_plane_state() {
+        let (fake, mut gpu, crtc) = gpu(ModesetBackend::Atomic);
+        add_cursor_plane(&fake, &mut gpu, gbm::Format::ARGB8888);
+
+        let mut cursor = Cursor::new(&gpu, crtc);
+        assert_eq!(cursor.backend, CursorBackend::Plane(CURSOR_PLANE));
+
+        cursor.set_image(&gpu, image(16, 16)).unwrap();
+        assert_ne!(fake.property_value(CURSOR_PLANE, "FB_ID"), Some(0));
+        assert_eq!(fake.property_value(CURSOR_PLANE, "CRTC_ID"), Some(u64::from(u32::from(crtc))));
+        assert_eq!(fake.property_value(CURSOR_PLANE, "CRTC_W"), Some(64));
+        assert!(!cursor.is_dirty());
+
+        cursor.move_to(&gpu, 100, 50).unwrap();
+        assert_eq!(fake.property_value(CURSOR_PLANE, "CRTC_X"), Some(98));
+        assert_eq!(fake.property_value(CURSOR_PLANE, "CRTC_Y"), Some(47));
+
+        cursor.hide(&gpu).unwrap();
+        assert_eq!(fake.property_value(CURSOR_PLANE, "FB_ID"), Some(0));
+        assert_eq!(fake.property_value(CURSOR_PLANE, "CRTC_ID"), Some(0));
+        assert!(cursor.composited().is_none());
+    }
+
+    #[test]
+    fn legacy_cursor_goes_through_the_cursor_ioctls() {
+        let (fake, mut gpu, crtc) = gpu(ModesetBackend::Atomic);
+        // a cursor plane that can't take ARGB8888 is no use
+        add_cursor_plane(&fake, &mut gpu, gbm::Format::XRGB8888);
+
+        let mut cursor = Cursor::new(&gpu, crtc);
+        assert_eq!(cursor.backend, CursorBackend::Legacy);
+
+        cursor.set_image(&gpu, image(16, 16)).unwrap();
+        let shown = fake.cursor(crtc).unwrap();
+        assert_ne!(shown.handle, 0);
+        assert_eq!(shown.hotspot, (2, 3));
+        assert_eq!(shown.position, (-2, -3));
+
+        cursor.move_to(&gpu, 10, 10).unwrap();
+        assert_eq!(fake.cursor(crtc).unwrap().position, (8, 7));
+
+        cursor.hide(&gpu).unwrap();
+        assert_eq!(fake.cursor(crtc).unwrap().handle, 0);
+
+        // hidden cursors stay where they are
+        cursor.move_to(&gpu, 20, 20).unwrap();
+        assert_eq!(fake.cursor(crtc).unwrap(), FakeCursor { handle: 0, hotspot: (0, 0), position: (8, 7) });
+    }
+
+    #[test]
+    fn cursor_is_composited_without_a_cursor_size() {
+        let (_fake, mut gpu, crtc) = gpu(ModesetBackend::Legacy);
+        gpu.caps.cursor_size = (0, 0);
+
+        let mut cursor = Cursor::new(&gpu, crtc);
+        assert_eq!(cursor.backend, CursorBackend::Composited);
+
+        // the renderer draws it, any size goes
+        cursor.set_image(&gpu, image(128, 128)).unwrap();
+        cursor.move_to(&gpu, 10, 10).unwrap();
+        let (shown, position) = cursor.composited().unwrap();
+        assert_eq!((shown.width, position), (128, (8, 7)));
+
+        cursor.hide(&gpu).unwrap();
+        assert!(cursor.composited().is_none());
+    }
+
+    #[test]
+    fn cursor_falls_back_to_compositing_when_the_hardware_refuses() {
+        let (fake, gpu, crtc) = gpu(ModesetBackend::Legacy);
+        fake.set_hardware_cursor(false);
+
+        let mut cursor = Cursor::new(&gpu, crtc);
+        cursor.set_image(&gpu, image(16, 16)).unwrap();
+        assert_eq!(cursor.backend, CursorBackend::Composited);
+        assert!(cursor.composited().is_some());
+
+        // no buffer to put the image in either
+        let (_fake, mut gpu, crtc) = self::gpu(ModesetBackend::Legacy);
+        gpu.caps.dumb_buffer = false;
+
+        let mut cursor = Cursor::new(&gpu, crtc);
+        cursor.set_image(&gpu, image(16, 16)).unwrap();
+        assert_eq!(cursor.backend, CursorBackend::Composited);
+    }
+
+    #[test]
+    fn malformed_images_are_rejected() {
+        let (_fake, gpu, crtc) = gpu(ModesetBackend::Legacy);
+        let mut cursor = Cursor::new(&gpu, crtc);
+
+        let mut short = image(16, 16);
+        short.pixels.truncate(100);
+        match cursor.set_image(&gpu, short) {
+            Err(Error::InvalidCursorImage { width: 16, height: 16, pixels: 100 }) => {}
+            other => panic!("short image gave {:?}", other),
+        }
+
+        match cursor.set_image(&gpu, image(128, 16)) {
+            Err(Error::CursorTooLarge { max_width: 64, .. }) => {}
+            other => panic!("oversized image gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arrow_points_at_its_hotspot() {
+        let arrow = CursorImage::arrow();
+        assert_eq!(arrow.hotspot, (0, 0));
+        assert_eq!(arrow.pixels.len(), (arrow.width * arrow.height) as usize);
+        assert_eq!(arrow.pixels[0] >> 24, 0xff);
+        assert_eq!(arrow.pixels[arrow.width as usize - 1], 0);
+    }
+}
@@ -1,23 +1,49 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::ops::Deref;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::os::unix::io::RawFd;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::IntoRawFd;
+use std::mem;
 use std::ptr;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
 
+use drm::ClientCapability;
 use drm::Device as DrmDevice;
 use drm::control::Device as DrmControlDevice;
-use drm::control::{ResourceHandle, ResourceInfo};
-use drm::control::{connector, encoder, crtc, Mode};
+use drm::control::{connector, crtc, Mode};
+use drm::control::crtc::PageFlipEvent;
+use drm::control::framebuffer as drm_fb;
+use drm::ffi;
 
 use gbm;
 use gbm::AsRaw;
 
 use egl;
+use libc;
+use udev;
 
-use display::{Display, Surface as DisplaySurface};
+use atomic::{self, CommitFlags};
+use bo::{self, GbmSurface};
+use caps::Capabilities;
+use color::{Ctm, Lut};
+use display::{Display, EglDisplay, Scanout, Surface as DisplaySurface};
+use error::{Error, Result};
+use hotplug::DisplayEvent;
+use kms::{ConnectorInfo, CrtcInfo, EncoderInfo, KmsDevice, Resources};
+use lease::{self, Lease};
+use mode;
+use plane::{self, Plane, PlaneType};
+use property::{self, ObjectType, Property};
 
-pub struct DeviceFile(File);
+// Shared by the gbm device, the KMS calls and every object that cleans up
+// after itself on drop, the fd is closed once the last of them is gone
+#[derive(Clone)]
+pub struct DeviceFile(Rc<File>);
 
 impl AsRawFd for DeviceFile {
     fn as_raw_fd(&self) -> RawFd {
@@ -25,109 +51,739 @@ impl AsRawFd for DeviceFile {
     }
 }
 
+impl From<File> for DeviceFile {
+    fn from(file: File) -> DeviceFile {
+        DeviceFile(Rc::new(file))
+    }
+}
+
+// Hands out a dup while anything else still uses the fd
 impl IntoRawFd for DeviceFile {
     fn into_raw_fd(self) -> RawFd {
-        self.0.into_raw_fd()
+        match Rc::try_unwrap(self.0) {
+            Ok(file) => file.into_raw_fd(),
+            Err(file) => unsafe { libc::dup(file.as_raw_fd()) },
+        }
     }
 }
 
 impl DrmDevice for DeviceFile {}
 impl DrmControlDevice for DeviceFile {}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModesetBackend {
+    Legacy,
+    Atomic,
+}
+
 pub struct Gpu {
-    pub gbm_device: gbm::Device<DeviceFile>,
-    pub connectors: Vec<connector::Info>,
-    pub encoders: Vec<encoder::Info>,
-    pub crtcs: Vec<crtc::Info>,
+    gbm_device: Option<gbm::Device<DeviceFile>>,
+    kms: Rc<dyn KmsDevice>,
+    pub backend: ModesetBackend,
+    pub caps: Capabilities,
+    pub resources: Resources,
+    pub connectors: Vec<ConnectorInfo>,
+    pub encoders: Vec<EncoderInfo>,
+    pub crtcs: Vec<CrtcInfo>,
+    pub planes: Vec<Plane>,
+    master: Cell<bool>,
+    saved_crtcs: Vec<SavedCrtc>,
+    // crtcs we asked for a flip event and didn't get it from yet
+    pending_flips: RefCell<Vec<crtc::Handle>>,
+    // flips wait_for_flip read while waiting for another crtc
+    queued_flips: RefCell<Vec<PageFlipEvent>>,
+    egl_display: RefCell<Weak<EglDisplay>>,
+    // property name to id, per object
+    property_ids: RefCell<HashMap<u32, HashMap<String, u32>>>,
 }
 
-impl Deref for Gpu {
-    type Target = gbm::Device<DeviceFile>;
+// What a crtc showed when we gave up master, put back once we get it again
+struct SavedCrtc {
+    info: CrtcInfo,
+    connectors: Vec<connector::Handle>,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.gbm_device
+// The fake in tests has no node, every ioctl going around it fails with EBADF
+impl AsRawFd for Gpu {
+    fn as_raw_fd(&self) -> RawFd {
+        self.kms.as_raw_fd()
     }
 }
 
-//impl AsRawFd for Gpu {
-//    fn as_raw_fd(&self) -> RawFd {
-//        self.gbm_device.as_raw_fd()
-//    }
-//}
-//
 //impl IntoRawFd for Gpu {
 //    fn into_raw_fd(self) -> RawFd {
 //        self.gbm_device.into_raw_fd()
 //    }
 //}
-//
-//impl DrmDevice for Gpu {}
-//impl DrmControlDevice for Gpu {}
+
+impl DrmDevice for Gpu {}
+impl DrmControlDevice for Gpu {}
 
 impl Gpu {
-    pub fn get_crtc(&self, crtc: crtc::Handle) -> Option<crtc::Info> {
-        self.crtcs.iter().find(|c| c.handle() == crtc).map(|c| c.to_owned())
+    // Mode setting goes through kms, anything rendering related needs the gbm device
+    pub fn new(kms: Rc<dyn KmsDevice>, gbm_device: Option<gbm::Device<DeviceFile>>) -> Result<Gpu> {
+        let resources = kms.resources()?;
+        let connectors = kms.load_connectors(&resources)?;
+        let encoders = kms.load_encoders(&resources)?;
+        let crtcs = kms.load_crtcs(&resources)?;
+
+        let (caps, planes) = match gbm_device {
+            Some(ref device) => {
+                // primary and cursor planes are hidden from us unless we ask for them
+                if let Err(err) = device.set_client_cap(ClientCapability::UniversalPlanes, true) {
+                    eprintln!("[drm] universal planes are not supported: {}", err);
+                }
+                (Capabilities::query(device), plane::load_planes(device)?)
+            }
+            None => (Capabilities::default(), Vec::new()),
+        };
+
+        let gpu = Gpu {
+            gbm_device,
+            kms,
+            backend: ModesetBackend::Legacy,
+            caps,
+            resources,
+            connectors,
+            encoders,
+            crtcs,
+            planes,
+            master: Cell::new(false),
+            saved_crtcs: Vec::new(),
+            pending_flips: RefCell::new(Vec::new()),
+            queued_flips: RefCell::new(Vec::new()),
+            egl_display: RefCell::new(Weak::new()),
+            property_ids: RefCell::new(HashMap::new()),
+        };
+
+        gpu.is_master();
+        Ok(gpu)
+    }
+
+    pub fn gbm_device(&self) -> Result<&gbm::Device<DeviceFile>> {
+        self.gbm_device.as_ref().ok_or(Error::NoGbmDevice)
+    }
+
+    // For objects that have to reach the device after being dropped
+    pub fn kms(&self) -> Rc<dyn KmsDevice> {
+        self.kms.clone()
+    }
+
+    // Atomic has to be requested from the kernel first, falls back to the
+    // legacy ioctls when the driver refuses DRM_CLIENT_CAP_ATOMIC
+    pub fn select_backend(&mut self, preferred: ModesetBackend) -> ModesetBackend {
+        self.backend = match preferred {
+            ModesetBackend::Atomic => match self.set_client_cap(ClientCapability::Atomic, true) {
+                Ok(_) => ModesetBackend::Atomic,
+                Err(err) => {
+                    eprintln!("[drm] atomic modesetting is not available, using legacy: {}", err);
+                    ModesetBackend::Legacy
+                }
+            },
+            ModesetBackend::Legacy => ModesetBackend::Legacy,
+        };
+
+        self.backend
+    }
+
+    pub fn atomic_commit(&self, request: &atomic::AtomicRequest, flags: CommitFlags, user_data: u64) -> Result<()> {
+        self.kms.atomic_commit(request, flags, user_data)
+    }
+
+    pub fn cursor_size(&self) -> (u32, u32) {
+        self.caps.cursor_size
+    }
+
+    // For paths that need a capability, refuses up front rather than
+    // failing halfway through with a bare errno
+    pub fn require(&self, supported: bool, feature: &'static str) -> Result<()> {
+        if supported {
+            Ok(())
+        } else {
+            Err(Error::Unsupported { driver: self.caps.driver.name.clone(), feature })
+        }
+    }
+
+    pub fn get_crtc(&self, crtc: crtc::Handle) -> Option<CrtcInfo> {
+        self.crtcs.iter().find(|c| c.handle == crtc).cloned()
+    }
+
+    // Bit position of the crtc in possible_crtcs masks
+    pub fn crtc_index(&self, crtc: crtc::Handle) -> Option<usize> {
+        self.crtcs.iter().position(|c| c.handle == crtc)
+    }
+
+    pub fn planes_for_crtc(&self, crtc: crtc::Handle) -> Vec<&Plane> {
+        match self.crtc_index(crtc) {
+            Some(index) => self.planes.iter().filter(|p| p.can_drive(index)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Prefers the plane already bound to the crtc over any other compatible one
+    pub fn find_plane(&self, crtc: crtc::Handle, plane_type: PlaneType) -> Option<&Plane> {
+        let planes = self.planes_for_crtc(crtc)
+            .into_iter()
+            .filter(|p| p.plane_type == plane_type)
+            .collect::<Vec<_>>();
+
+        planes.iter()
+            .find(|p| p.crtc == Some(crtc))
+            .or_else(|| planes.first())
+            .cloned()
+    }
+
+    pub fn get_connector(&self, connector: connector::Handle) -> Option<&ConnectorInfo> {
+        self.connectors.iter().find(|c| c.handle == connector)
+    }
+
+    // Every crtc one of the connector's encoders can be routed to, the one
+    // currently lighting the connector comes first
+    pub fn possible_crtcs(&self, connector: connector::Handle) -> Vec<crtc::Handle> {
+        let info = match self.get_connector(connector) {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+
+        let encoders = info.encoders
+            .iter()
+            .filter_map(|&handle| self.encoders.iter().find(|enc| enc.handle == handle))
+            .collect::<Vec<_>>();
+
+        let mut crtcs = info.current_encoder
+            .and_then(|current| encoders.iter().find(|enc| enc.handle == current))
+            .and_then(|enc| enc.current_crtc)
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        for encoder in encoders {
+            for crtc in self.resources.filter_crtcs(encoder.possible_crtcs) {
+                if !crtcs.contains(&crtc) {
+                    crtcs.push(crtc);
+                }
+            }
+        }
+
+        crtcs
+    }
+
+    pub fn find_crtc(&self, connector: connector::Handle, in_use: &[crtc::Handle]) -> Option<crtc::Handle> {
+        self.possible_crtcs(connector)
+            .into_iter()
+            .find(|crtc| !in_use.contains(crtc))
+    }
+
+    // Gives every connector its own crtc, in the same order as connectors.
+    // Greedy picks can paint us into a corner so this backtracks.
+    pub fn assign_crtcs(&self, connectors: &[connector::Handle], in_use: &[crtc::Handle]) -> Option<Vec<crtc::Handle>> {
+        let candidates = connectors.iter().map(|&c| self.possible_crtcs(c)).collect::<Vec<_>>();
+        let mut taken = in_use.to_vec();
+        let mut assignment = Vec::with_capacity(connectors.len());
+
+        if assign_from(&candidates, &mut taken, &mut assignment) {
+            Some(assignment)
+        } else {
+            None
+        }
     }
 
+    // Headsets and other non-desktop sinks are left out, those are only
+    // handed to other clients through leases
     pub fn displays(&self) -> Vec<Display> {
+        self.connected_displays().into_iter().filter(|d| !d.non_desktop).collect()
+    }
+
+    pub fn non_desktop_displays(&self) -> Vec<Display> {
+        self.connected_displays().into_iter().filter(|d| d.non_desktop).collect()
+    }
+
+    fn connected_displays(&self) -> Vec<Display> {
         self.connectors
             .iter()
-            .cloned()
-            .filter(|c| c.connection_state() == connector::State::Connected)
-            .enumerate()
-            .map(|(i, c)| {
-                let modes = c.modes().to_owned();
-                let connector = c.handle();
-                let identifier = format!("Display {}", i);
-                let encoder = c.current_encoder().and_then(|cur_enc| {
-                    self.encoders.iter().find(|enc| enc.handle() == cur_enc)
-                }).map(|enc| enc.to_owned());
-                Display { identifier, connector, modes, encoder }
+            .filter(|c| c.state == connector::State::Connected)
+            .map(|c| {
+                let modes = c.modes.clone();
+                let connector = c.handle;
+                let name = ::connector::name(c.interface, c.interface_id);
+                let vrr_capable = self.vrr_capable(connector);
+                let non_desktop = self.non_desktop(connector);
+                let identifier = match c.edid {
+                    Some(ref edid) => edid.identifier(),
+                    None => name.clone(),
+                };
+                let encoder = c.current_encoder.and_then(|cur_enc| {
+                    self.encoders.iter().find(|enc| enc.handle == cur_enc)
+                }).cloned();
+                Display { identifier, name, connector, modes, encoder, vrr_capable, non_desktop }
             })
             .collect()
     }
 
-    pub fn modeset(&self, crt: crtc::Info, displays: &[&Display], surface: &DisplaySurface) {
-        let connections = displays.into_iter().map(|d| d.connector).collect::<Vec<_>>();
+    pub fn vrr_capable(&self, connector: connector::Handle) -> bool {
+        match self.find_property(connector.into(), ffi::DRM_MODE_OBJECT_CONNECTOR, "vrr_capable") {
+            Ok(Some((_, value))) => value == 1,
+            _ => false,
+        }
+    }
+
+    pub fn non_desktop(&self, connector: connector::Handle) -> bool {
+        match self.find_property(connector.into(), ffi::DRM_MODE_OBJECT_CONNECTOR, "non-desktop") {
+            Ok(Some((_, value))) => value == 1,
+            _ => false,
+        }
+    }
+
+    // VRR_ENABLED only exists for atomic clients
+    pub fn set_vrr(&self, crtc: crtc::Handle, enabled: bool) -> Result<()> {
+        self.require(self.backend == ModesetBackend::Atomic, "VRR without atomic modesetting")?;
+        self.set_property(crtc, ObjectType::Crtc, "VRR_ENABLED", enabled as u64)
+    }
+
+    // Property ids don't change for the lifetime of an object, so the names
+    // are only looked up the first time anything asks about the object
+    pub fn property_id(&self, object: u32, object_type: u32, name: &str) -> Result<Option<u32>> {
+        if let Some(ids) = self.property_ids.borrow().get(&object) {
+            return Ok(ids.get(name).cloned());
+        }
 
-        let framebuffer = surface.framebuffer.as_ref().expect("[gpu] display surface doesn't have a framebuffer");
-        crtc::set(&self.gbm_device, crt.handle(), framebuffer.handle(), &connections, (0, 0), Some(surface.mode))
-            .expect("[drm] failed to set mode");
+        let mut ids = HashMap::new();
+        for (property, _) in self.kms.object_properties(object, object_type)? {
+            ids.insert(self.kms.property_info(property)?.name, property);
+        }
+        let id = ids.get(name).cloned();
+        self.property_ids.borrow_mut().insert(object, ids);
+        Ok(id)
     }
 
-    pub fn modeset_by_crtc(&self, conn: connector::Handle, crt: crtc::Info) {
-        crtc::set(&self.gbm_device, crt.handle(), crt.fb(), &[conn], crt.position(), crt.mode())
-            .expect("[drm] failed to set mode by crtc");
+    // Returns the property id and its current value on the object
+    pub fn find_property(&self, object: u32, object_type: u32, name: &str) -> Result<Option<(u32, u64)>> {
+        let id = match self.property_id(object, object_type, name)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        Ok(self.kms.object_properties(object, object_type)?.into_iter().find(|&(property, _)| property == id))
+    }
+
+    pub fn properties<H: Into<u32>>(&self, object: H, object_type: ObjectType) -> Result<Vec<Property>> {
+        self.kms.object_properties(object.into(), object_type.raw())?
+            .into_iter()
+            .map(|(id, raw)| Property::load(&*self.kms, id, raw))
+            .collect()
+    }
+
+    pub fn get_property<H: Into<u32>>(&self, object: H, object_type: ObjectType, name: &str) -> Result<Option<Property>> {
+        match self.find_property(object.into(), object_type.raw(), name)? {
+            Some((id, raw)) => Property::load(&*self.kms, id, raw).map(Some),
+            None => Ok(None),
+        }
     }
 
-    pub fn page_flip(&self, crtc: crtc::Handle, surface: &DisplaySurface) {
-        let fb = surface.framebuffer.as_ref().expect("[gpu] cannot do pageflip. display surface has no framebuffer");
+    // Goes through an atomic commit when the atomic backend is in use. Atomic
+    // commits refuse DPMS, and without a modeset they refuse properties like
+    // "max bpc" or "Broadcast RGB" too, SETPROPERTY still takes those.
+    pub fn set_property<H: Into<u32>>(&self, object: H, object_type: ObjectType, name: &str, value: u64) -> Result<()> {
+        let object = object.into();
+        let info = self.get_property(object, object_type, name)?
+            .ok_or_else(|| Error::MissingProperty { object, name: name.to_owned() })?
+            .info;
 
-        crtc::page_flip(&self.gbm_device, crtc, fb.handle(), &[crtc::PageFlipFlags::PageFlipEvent])
-            .expect("[gpu] page flip failed");
+        self.ensure_master()?;
+        if info.immutable {
+            return Err(Error::ImmutableProperty { object, name: info.name });
+        }
+        if !info.accepts(value) {
+            return Err(Error::InvalidPropertyValue { object, name: info.name, value: value.to_string() });
+        }
+
+        let legacy = || property::set_property(self, object, object_type.raw(), info.id, value);
+        match self.backend {
+            ModesetBackend::Legacy => legacy(),
+            ModesetBackend::Atomic if info.name == "DPMS" => legacy(),
+            ModesetBackend::Atomic => {
+                let mut request = atomic::AtomicRequest::new();
+                request.add_property(object, info.id, value);
+                self.atomic_commit(&request, CommitFlags::NONE, 0)
+                    .or_else(|err| if info.atomic_only { Err(err) } else { legacy() })
+            }
+        }
     }
 
-    pub fn receive_events(&self) -> crtc::Events {
-        crtc::receive_events(&self.gbm_device)
-            .expect("[gpu] failed receive crtc events")
+    // Sets an enum property by entry name, or a bitmask property to the named bits
+    pub fn set_property_by_name<H: Into<u32>>(&self, object: H, object_type: ObjectType, name: &str, entries: &[&str]) -> Result<()> {
+        let object = object.into();
+        let info = self.get_property(object, object_type, name)?
+            .ok_or_else(|| Error::MissingProperty { object, name: name.to_owned() })?
+            .info;
+
+        let value = info.encode(entries)
+            .ok_or_else(|| Error::InvalidPropertyValue { object, name: info.name.clone(), value: entries.join("|") })?;
+        self.set_property(object, object_type, name, value)
     }
 
-    pub fn initialize_display(&self, _display: &Display, crtc: crtc::Handle, format: gbm::Format, mode: Mode) -> DisplaySurface {
-        use cognitive_graphics::egl_tools;
+    // Entries the crtc's gamma LUT takes, GAMMA_LUT_SIZE when the atomic
+    // GAMMA_LUT is going to be used, the legacy ramp size otherwise
+    pub fn gamma_size(&self, crtc: crtc::Handle) -> Result<usize> {
+        if self.backend == ModesetBackend::Atomic {
+            if let Some((_, size)) = self.find_property(crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "GAMMA_LUT_SIZE")? {
+                return Ok(size as usize);
+            }
+        }
 
-        let egl_display = egl_tools::get_gbm_display(self.gbm_device.as_raw() as _)
-            .expect("Failed to get gbm display");
+        crtc::gamma(self, crtc)
+            .map(|ramp| ramp.red.len())
+            .map_err(|err| Error::drm("get gamma", crtc, err))
+    }
 
-        let mut maj: egl::EGLint = 0;
-        let mut min: egl::EGLint = 0;
-        if !egl::initialize(egl_display, &mut maj, &mut min) {
-            panic!("[egl] failed to initialize EGL");
+    pub fn set_gamma(&self, crtc: crtc::Handle, lut: &Lut) -> Result<()> {
+        self.ensure_master()?;
+        if self.backend == ModesetBackend::Atomic {
+            let gamma_lut = self.find_property(crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "GAMMA_LUT")?;
+            if gamma_lut.is_some() {
+                return self.set_crtc_lut(crtc, "GAMMA_LUT", "GAMMA_LUT_SIZE", Some(lut));
+            }
         }
 
-        println!("EGL major: {}, minor: {}", maj, min);
+        let size = crtc::gamma(self, crtc)
+            .map_err(|err| Error::drm("get gamma", crtc, err))?
+            .red.len();
+        crtc::set_gamma(self, crtc, lut.resample(size).to_gamma_ramp())
+            .map_err(|err| Error::drm("set gamma", crtc, err))
+    }
+
+    // Applied before the CTM, None puts the crtc back to pass-through
+    pub fn set_degamma(&self, crtc: crtc::Handle, lut: Option<&Lut>) -> Result<()> {
+        self.set_crtc_lut(crtc, "DEGAMMA_LUT", "DEGAMMA_LUT_SIZE", lut)
+    }
+
+    pub fn set_ctm(&self, crtc: crtc::Handle, ctm: Option<&Ctm>) -> Result<()> {
+        let data = ctm.map(|ctm| ctm.to_blob());
+        self.set_crtc_blob(crtc, "CTM", data.as_deref())
+    }
+
+    fn set_crtc_lut(&self, crtc: crtc::Handle, name: &'static str, size_name: &'static str, lut: Option<&Lut>) -> Result<()> {
+        let data = match lut {
+            Some(lut) => {
+                let size = self.find_property(crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, size_name)?
+                    .ok_or_else(|| Error::MissingProperty { object: crtc.into(), name: size_name.to_owned() })?
+                    .1;
+                Some(lut.resample(size as usize).to_blob())
+            }
+            None => None,
+        };
+
+        self.set_crtc_blob(crtc, name, data.as_deref())
+    }
+
+    // The color management properties aren't atomic only, legacy clients set
+    // them through SETPROPERTY. The kernel keeps its own reference to the
+    // blob so ours is dropped right after.
+    fn set_crtc_blob(&self, crtc: crtc::Handle, name: &'static str, data: Option<&[u8]>) -> Result<()> {
+        self.ensure_master()?;
+        let prop = atomic::required_property(self, crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, name)?;
+        let blob = match data {
+            Some(data) => property::create_blob(self, data)?,
+            None => 0,
+        };
+
+        let result = match self.backend {
+            ModesetBackend::Legacy => property::set_property(self, crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, prop, u64::from(blob)),
+            ModesetBackend::Atomic => {
+                let mut request = atomic::AtomicRequest::new();
+                request.add_property(crtc, prop, u64::from(blob));
+                self.atomic_commit(&request, CommitFlags::NONE, 0)
+            }
+        };
+
+        if blob != 0 {
+            if let Err(err) = property::destroy_blob(self, blob) {
+                eprintln!("{}", err);
+            }
+        }
+        result
+    }
+
+    pub fn is_master(&self) -> bool {
+        let master = self.kms.is_master();
+        self.master.set(master);
+        master
+    }
+
+    // KMS calls fail with EACCES without master, refuse them up front instead
+    pub fn ensure_master(&self) -> Result<()> {
+        if self.master.get() {
+            Ok(())
+        } else {
+            Err(Error::NotMaster)
+        }
+    }
+
+    // Called when we get the VT back or logind resumes the device. Puts back
+    // what every crtc was showing when we released master.
+    pub fn acquire_master(&mut self) -> Result<()> {
+        if !self.master.get() {
+            self.kms.set_master()?;
+            self.master.set(true);
+        }
+
+        for saved in self.saved_crtcs.drain(..) {
+            let info = saved.info;
+            let result = match info.mode {
+                Some(mode) if !saved.connectors.is_empty() => {
+                    self.kms.set_crtc(info.handle, info.fb, &saved.connectors, info.position, Some(mode))
+                }
+                _ => self.kms.set_crtc(info.handle, drm_fb::Handle::from(0u32), &[], (0, 0), None),
+            };
+
+            if let Err(err) = result {
+                eprintln!("{}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Called before switching VT away or when logind pauses the device
+    pub fn release_master(&mut self) -> Result<()> {
+        if !self.master.get() {
+            return Ok(());
+        }
+
+        let crtcs = self.kms.load_crtcs(&self.resources)?;
+        let connectors = self.kms.load_connectors(&self.resources)?;
+        let encoders = self.kms.load_encoders(&self.resources)?;
+
+        self.saved_crtcs = crtcs.into_iter().map(|info| {
+            let connectors = connectors.iter()
+                .filter(|c| {
+                    c.current_encoder
+                        .and_then(|enc| encoders.iter().find(|e| e.handle == enc))
+                        .and_then(|enc| enc.current_crtc) == Some(info.handle)
+                })
+                .map(|c| c.handle)
+                .collect();
+            SavedCrtc { info, connectors }
+        }).collect();
+
+        self.kms.drop_master()?;
+        self.master.set(false);
+        Ok(())
+    }
+
+    // Device number of the card node, udev reports hotplug events against it
+    pub fn devnum(&self) -> Result<libc::dev_t> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(self.as_raw_fd(), &mut stat) } < 0 {
+            return Err(Error::ioctl("fstat", 0u32, io::Error::last_os_error()));
+        }
+
+        Ok(stat.st_rdev)
+    }
+
+    // We lose control over the leased objects until the lease is revoked or
+    // the lessee closes every copy of its fd
+    pub fn create_lease(&self, connectors: &[connector::Handle], crtcs: &[crtc::Handle], planes: &[u32]) -> Result<Lease> {
+        self.ensure_master()?;
+        let objects = connectors.iter().map(|&c| u32::from(c))
+            .chain(crtcs.iter().map(|&c| u32::from(c)))
+            .chain(planes.iter().cloned())
+            .collect::<Vec<_>>();
+
+        lease::create_lease(self, &objects)
+    }
+
+    // Everything a client needs to light up the display on its own: the
+    // connector, a free crtc and that crtc's primary plane
+    pub fn lease_display(&self, display: &Display, in_use: &[crtc::Handle]) -> Result<Lease> {
+        let crtc = self.find_crtc(display.connector, in_use)
+            .ok_or(Error::NoCrtc { connector: display.connector.into() })?;
+        let planes = self.find_plane(crtc, PlaneType::Primary)
+            .map(|p| vec![u32::from(p.handle)])
+            .unwrap_or_default();
+
+        self.create_lease(&[display.connector], &[crtc], &planes)
+    }
+
+    pub fn leases(&self) -> Result<Vec<u32>> {
+        lease::lessees(self)
+    }
+
+    pub fn revoke_lease(&self, lessee_id: u32) -> Result<()> {
+        self.ensure_master()?;
+        lease::revoke_lease(self, lessee_id)
+    }
+
+    // Reloads connectors and encoders after a hotplug and reports how the set
+    // of connected displays changed since the last probe
+    pub fn reprobe(&mut self) -> Result<Vec<DisplayEvent>> {
+        let before = self.displays();
+
+        let resources = self.kms.resources()?;
+        self.connectors = self.kms.load_connectors(&resources)?;
+        self.encoders = self.kms.load_encoders(&resources)?;
+        self.crtcs = self.kms.load_crtcs(&resources)?;
+        self.resources = resources;
+        // MST connectors come and go, and their ids get reused
+        self.property_ids.borrow_mut().clear();
+
+        let after = self.displays();
+        let mut events = Vec::new();
+
+        for old in &before {
+            if !after.iter().any(|d| d.connector == old.connector) {
+                events.push(DisplayEvent::DisplayRemoved(old.connector));
+            }
+        }
+
+        for new in after {
+            match before.iter().find(|d| d.connector == new.connector) {
+                None => events.push(DisplayEvent::DisplayAdded(new)),
+                Some(old) if !mode::same_modes(&old.modes, &new.modes) => events.push(DisplayEvent::ModesChanged(new)),
+                Some(_) => {}
+            }
+        }
+
+        Ok(events)
+    }
+
+    // The returned guard puts back what the displays showed before once dropped
+    pub fn modeset<S: Scanout>(&self, crt: CrtcInfo, displays: &[&Display], surface: &S) -> Result<ModesetGuard> {
+        self.ensure_master()?;
+        let connections = displays.iter().map(|d| d.connector).collect::<Vec<_>>();
+        let previous = displays.iter().filter_map(|d| d.current_crtc(self)).next();
+
+        let framebuffer = surface.framebuffer().ok_or(Error::NoFramebuffer)?;
+        match self.backend {
+            ModesetBackend::Legacy => {
+                self.kms.set_crtc(crt.handle, framebuffer.handle(), &connections, (0, 0), Some(surface.mode()))?
+            }
+            ModesetBackend::Atomic => {
+                self.atomic_modeset(crt.handle, &connections, surface, CommitFlags::ALLOW_MODESET)?
+            }
+        }
+
+        Ok(ModesetGuard { kms: self.kms.clone(), crtc: crt.handle, connectors: connections, previous, armed: true })
+    }
+
+    fn atomic_modeset<S: Scanout>(&self, crtc: crtc::Handle, connections: &[connector::Handle], surface: &S, flags: CommitFlags) -> Result<()> {
+        let framebuffer = surface.framebuffer().ok_or(Error::NoFramebuffer)?;
+        let mode = surface.mode();
+
+        // the kernel keeps its own reference to the blob once committed
+        let mode_blob = atomic::create_mode_blob(self, &mode)?;
+        let result = atomic::modeset_request(self, crtc, connections, framebuffer.handle(), &mode, mode_blob)
+            .and_then(|request| self.atomic_commit(&request, flags, 0));
+
+        if let Err(err) = property::destroy_blob(self, mode_blob) {
+            eprintln!("{}", err);
+        }
+
+        result
+    }
+
+    // Turns the crtc off, any connector it was driving goes dark with it
+    pub fn disable_crtc(&self, crtc: crtc::Handle) -> Result<()> {
+        self.ensure_master()?;
+        self.kms.set_crtc(crtc, drm_fb::Handle::from(0u32), &[], (0, 0), None)
+    }
+
+    pub fn page_flip<S: Scanout>(&self, crtc: crtc::Handle, surface: &S) -> Result<()> {
+        self.ensure_master()?;
+        let fb = surface.framebuffer().ok_or(Error::NoFramebuffer)?;
+
+        match self.backend {
+            ModesetBackend::Legacy => self.kms.page_flip(crtc, fb.handle())?,
+            ModesetBackend::Atomic => {
+                let mut request = atomic::page_flip_request(self, crtc, fb.handle())?;
+
+                let cursor = surface.cursor().filter(|c| c.is_dirty());
+                if let Some(cursor) = cursor {
+                    cursor.add_to_request(self, &mut request)?;
+                }
+
+                self.atomic_commit(&request, CommitFlags::NONBLOCK | CommitFlags::PAGE_FLIP_EVENT, u32::from(crtc) as u64)?;
+
+                if let Some(cursor) = cursor {
+                    cursor.mark_committed();
+                }
+            }
+        }
+
+        self.pending_flips.borrow_mut().push(crtc);
+        Ok(())
+    }
+
+    // On the clock flip events are stamped with
+    pub fn now(&self) -> Duration {
+        self.kms.now()
+    }
+
+    // Hands out the flips wait_for_flip queued up first, the device is only
+    // read once there are none left
+    pub fn receive_events(&self) -> Result<Vec<crtc::Event>> {
+        let queued = mem::take(&mut *self.queued_flips.borrow_mut());
+        if !queued.is_empty() {
+            return Ok(queued.into_iter().map(crtc::Event::PageFlip).collect());
+        }
+
+        self.read_events()
+    }
+
+    // Events are ready without touching the device, don't wait for it to be readable
+    pub fn has_queued_events(&self) -> bool {
+        !self.queued_flips.borrow().is_empty()
+    }
+
+    fn read_events(&self) -> Result<Vec<crtc::Event>> {
+        let events = self.kms.receive_events()?;
+        self.pending_flips.borrow_mut().retain(|&pending| {
+            !events.iter().any(|event| match *event {
+                crtc::Event::PageFlip(ref flip) => flip.crtc == pending,
+                _ => false,
+            })
+        });
+        Ok(events)
+    }
+
+    // Blocks until the crtc's flip is done and returns its timestamp. Flips of
+    // other crtcs that show up in the meantime are queued for receive_events.
+    pub fn wait_for_flip(&self, crtc: crtc::Handle) -> Result<Duration> {
+        {
+            let mut queued = self.queued_flips.borrow_mut();
+            if let Some(index) = queued.iter().position(|flip| flip.crtc == crtc) {
+                return Ok(queued.remove(index).duration);
+            }
+        }
+
+        while self.pending_flips.borrow().contains(&crtc) {
+            let events = self.read_events()?;
+
+            // the flip got lost, e.g. a modeset replaced it
+            if events.is_empty() {
+                self.pending_flips.borrow_mut().retain(|&pending| pending != crtc);
+            }
+
+            let mut timestamp = None;
+            for event in events {
+                match event {
+                    crtc::Event::PageFlip(ref flip) if flip.crtc == crtc => timestamp = Some(flip.duration),
+                    crtc::Event::PageFlip(flip) => self.queued_flips.borrow_mut().push(flip),
+                    _ => {}
+                }
+            }
+            if let Some(timestamp) = timestamp {
+                return Ok(timestamp);
+            }
+        }
+
+        Err(Error::NoFlipPending { crtc: crtc.into() })
+    }
+
+    pub fn initialize_display(&self, _display: &Display, crtc: crtc::Handle, format: gbm::Format, mode: Mode) -> Result<DisplaySurface> {
+        let egl_display = self.egl_display()?;
 
         if !egl::bind_api(egl::EGL_OPENGL_API) {
-            panic!("[egl] failed to bing OpenGL api")
+            return Err(Error::egl("bind OpenGL api"));
         }
 
         const CONFIG_ATTRIBS: [egl::EGLint; 13] = [
@@ -140,53 +796,638 @@ impl Gpu {
             egl::EGL_NONE
         ];
 
-        let config = egl::choose_config(egl_display, &CONFIG_ATTRIBS, 1)
-            .expect("[egl] failed to choose configuration");
+        let config = egl::choose_config(egl_display.as_raw(), &CONFIG_ATTRIBS, 1)
+            .ok_or_else(|| Error::egl("choose config"))?;
 
         const CONTEXT_ATTRIB_LIST: [egl::EGLint; 3] = [
             egl::EGL_CONTEXT_CLIENT_VERSION, 2,
             egl::EGL_NONE
         ];
 
-        let egl_context = egl::create_context(egl_display, ptr::null_mut(), egl::EGL_NO_CONTEXT, &CONTEXT_ATTRIB_LIST)
-            .expect("[egl] failed to create context");
+        let egl_context = egl::create_context(egl_display.as_raw(), ptr::null_mut(), egl::EGL_NO_CONTEXT, &CONTEXT_ATTRIB_LIST)
+            .ok_or_else(|| Error::egl("create context"))?;
 
         let (width, height) = mode.size();
-        let surface = self.gbm_device.create_surface(width as u32, height as u32, format, gbm::BufferObjectFlags::SCANOUT | gbm::BufferObjectFlags::RENDERING)
-            .expect("[gbm] failed to create surface");
+        let modifiers = self.scanout_modifiers(crtc, format);
+        let surface = GbmSurface::new(self, u32::from(width), u32::from(height), format, &modifiers)?;
+
+        let egl_surface = egl::create_window_surface(egl_display.as_raw(), config, surface.as_raw() as _, &[])
+            .ok_or_else(|| Error::egl("create window surface"))?;
+
+        Ok(DisplaySurface::new(egl_display, egl_context, egl_surface, surface, crtc, mode))
+    }
+
+    // Modifiers the crtc's primary plane takes for the format according to its
+    // IN_FORMATS. Empty when the plane doesn't list any, gbm falls back to
+    // the implicit layout then.
+    pub fn scanout_modifiers(&self, crtc: crtc::Handle, format: gbm::Format) -> Vec<u64> {
+        match self.find_plane(crtc, PlaneType::Primary) {
+            Some(plane) => plane.modifiers_for(format.as_ffi()).into_iter().filter(|&m| m != bo::MOD_INVALID).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // All surfaces of a gpu share one EGL display, terminating it for one
+    // would take the others' contexts along
+    fn egl_display(&self) -> Result<Rc<EglDisplay>> {
+        use cognitive_graphics::egl_tools;
+
+        if let Some(display) = self.egl_display.borrow().upgrade() {
+            return Ok(display);
+        }
+
+        let raw = egl_tools::get_gbm_display(self.gbm_device()?.as_raw() as _)
+            .map_err(|_| Error::egl("get gbm display"))?;
+
+        let mut maj: egl::EGLint = 0;
+        let mut min: egl::EGLint = 0;
+        if !egl::initialize(raw, &mut maj, &mut min) {
+            return Err(Error::egl("initialize"));
+        }
+
+        println!("EGL major: {}, minor: {}", maj, min);
+
+        let display = Rc::new(EglDisplay::new(raw));
+        *self.egl_display.borrow_mut() = Rc::downgrade(&display);
+        Ok(display)
+    }
+}
+
+// Puts back what the crtc showed before our modeset once dropped, unwinding
+// included. Restoring goes through the legacy ioctl, which atomic drivers
+// still support.
+pub struct ModesetGuard {
+    kms: Rc<dyn KmsDevice>,
+    crtc: crtc::Handle,
+    connectors: Vec<connector::Handle>,
+    previous: Option<CrtcInfo>,
+    armed: bool,
+}
+
+impl ModesetGuard {
+    pub fn previous(&self) -> Option<CrtcInfo> {
+        self.previous
+    }
+
+    // For a modeset on top of our own, which must restore what came before the first one
+    pub fn set_previous(&mut self, previous: Option<CrtcInfo>) {
+        self.previous = previous;
+    }
+
+    // Leaves the crtc as it is, e.g. when the connector is gone
+    pub fn dismiss(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for ModesetGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let previous = self.previous.filter(|info| info.mode.is_some());
+        if previous.is_none_or(|info| info.handle != self.crtc) {
+            if let Err(err) = self.kms.set_crtc(self.crtc, drm_fb::Handle::from(0u32), &[], (0, 0), None) {
+                eprintln!("{}", err);
+            }
+        }
+
+        if let Some(info) = previous {
+            if let Err(err) = self.kms.set_crtc(info.handle, info.fb, &self.connectors, info.position, info.mode) {
+                eprintln!("{}", err);
+            }
+        }
+    }
+}
 
-        let egl_surface = egl::create_window_surface(egl_display, config, surface.as_raw() as _, &[])
-            .expect("[egl] failed to create window surface");
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+}
+
+// vendor:device the way lspci -n prints it
+impl fmt::Display for PciId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.device)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GpuInfo {
+    pub sysname: String,
+    pub devnode: PathBuf,
+    pub syspath: PathBuf,
+    pub seat: String,
+    pub pci_id: Option<PciId>,
+    pub driver: Option<String>,
+    pub boot_vga: bool,
+    pub platform: bool,
+    pub preferred_primary: bool,
+}
+
+impl GpuInfo {
+    // simpledrm/efifb style stubs only exist until the real driver takes over,
+    // they should never be picked while a real gpu is around
+    pub fn is_firmware_framebuffer(&self) -> bool {
+        let stubs = ["simple-framebuffer", "simpledrm", "efi-framebuffer", "vesa-framebuffer"];
+        stubs.iter().any(|stub| self.driver.as_deref() == Some(*stub))
+    }
+}
+
+pub fn enumerate(seat: &str) -> Result<Vec<GpuInfo>> {
+    let context = udev::Context::new()?;
+    let mut enumerator = udev::Enumerator::new(&context)?;
+    enumerator.match_subsystem("drm")?;
+    enumerator.match_sysname("card[0-9]*")?;
+
+    let mut gpus = enumerator.scan_devices()?
+        .filter_map(|device| gpu_info_from_udev(&device))
+        .filter(|gpu| gpu.seat == seat)
+        .collect::<Vec<_>>();
 
-        DisplaySurface::new(egl_display, egl_context, egl_surface, surface, crtc, mode, format)
+    gpus.sort_by_key(|gpu| gpu_number(&gpu.sysname));
+    Ok(gpus)
+}
+
+// Same order of preference logind and mutter use to pick the primary gpu:
+// explicitly tagged device, then the pci device the firmware booted on,
+// then the first platform device and lastly whatever card comes first.
+pub fn primary_gpu(gpus: &[GpuInfo]) -> Option<&GpuInfo> {
+    let real_gpus = || gpus.iter().filter(|gpu| !gpu.is_firmware_framebuffer());
+
+    real_gpus().find(|gpu| gpu.preferred_primary)
+        .or_else(|| real_gpus().find(|gpu| gpu.boot_vga))
+        .or_else(|| real_gpus().find(|gpu| gpu.platform))
+        .or_else(|| real_gpus().next())
+        .or_else(|| gpus.first())
+}
+
+fn gpu_info_from_udev(device: &udev::Device) -> Option<GpuInfo> {
+    // card0-HDMI-A-1 and friends are connectors, not cards
+    if device.devtype().and_then(|t| t.to_str()) != Some("drm_minor") {
+        return None;
     }
+
+    let sysname = device.sysname().to_str()?.to_owned();
+    let devnode = device.devnode()?.to_owned();
+    let syspath = device.syspath().to_owned();
+
+    let seat = device.property_value("ID_SEAT")
+        .and_then(|s| s.to_str())
+        .unwrap_or("seat0")
+        .to_owned();
+
+    let preferred_primary = device.property_value("TAGS")
+        .and_then(|t| t.to_str())
+        .map(|tags| tags.split(':').any(|tag| tag == "mutter-device-preferred-primary"))
+        .unwrap_or(false);
+
+    let parent = device.parent();
+    let driver = parent.as_ref()
+        .and_then(|p| p.driver())
+        .and_then(|d| d.to_str())
+        .map(|d| d.to_owned());
+
+    let pci = device.parent_with_subsystem(Path::new("pci")).ok().and_then(|p| p);
+    let pci_id = pci.as_ref().and_then(|pci| {
+        let vendor = parse_hex_attribute(pci, "vendor")?;
+        let device = parse_hex_attribute(pci, "device")?;
+        Some(PciId { vendor, device })
+    });
+    let boot_vga = pci.as_ref()
+        .and_then(|pci| pci.attribute_value("boot_vga"))
+        .map(|v| v == "1")
+        .unwrap_or(false);
+
+    let platform = pci.is_none() && device.parent_with_subsystem(Path::new("platform"))
+        .ok()
+        .and_then(|p| p)
+        .is_some();
+
+    Some(GpuInfo { sysname, devnode, syspath, seat, pci_id, driver, boot_vga, platform, preferred_primary })
+}
+
+fn parse_hex_attribute(device: &udev::Device, attribute: &str) -> Option<u16> {
+    let value = device.attribute_value(attribute)?.to_str()?;
+    u16::from_str_radix(value.trim_start_matches("0x"), 16).ok()
 }
 
-pub fn open(path: &'static str) -> Gpu {
+fn gpu_number(sysname: &str) -> u32 {
+    sysname.trim_start_matches("card").parse().unwrap_or(u32::MAX)
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Gpu> {
+    let path = path.as_ref();
     let mut options = OpenOptions::new();
     options.read(true);
     options.write(true);
 
-    let gpu_file = options.open(path).expect(&format!("Failed to open {}", path));
-    let gbm_device = gbm::Device::new(DeviceFile(gpu_file)).expect("Failed to create a gbm device");
-
-    let resource_handles = gbm_device.resource_handles().expect("Failed to get resource handles from gbm device");
-    let connectors = load_information(&gbm_device, resource_handles.connectors());
-    let encoders = load_information(&gbm_device, resource_handles.encoders());
-    let crtcs = load_information(&gbm_device, resource_handles.crtcs());
-
-    Gpu { gbm_device, connectors, encoders, crtcs }
-}
-
-fn load_information<T, U>(card: &gbm::Device<DeviceFile>, handles: &[T]) -> Vec<U>
-    where
-        T: ResourceHandle,
-        U: ResourceInfo<Handle = T>,
-{
-    handles
-        .iter()
-        .map(|&h| {
-            card.resource_info(h).expect("Could not load resource info")
-        })
-        .collect()
-}
\ No newline at end of file
+    let gpu_file = options.open(path)
+        .map_err(|error| Error::Open { path: path.to_owned(), error })?;
+    let file = DeviceFile::from(gpu_file);
+    // Mesa may have no driver for the display controller, e.g. simpledrm.
+    // The outputs fall back to dumb buffers then.
+    let gbm_device = match gbm::Device::new(file.clone()) {
+        Ok(device) => Some(device),
+        Err(err) => {
+            eprintln!("{}", Error::gbm("create device", err));
+            None
+        }
+    };
+    let kms = Rc::new(file);
+    let gpu = Gpu::new(kms, gbm_device)?;
+
+    // someone else (another compositor, a VT we're not on) may hold it
+    if !gpu.master.get() {
+        eprintln!("[drm] {} opened without DRM master, modesetting is suspended", path.display());
+    }
+    // frame pacing compares flip timestamps against CLOCK_MONOTONIC
+    if !gpu.caps.timestamp_monotonic {
+        eprintln!("[drm] {} doesn't timestamp flips with CLOCK_MONOTONIC, frame pacing will be off", gpu.caps.driver.name);
+    }
+    Ok(gpu)
+}
+
+fn assign_from(candidates: &[Vec<crtc::Handle>], taken: &mut Vec<crtc::Handle>, assignment: &mut Vec<crtc::Handle>) -> bool {
+    let (first, rest) = match candidates.split_first() {
+        Some(split) => split,
+        None => return true,
+    };
+
+    for &crtc in first {
+        if taken.contains(&crtc) {
+            continue;
+        }
+
+        taken.push(crtc);
+        assignment.push(crtc);
+
+        if assign_from(rest, taken, assignment) {
+            return true;
+        }
+
+        taken.pop();
+        assignment.pop();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use drm::control::{connector, crtc, Mode};
+    use drm::control::framebuffer as drm_fb;
+    use drm::ffi;
+
+    use display::Scanout;
+    use edid::Edid;
+    use error::Error;
+    use fake::FakeDevice;
+    use framebuffer::Framebuffer;
+    use hotplug::DisplayEvent;
+    use kms::KmsDevice;
+    use mode;
+    use modeline;
+    use property::{ObjectType, PropertyKind, PropertyValue};
+    use super::{primary_gpu, Gpu, GpuInfo};
+
+    struct TestScanout {
+        mode: Mode,
+        framebuffer: Framebuffer,
+    }
+
+    impl TestScanout {
+        fn new(gpu: &Gpu, mode: Mode, fb: u32) -> TestScanout {
+            let (width, height) = mode.size();
+            let framebuffer = Framebuffer::new(gpu, drm_fb::Handle::from(fb), u32::from(width), u32::from(height));
+            TestScanout { mode, framebuffer }
+        }
+    }
+
+    impl Scanout for TestScanout {
+        fn mode(&self) -> Mode {
+            self.mode
+        }
+
+        fn framebuffer(&self) -> Option<&Framebuffer> {
+            Some(&self.framebuffer)
+        }
+    }
+
+    fn full_hd() -> Mode {
+        modeline::cvt_reduced(1920, 1080, 60.0).unwrap()
+    }
+
+    fn frame_time(mode: &Mode) -> Duration {
+        Duration::from_nanos(1_000_000_000_000 / u64::from(mode::refresh_mhz(mode)))
+    }
+
+    #[test]
+    fn displays_skip_disconnected_connectors() {
+        let fake = Rc::new(FakeDevice::new());
+        fake.add_crtc();
+        let hdmi = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+        let dp = fake.add_connector(connector::Type::DisplayPort, vec![full_hd()], 0b1);
+        fake.set_connected(dp, false);
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+        let displays = gpu.displays();
+
+        assert_eq!(displays.len(), 1);
+        assert_eq!(displays[0].connector, hdmi);
+        assert_eq!(displays[0].name, "HDMI-A-1");
+        assert!(displays[0].preferred_mode().is_some());
+    }
+
+    fn edid(name: &str) -> Edid {
+        Edid {
+            manufacturer: "DEL".to_owned(),
+            product_code: 0xa0c4,
+            serial: 0,
+            serial_string: None,
+            name: Some(name.to_owned()),
+            year: 2020,
+            version: (1, 4),
+            size_mm: None,
+            timings: Vec::new(),
+            cta: None,
+        }
+    }
+
+    #[test]
+    fn displays_keep_the_edid_read_at_probe() {
+        let fake = Rc::new(FakeDevice::new());
+        fake.add_crtc();
+        let hdmi = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+        fake.set_edid(hdmi, Some(edid("DELL U2720Q")));
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        assert_eq!(gpu.displays()[0].identifier, "DEL DELL U2720Q");
+
+        // a different sink on the same port only shows up after a reprobe
+        fake.set_edid(hdmi, Some(edid("DELL P2419H")));
+        assert_eq!(gpu.displays()[0].identifier, "DEL DELL U2720Q");
+        gpu.reprobe().unwrap();
+        assert_eq!(gpu.displays()[0].identifier, "DEL DELL P2419H");
+    }
+
+    #[test]
+    fn assign_crtcs_backtracks() {
+        let fake = Rc::new(FakeDevice::new());
+        let first = fake.add_crtc();
+        let second = fake.add_crtc();
+        let any = fake.add_connector(connector::Type::DisplayPort, vec![full_hd()], 0b11);
+        let only_first = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b01);
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+
+        assert_eq!(gpu.find_crtc(only_first, &[first]), None);
+        assert_eq!(gpu.assign_crtcs(&[any, only_first], &[]), Some(vec![second, first]));
+        assert_eq!(gpu.assign_crtcs(&[any, only_first], &[second]), None);
+    }
+
+    #[test]
+    fn modeset_guard_restores_previous_crtc() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        let connector = fake.add_connector(connector::Type::EmbeddedDisplayPort, vec![full_hd()], 0b1);
+        // what the firmware left on screen
+        fake.set_crtc(crtc, drm_fb::Handle::from(1u32), &[connector], (0, 0), Some(full_hd())).unwrap();
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+        let display = gpu.displays().remove(0);
+        let surface = TestScanout::new(&gpu, full_hd(), 2);
+
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &surface).unwrap();
+        assert_eq!(fake.scanout(crtc).unwrap().fb, drm_fb::Handle::from(2u32));
+
+        drop(guard);
+        assert_eq!(fake.scanout(crtc).unwrap().fb, drm_fb::Handle::from(1u32));
+    }
+
+    #[test]
+    fn modeset_guard_disables_crtc_without_previous() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+        let display = gpu.displays().remove(0);
+        let surface = TestScanout::new(&gpu, full_hd(), 2);
+
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &surface).unwrap();
+        assert!(fake.scanout(crtc).unwrap().mode.is_some());
+
+        drop(guard);
+        assert!(fake.scanout(crtc).unwrap().mode.is_none());
+    }
+
+    #[test]
+    fn acquire_master_restores_the_saved_crtcs() {
+        let fake = Rc::new(FakeDevice::new());
+        let ours = fake.add_crtc();
+        let spare = fake.add_crtc();
+        let edp = fake.add_connector(connector::Type::EmbeddedDisplayPort, vec![full_hd()], 0b11);
+        let hdmi = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b11);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        assert!(gpu.is_master());
+        let display = gpu.displays().into_iter().find(|d| d.name == "eDP-1").unwrap();
+        let surface = TestScanout::new(&gpu, full_hd(), 2);
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &surface).unwrap();
+
+        gpu.release_master().unwrap();
+        assert!(!fake.is_master());
+        match gpu.modeset(gpu.crtcs[1], &[&display], &surface) {
+            Err(Error::NotMaster) => {}
+            _ => panic!("modeset went through without master"),
+        }
+
+        // the session we switched to moves everything around
+        fake.set_master().unwrap();
+        fake.set_crtc(ours, drm_fb::Handle::from(7u32), &[hdmi], (0, 0), Some(full_hd())).unwrap();
+        fake.set_crtc(spare, drm_fb::Handle::from(8u32), &[edp], (0, 0), Some(full_hd())).unwrap();
+        fake.drop_master().unwrap();
+
+        gpu.acquire_master().unwrap();
+        assert!(fake.is_master());
+        let restored = fake.scanout(ours).unwrap();
+        assert_eq!(restored.fb, drm_fb::Handle::from(2u32));
+        assert!(mode::same_timings(&restored.mode.unwrap(), &full_hd()));
+        assert!(fake.scanout(spare).unwrap().mode.is_none());
+
+        let encoder = fake.connector_info(edp).unwrap().current_encoder.unwrap();
+        assert_eq!(fake.encoder_info(encoder).unwrap().current_crtc, Some(ours));
+        assert_eq!(fake.connector_info(hdmi).unwrap().current_encoder, None);
+        drop(guard);
+    }
+
+    #[test]
+    fn page_flips_complete_on_vblank() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+        let display = gpu.displays().remove(0);
+        let front = TestScanout::new(&gpu, full_hd(), 2);
+        let back = TestScanout::new(&gpu, full_hd(), 3);
+
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &front).unwrap();
+        let start = fake.now();
+        let period = frame_time(&full_hd());
+
+        gpu.page_flip(crtc, &back).unwrap();
+        assert!(fake.is_flip_pending(crtc));
+        match gpu.page_flip(crtc, &front) {
+            Err(Error::Drm { errno, .. }) => assert_eq!(errno, ::libc::EBUSY),
+            _ => panic!("second flip in the same frame went through"),
+        }
+
+        assert_eq!(gpu.wait_for_flip(crtc).unwrap(), start + period);
+        assert_eq!(fake.scanout(crtc).unwrap().fb, drm_fb::Handle::from(3u32));
+
+        // a flip queued late in the frame still lands on the next vblank
+        fake.advance(period * 3 / 2);
+        gpu.page_flip(crtc, &front).unwrap();
+        assert_eq!(gpu.wait_for_flip(crtc).unwrap(), start + period * 3);
+        assert!(!fake.is_flip_pending(crtc));
+
+        guard.dismiss();
+    }
+
+    #[test]
+    fn flips_of_other_crtcs_are_kept_for_later() {
+        let fake = Rc::new(FakeDevice::new());
+        let slow = fake.add_crtc();
+        let fast = fake.add_crtc();
+        let fast_mode = modeline::cvt_reduced(1920, 1080, 120.0).unwrap();
+        let first = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b01);
+        let second = fake.add_connector(connector::Type::DisplayPort, vec![fast_mode], 0b10);
+
+        let gpu = Gpu::new(fake.clone(), None).unwrap();
+        let displays = gpu.displays();
+        let display = |connector| displays.iter().find(|d| d.connector == connector).unwrap();
+        let (slow_front, slow_back) = (TestScanout::new(&gpu, full_hd(), 2), TestScanout::new(&gpu, full_hd(), 3));
+        let (fast_front, fast_back) = (TestScanout::new(&gpu, fast_mode, 4), TestScanout::new(&gpu, fast_mode, 5));
+
+        let slow_guard = gpu.modeset(gpu.crtcs[0], &[display(first)], &slow_front).unwrap();
+        let fast_guard = gpu.modeset(gpu.crtcs[1], &[display(second)], &fast_front).unwrap();
+        let start = fake.now();
+
+        gpu.page_flip(slow, &slow_back).unwrap();
+        gpu.page_flip(fast, &fast_back).unwrap();
+
+        // the fast crtc's flip comes in first and has to wait for dispatch
+        assert_eq!(gpu.wait_for_flip(slow).unwrap(), start + frame_time(&full_hd()));
+        assert!(gpu.has_queued_events());
+
+        let events = gpu.receive_events().unwrap();
+        match events.as_slice() {
+            [crtc::Event::PageFlip(flip)] => {
+                assert_eq!(flip.crtc, fast);
+                assert_eq!(flip.duration, start + frame_time(&fast_mode));
+            }
+            _ => panic!("the fast crtc's flip got lost"),
+        }
+        assert!(!gpu.has_queued_events());
+
+        slow_guard.dismiss();
+        fast_guard.dismiss();
+    }
+
+    fn gpu_info(sysname: &str, driver: &str, boot_vga: bool) -> GpuInfo {
+        GpuInfo {
+            sysname: sysname.to_owned(),
+            devnode: format!("/dev/dri/{}", sysname).into(),
+            syspath: format!("/sys/class/drm/{}", sysname).into(),
+            seat: "seat0".to_owned(),
+            pci_id: None,
+            driver: Some(driver.to_owned()),
+            boot_vga,
+            platform: false,
+            preferred_primary: false,
+        }
+    }
+
+    #[test]
+    fn primary_gpu_prefers_boot_vga_then_the_first_card() {
+        let sysnames = |gpus: &[GpuInfo]| primary_gpu(gpus).map(|gpu| gpu.sysname.clone());
+
+        let gpus = [gpu_info("card0", "i915", false), gpu_info("card1", "amdgpu", true)];
+        assert_eq!(sysnames(&gpus), Some("card1".to_owned()));
+
+        let gpus = [gpu_info("card0", "i915", false), gpu_info("card1", "amdgpu", false)];
+        assert_eq!(sysnames(&gpus), Some("card0".to_owned()));
+
+        // the firmware framebuffer only wins when it's all there is
+        let gpus = [gpu_info("card0", "simpledrm", true), gpu_info("card1", "amdgpu", false)];
+        assert_eq!(sysnames(&gpus), Some("card1".to_owned()));
+        assert_eq!(sysnames(&gpus[..1]), Some("card0".to_owned()));
+        assert_eq!(sysnames(&[]), None);
+    }
+
+    #[test]
+    fn reprobe_reports_hotplug() {
+        let fake = Rc::new(FakeDevice::new());
+        fake.add_crtc();
+        let hdmi = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+        let dp = fake.add_connector(connector::Type::DisplayPort, vec![full_hd()], 0b1);
+        fake.set_connected(dp, false);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        fake.set_connected(hdmi, false);
+        fake.set_connected(dp, true);
+
+        let events = gpu.reprobe().unwrap();
+        assert_eq!(events.len(), 2);
+        match events[0] {
+            DisplayEvent::DisplayRemoved(connector) => assert_eq!(connector, hdmi),
+            _ => panic!("expected {:?} to be removed", hdmi),
+        }
+        match events[1] {
+            DisplayEvent::DisplayAdded(ref display) => assert_eq!(display.name, "DP-1"),
+            _ => panic!("expected {:?} to be added", dp),
+        }
+        assert!(gpu.reprobe().unwrap().is_empty());
+    }
+
+    #[test]
+    fn property_names_are_looked_up_once_per_object() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        let hdmi = fake.add_connector(connector::Type::HDMIA, vec![full_hd()], 0b1);
+        let flag = PropertyKind::Range { min: 0, max: 1 };
+        let vrr = fake.add_property(hdmi.into(), "vrr_capable", flag.clone(), 0);
+        fake.add_property(hdmi.into(), "non-desktop", flag.clone(), 0);
+        let active = fake.add_property(crtc.into(), "ACTIVE", flag, 1);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        let lookups = fake.property_lookups();
+
+        assert!(!gpu.vrr_capable(hdmi));
+        assert!(!gpu.non_desktop(hdmi));
+        assert_eq!(fake.property_lookups(), lookups + 2);
+
+        // only the ids are kept, values are read again every time
+        fake.set_property_value(hdmi.into(), vrr, 1);
+        assert!(gpu.vrr_capable(hdmi));
+        let property = gpu.get_property(hdmi, ObjectType::Connector, "vrr_capable").unwrap().unwrap();
+        assert_eq!(property.value, PropertyValue::Unsigned(1));
+        assert_eq!(fake.property_lookups(), lookups + 3);
+
+        assert_eq!(gpu.find_property(crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "ACTIVE").unwrap(), Some((active, 1)));
+        assert_eq!(gpu.property_id(crtc.into(), ffi::DRM_MODE_OBJECT_CRTC, "MODE_ID").unwrap(), None);
+        assert_eq!(fake.property_lookups(), lookups + 4);
+
+        // a connector id can come back as a different MST sink after a hotplug
+        gpu.reprobe().unwrap();
+        assert!(gpu.vrr_capable(hdmi));
+        assert_eq!(fake.property_lookups(), lookups + 6);
+    }
+}
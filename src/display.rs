@@ -1,67 +1,175 @@
-use std::ops::Deref;
+use std::rc::Rc;
+use std::time::Duration;
 
 use drm::control::crtc;
-use drm::control::encoder;
 use drm::control::connector;
 use drm::control::Mode as DrmMode;
 use drm::control::framebuffer as drm_fb;
-use drm::control::ResourceInfo;
 
 use egl;
-use gbm;
 use gbm::Format;
 
+use bo::{GbmSurface, LockedBuffer};
+use clock::FrameClock;
+use cursor::{Cursor, CursorImage};
 use device::Gpu;
+use kms::{CrtcInfo, EncoderInfo};
+use error::{Error, Result};
 use framebuffer::Framebuffer;
+use mode;
 
 pub struct Display {
     pub identifier: String,
+    pub name: String,
     pub modes: Vec<DrmMode>,
     pub connector: connector::Handle,
-    pub encoder: Option<encoder::Info>
+    pub encoder: Option<EncoderInfo>,
+    pub vrr_capable: bool,
+    pub non_desktop: bool,
 }
 
 impl Display {
-    pub fn current_crtc(&self, gpu: &Gpu) -> Option<crtc::Info> {
+    // The mode flagged preferred by the connector, usually the panel's native
+    // one. Falls back to the first mode for connectors that don't flag any.
+    pub fn preferred_mode(&self) -> Option<DrmMode> {
+        self.modes.iter().find(|m| mode::is_preferred(m)).or_else(|| self.modes.first()).cloned()
+    }
+
+    // Interlaced and doublescan modes are left out, those are never what a
+    // caller asking for a resolution wants
+    pub fn progressive_modes(&self) -> Vec<DrmMode> {
+        self.modes.iter().filter(|m| !mode::is_interlaced(m) && !mode::is_doublescan(m)).cloned().collect()
+    }
+
+    // Exact resolution wins over refresh rate, without a refresh the preferred
+    // or otherwise fastest mode at that resolution is picked
+    pub fn closest_mode(&self, width: u32, height: u32, refresh_mhz: Option<u32>) -> Option<DrmMode> {
+        self.progressive_modes().into_iter().min_by_key(|m| {
+            let (w, h) = m.size();
+            let size_diff = (i64::from(w) - i64::from(width)).abs() + (i64::from(h) - i64::from(height)).abs();
+            let refresh = i64::from(mode::refresh_mhz(m));
+            let refresh_diff = match refresh_mhz {
+                Some(wanted) => (refresh - i64::from(wanted)).abs(),
+                None if mode::is_preferred(m) => i64::MIN,
+                None => -refresh,
+            };
+            (size_diff, refresh_diff, !mode::is_preferred(m))
+        })
+    }
+
+    pub fn current_crtc(&self, gpu: &Gpu) -> Option<CrtcInfo> {
         self.encoder
-            .and_then(|enc| enc.current_crtc())
+            .and_then(|enc| enc.current_crtc)
             .and_then(|crtc| gpu.get_crtc(crtc))
     }
 }
 
+// Whatever a crtc can be pointed at: the framebuffer to show, the mode it's
+// sized for and the cursor that rides along with flips
+pub trait Scanout {
+    fn mode(&self) -> DrmMode;
+    fn framebuffer(&self) -> Option<&Framebuffer>;
+
+    fn cursor(&self) -> Option<&Cursor> {
+        None
+    }
+}
+
+// Initialized EGL display, terminated once the last surface using it is gone
+pub struct EglDisplay(egl::EGLDisplay);
+
+impl EglDisplay {
+    pub fn new(raw: egl::EGLDisplay) -> EglDisplay {
+        EglDisplay(raw)
+    }
+
+    pub fn as_raw(&self) -> egl::EGLDisplay {
+        self.0
+    }
+}
+
+impl Drop for EglDisplay {
+    fn drop(&mut self) {
+        if !egl::terminate(self.0) {
+            eprintln!("{}", Error::egl("terminate"));
+        }
+    }
+}
+
 pub struct Surface {
-    egl_display: egl::EGLDisplay,
+    egl_display: Rc<EglDisplay>,
     egl_context: egl::EGLContext,
     egl_surface: egl::EGLSurface,
-    gbm_surface: gbm::Surface<drm_fb::Handle>,
+    gbm_surface: GbmSurface,
     pub mode: DrmMode,
     pub format: Format,
-    pub framebuffer: Option<Framebuffer>,
+    framebuffer: Option<drm_fb::Handle>,
     crtc: crtc::Handle,
-    current_bo: Option<gbm::SurfaceBufferHandle<drm_fb::Handle>>,
-    next_bo: Option<gbm::SurfaceBufferHandle<drm_fb::Handle>>,
+    current_bo: Option<LockedBuffer>,
+    next_bo: Option<LockedBuffer>,
+    cursor: Option<Cursor>,
+    flip_pending: bool,
+    // keyed by the buffer object they were made from
+    framebuffers: Vec<(usize, Framebuffer)>,
+    clock: FrameClock,
 }
 
 impl Surface {
-    pub fn new(egl_display: egl::EGLDisplay, egl_context: egl::EGLContext, egl_surface: egl::EGLSurface, gbm_surface: gbm::Surface<drm_fb::Handle>, crtc: crtc::Handle, mode: DrmMode, format: Format) -> Surface {
-        Surface { egl_display, egl_context, egl_surface, gbm_surface, mode, format, framebuffer: None, current_bo: None, next_bo: None, crtc }
+    pub fn new(egl_display: Rc<EglDisplay>, egl_context: egl::EGLContext, egl_surface: egl::EGLSurface, gbm_surface: GbmSurface, crtc: crtc::Handle, mode: DrmMode) -> Surface {
+        let format = gbm_surface.format;
+        Surface { egl_display, egl_context, egl_surface, gbm_surface, mode, format, framebuffer: None, current_bo: None, next_bo: None, crtc, cursor: None, flip_pending: false, framebuffers: Vec::new(), clock: FrameClock::new(&mode) }
+    }
+
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+
+    pub fn set_cursor(&mut self, gpu: &Gpu, image: CursorImage) -> Result<()> {
+        let crtc = self.crtc;
+        self.cursor
+            .get_or_insert_with(|| Cursor::new(gpu, crtc))
+            .set_image(gpu, image)
     }
 
-    pub fn make_current(&self) {
-        if !egl::make_current(self.egl_display, self.egl_surface, self.egl_surface, self.egl_context) {
-            panic!("[egl] failed to make DisplaySurface the current one");
+    pub fn move_cursor(&mut self, gpu: &Gpu, x: i32, y: i32) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.move_to(gpu, x, y),
+            None => Ok(()),
         }
     }
 
-    pub fn swap_buffers(&mut self, gpu: &Gpu) {
-        egl::swap_buffers(self.egl_display, self.egl_surface);
+    pub fn hide_cursor(&mut self, gpu: &Gpu) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.hide(gpu),
+            None => Ok(()),
+        }
+    }
+
+    pub fn make_current(&self) -> Result<()> {
+        if !egl::make_current(self.egl_display.as_raw(), self.egl_surface, self.egl_surface, self.egl_context) {
+            return Err(Error::egl("make current"));
+        }
+
+        Ok(())
+    }
 
-        let mut gbm_bo = self.gbm_surface.lock_front_buffer()
-            .expect("[gbm] failed to lock front buffer");
+    pub fn swap_buffers(&mut self, gpu: &Gpu) -> Result<()> {
+        if !egl::swap_buffers(self.egl_display.as_raw(), self.egl_surface) {
+            return Err(Error::egl("swap buffers"));
+        }
 
-        let drm_fb = Self::get_framebuffer_from_gbm_buffer(gpu, &mut gbm_bo);
+        let gbm_bo = self.gbm_surface.lock_front_buffer()?;
 
-        let (width, height) = self.mode.size();
+        let cached = self.framebuffers.iter().find(|&&(bo, _)| bo == gbm_bo.id()).map(|(_, fb)| fb.handle());
+        let drm_fb = match cached {
+            Some(handle) => handle,
+            None => {
+                let framebuffer = Framebuffer::create(gpu, &gbm_bo.layout())?;
+                let handle = framebuffer.handle();
+                self.framebuffers.push((gbm_bo.id(), framebuffer));
+                handle
+            }
+        };
 
         if self.current_bo.is_none() {
             self.current_bo = Some(gbm_bo);
@@ -69,43 +177,155 @@ impl Surface {
             self.next_bo = Some(gbm_bo);
         }
 
-        self.framebuffer = Some(Framebuffer{ drm_fb, width: width as u32, height: height as u32 });
+        self.framebuffer = Some(drm_fb);
+        Ok(())
     }
 
-    pub fn present(&mut self, gpu: &Gpu) {
-        self.swap_buffers(gpu);
-        gpu.page_flip(self.crtc, self);
-
-        'waitloop:
-        loop {
-            let events = gpu.receive_events();
-            for e in events {
-                match e {
-                    crtc::Event::PageFlip(_) => break 'waitloop,
-                    _ => {}
-                }
-            }
+    // Schedules the flip and returns right away, the caller has to hand the
+    // flip event back through page_flip_complete before presenting again
+    pub fn queue_present(&mut self, gpu: &Gpu) -> Result<()> {
+        if self.flip_pending {
+            return Err(Error::FlipPending { crtc: self.crtc.into() });
         }
 
+        self.swap_buffers(gpu)?;
+        let now = gpu.now();
+        gpu.page_flip(self.crtc, self)?;
+        self.clock.flip_queued(now);
+        self.flip_pending = true;
+        Ok(())
+    }
+
+    pub fn is_flip_pending(&self) -> bool {
+        self.flip_pending
+    }
+
+    // Takes the CLOCK_MONOTONIC timestamp the flip event carries
+    pub fn page_flip_complete(&mut self, timestamp: Duration) {
+        self.flip_pending = false;
         self.current_bo.take();
         self.current_bo = self.next_bo.take();
+        self.clock.flip_complete(timestamp);
     }
 
-    fn get_framebuffer_from_gbm_buffer(gpu: &Gpu, bo: &mut gbm::SurfaceBufferHandle<drm_fb::Handle>) -> drm_fb::Handle {
-        if let Ok(Some(handle)) = bo.userdata() {
-            return handle.to_owned();
+    // The panel follows our flips once VRR is on, otherwise it doesn't change anything
+    pub fn set_vrr(&mut self, gpu: &Gpu, enabled: bool) -> Result<()> {
+        gpu.set_vrr(self.crtc, enabled)?;
+        self.clock.set_vrr(enabled);
+        Ok(())
+    }
+
+    pub fn clock(&self) -> &FrameClock {
+        &self.clock
+    }
+}
+
+// The crtc should be off or showing something else by now, its framebuffers
+// go away here
+impl Drop for Surface {
+    fn drop(&mut self) {
+        // locked buffers go back to the gbm surface before it's destroyed
+        self.framebuffer = None;
+        self.current_bo.take();
+        self.next_bo.take();
+        self.framebuffers.clear();
+
+        let egl_display = self.egl_display.as_raw();
+        egl::make_current(egl_display, egl::EGL_NO_SURFACE, egl::EGL_NO_SURFACE, egl::EGL_NO_CONTEXT);
+        if !egl::destroy_surface(egl_display, self.egl_surface) {
+            eprintln!("{}", Error::egl("destroy surface"));
         }
+        if !egl::destroy_context(egl_display, self.egl_context) {
+            eprintln!("{}", Error::egl("destroy context"));
+        }
+    }
+}
 
-        let handle = {
-            let buf_obj: &gbm::BufferObject<drm_fb::Handle> = bo.deref();
-            let fb = drm_fb::create(gpu.deref(), buf_obj)
-                .expect("[drm] failed to create framebuffer");
-            fb.handle()
-        };
+impl Scanout for Surface {
+    fn mode(&self) -> DrmMode {
+        self.mode
+    }
+
+    fn framebuffer(&self) -> Option<&Framebuffer> {
+        let current = self.framebuffer?;
+        self.framebuffers.iter().map(|(_, fb)| fb).find(|fb| fb.handle() == current)
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use drm::control::{connector, Mode};
+    use drm::ffi;
+
+    use mode;
+    use modeline;
+    use super::Display;
+
+    fn flagged(mode: Mode, type_: u32, flags: u32) -> Mode {
+        let mut raw = mode::as_raw(&mode);
+        raw.type_ |= type_;
+        raw.flags |= flags;
+        mode::from_raw(raw)
+    }
 
-        if let Err(err) = bo.set_userdata(handle) {
-            eprintln!("[gbm] failed to set user data: {}", err);
+    fn display(modes: Vec<Mode>) -> Display {
+        Display {
+            identifier: "HDMI-A-1".to_owned(),
+            name: "HDMI-A-1".to_owned(),
+            modes,
+            connector: connector::Handle::from(1),
+            encoder: None,
+            vrr_capable: false,
+            non_desktop: false,
         }
-        handle
     }
-}
\ No newline at end of file
+
+    // 1080p75, the preferred 1080p60, interlaced 1080i120, 720p60 and a doublescanned 640x480
+    fn modes() -> Vec<Mode> {
+        vec![
+            modeline::cvt(1920, 1080, 75.0).unwrap(),
+            flagged(modeline::cvt_reduced(1920, 1080, 60.0).unwrap(), ffi::DRM_MODE_TYPE_PREFERRED, 0),
+            flagged(modeline::cvt(1920, 1080, 60.0).unwrap(), 0, ffi::DRM_MODE_FLAG_INTERLACE),
+            modeline::cvt(1280, 720, 60.0).unwrap(),
+            flagged(modeline::cvt(640, 480, 60.0).unwrap(), 0, ffi::DRM_MODE_FLAG_DBLSCAN),
+        ]
+    }
+
+    fn same(a: Option<Mode>, b: &Mode) -> bool {
+        a.is_some_and(|a| mode::same_timings(&a, b))
+    }
+
+    #[test]
+    fn preferred_mode_needs_not_be_first() {
+        let modes = modes();
+        assert!(same(display(modes.clone()).preferred_mode(), &modes[1]));
+
+        let unflagged = vec![modes[3], modes[0]];
+        assert!(same(display(unflagged.clone()).preferred_mode(), &unflagged[0]));
+        assert!(display(Vec::new()).preferred_mode().is_none());
+    }
+
+    #[test]
+    fn progressive_modes_skip_interlace_and_doublescan() {
+        let progressive = display(modes()).progressive_modes();
+        assert_eq!(progressive.len(), 3);
+        assert!(progressive.iter().all(|m| !mode::is_interlaced(m) && !mode::is_doublescan(m)));
+    }
+
+    #[test]
+    fn closest_mode_matches_size_then_refresh() {
+        let modes = modes();
+        let display = display(modes.clone());
+
+        // the interlaced mode runs at 120 Hz, it still mustn't win
+        assert!(same(display.closest_mode(1920, 1080, None), &modes[1]));
+        assert!(same(display.closest_mode(1920, 1080, Some(75_000)), &modes[0]));
+        assert!(same(display.closest_mode(1920, 1080, Some(120_000)), &modes[0]));
+        assert!(same(display.closest_mode(1280, 700, Some(144_000)), &modes[3]));
+        assert!(same(display.closest_mode(640, 480, None), &modes[3]));
+    }
+}
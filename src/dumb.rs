@@ -0,0 +1,350 @@
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::time::Duration;
+
+use drm::buffer::{self, Buffer, PixelFormat};
+use drm::control::{crtc, Mode};
+use gbm;
+
+use bo::{self, BufferLayout};
+use clock::FrameClock;
+use cursor::{Cursor, CursorImage};
+use device::Gpu;
+use display::Scanout;
+use error::{Error, Result};
+use framebuffer::Framebuffer;
+use kms::{DumbMapping, KmsDevice};
+
+// CPU mapped buffer any KMS driver can scan out, no Mesa driver needed
+pub struct DumbBuffer {
+    device: Rc<dyn KmsDevice>,
+    buffer: DumbMapping,
+    width: u32,
+    height: u32,
+    format: gbm::Format,
+    framebuffer: Option<Framebuffer>,
+}
+
+impl DumbBuffer {
+    // XRGB8888, the one format every driver has to take
+    pub fn new(gpu: &Gpu, width: u32, height: u32) -> Result<DumbBuffer> {
+        DumbBuffer::with_format(gpu, width, height, gbm::Format::XRGB8888)
+    }
+
+    // Any 32 bit format, e.g. ARGB8888 for cursors
+    pub fn with_format(gpu: &Gpu, width: u32, height: u32, format: gbm::Format) -> Result<DumbBuffer> {
+        gpu.require(gpu.caps.dumb_buffer, "dumb buffers")?;
+        let device = gpu.kms();
+        let buffer = device.create_dumb_buffer(width, height)?;
+
+        // the buffer is released by drop if the framebuffer can't be made
+        let mut dumb = DumbBuffer { device, buffer, width, height, format, framebuffer: None };
+        let layout = BufferLayout {
+            width,
+            height,
+            format: format.as_ffi(),
+            modifier: bo::MOD_INVALID,
+            handles: [buffer.handle, 0, 0, 0],
+            pitches: [buffer.pitch, 0, 0, 0],
+            offsets: [0; 4],
+        };
+        dumb.framebuffer = Some(Framebuffer::create(gpu, &layout)?);
+
+        Ok(dumb)
+    }
+
+    // Rows are stride() pixels apart, which can be more than the width
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.buffer.map as *mut u32, self.buffer.size / 4) }
+    }
+
+    pub fn stride(&self) -> usize {
+        self.buffer.pitch as usize / 4
+    }
+
+    pub fn fill(&mut self, color: u32) {
+        for pixel in self.pixels_mut() {
+            *pixel = color;
+        }
+    }
+
+    // Copies rows of XRGB8888 pixels src_stride bytes apart, cut to our size
+    pub fn copy_from(&mut self, src: &[u8], src_stride: usize) {
+        let row = (self.width as usize * 4).min(src_stride);
+        let rows = (self.height as usize).min(src.len() / src_stride.max(1));
+        let pitch = self.buffer.pitch as usize;
+
+        for y in 0..rows {
+            let line = &src[y * src_stride..y * src_stride + row];
+            unsafe { ptr::copy_nonoverlapping(line.as_ptr(), self.buffer.map.add(y * pitch), row) };
+        }
+    }
+
+    pub fn framebuffer(&self) -> Option<&Framebuffer> {
+        self.framebuffer.as_ref()
+    }
+}
+
+impl Drop for DumbBuffer {
+    fn drop(&mut self) {
+        self.framebuffer = None;
+
+        if let Err(err) = self.device.destroy_dumb_buffer(&self.buffer) {
+            eprintln!("{}", err);
+        }
+    }
+}
+
+impl Buffer for DumbBuffer {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn format(&self) -> PixelFormat {
+        PixelFormat::from_raw(self.format.as_ffi()).unwrap_or(PixelFormat::XRGB8888)
+    }
+
+    fn pitch(&self) -> u32 {
+        self.buffer.pitch
+    }
+
+    fn handle(&self) -> buffer::Id {
+        buffer::Id::from_raw(self.buffer.handle)
+    }
+}
+
+// Double buffered software output: draw into back_mut(), then present. Flips
+// go through the same path as the EGL surfaces.
+pub struct DumbSurface {
+    buffers: [DumbBuffer; 2],
+    front: usize,
+    crtc: crtc::Handle,
+    pub mode: Mode,
+    cursor: Option<Cursor>,
+    flip_pending: bool,
+    clock: FrameClock,
+}
+
+impl DumbSurface {
+    pub fn new(gpu: &Gpu, crtc: crtc::Handle, mode: Mode) -> Result<DumbSurface> {
+        let (width, height) = mode.size();
+        let first = DumbBuffer::new(gpu, u32::from(width), u32::from(height))?;
+        let second = DumbBuffer::new(gpu, u32::from(width), u32::from(height))?;
+
+        Ok(DumbSurface { buffers: [first, second], front: 0, crtc, mode, cursor: None, flip_pending: false, clock: FrameClock::new(&mode) })
+    }
+
+    // The buffer that isn't on screen, safe to draw into while no flip is pending
+    pub fn back_mut(&mut self) -> &mut DumbBuffer {
+        &mut self.buffers[1 - self.front]
+    }
+
+    pub fn queue_present(&mut self, gpu: &Gpu) -> Result<()> {
+        if self.flip_pending {
+            return Err(Error::FlipPending { crtc: self.crtc.into() });
+        }
+
+        self.front = 1 - self.front;
+        let now = gpu.now();
+        if let Err(err) = gpu.page_flip(self.crtc, self) {
+            self.front = 1 - self.front;
+            return Err(err);
+        }
+
+        self.clock.flip_queued(now);
+        self.flip_pending = true;
+        Ok(())
+    }
+
+    pub fn is_flip_pending(&self) -> bool {
+        self.flip_pending
+    }
+
+    pub fn page_flip_complete(&mut self, timestamp: Duration) {
+        self.flip_pending = false;
+        self.clock.flip_complete(timestamp);
+    }
+
+    pub fn clock(&self) -> &FrameClock {
+        &self.clock
+    }
+
+    pub fn set_vrr(&mut self, gpu: &Gpu, enabled: bool) -> Result<()> {
+        gpu.set_vrr(self.crtc, enabled)?;
+        self.clock.set_vrr(enabled);
+        Ok(())
+    }
+
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+
+    // Hardware cursors work the same as on the EGL surfaces, a composited
+    // one has to be drawn into back_mut()
+    pub fn set_cursor(&mut self, gpu: &Gpu, image: CursorImage) -> Result<()> {
+        let crtc = self.crtc;
+        self.cursor
+            .get_or_insert_with(|| Cursor::new(gpu, crtc))
+            .set_image(gpu, image)
+    }
+
+    pub fn move_cursor(&mut self, gpu: &Gpu, x: i32, y: i32) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.move_to(gpu, x, y),
+            None => Ok(()),
+        }
+    }
+
+    pub fn hide_cursor(&mut self, gpu: &Gpu) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.hide(gpu),
+            None => Ok(()),
+        }
+    }
+
+    // Copies a composited cursor into the back buffer, the last thing to draw
+    // before presenting. Pixels at least half opaque show, the rest doesn't.
+    pub fn draw_cursor(&mut self) {
+        let (image, (x, y)) = match self.cursor.as_ref().and_then(Cursor::composited) {
+            Some(composited) => composited,
+            None => return,
+        };
+
+        let back = &mut self.buffers[1 - self.front];
+        let (width, height, stride) = (back.width as i32, back.height as i32, back.stride());
+        let pixels = back.pixels_mut();
+        for row in 0..image.height as i32 {
+            for col in 0..image.width as i32 {
+                let (px, py) = (x + col, y + row);
+                let pixel = image.pixels[(row * image.width as i32 + col) as usize];
+                if px >= 0 && py >= 0 && px < width && py < height && pixel >> 24 >= 0x80 {
+                    pixels[py as usize * stride + px as usize] = pixel | 0xff00_0000;
+                }
+            }
+        }
+    }
+}
+
+impl Scanout for DumbSurface {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn framebuffer(&self) -> Option<&Framebuffer> {
+        self.buffers[self.front].framebuffer()
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use drm::control::connector;
+
+    use device::Gpu;
+    use display::Scanout;
+    use error::Error;
+    use fake::FakeDevice;
+    use mode;
+    use modeline;
+    use super::{DumbBuffer, DumbSurface};
+
+    #[test]
+    fn dumb_surface_flips_on_vblank() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        fake.add_connector(connector::Type::HDMIA, vec![modeline::cvt_reduced(1920, 1080, 60.0).unwrap()], 0b1);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.caps.dumb_buffer = true;
+        let display = gpu.displays().remove(0);
+        let mode = display.preferred_mode().unwrap();
+        let period = Duration::from_nanos(1_000_000_000_000 / u64::from(mode::refresh_mhz(&mode)));
+
+        let mut surface = DumbSurface::new(&gpu, crtc, mode).unwrap();
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &surface).unwrap();
+        let start = fake.now();
+
+        surface.back_mut().fill(0x00ff_0000);
+        surface.queue_present(&gpu).unwrap();
+        let back = surface.framebuffer().unwrap().handle();
+        assert!(surface.is_flip_pending());
+        match surface.queue_present(&gpu) {
+            Err(Error::FlipPending { .. }) => {}
+            _ => panic!("queued a second flip before the first one completed"),
+        }
+
+        let timestamp = gpu.wait_for_flip(crtc).unwrap();
+        surface.page_flip_complete(timestamp);
+        assert_eq!(timestamp, start + period);
+        assert_eq!(fake.scanout(crtc).unwrap().fb, back);
+
+        surface.queue_present(&gpu).unwrap();
+        surface.page_flip_complete(gpu.wait_for_flip(crtc).unwrap());
+        assert_eq!(surface.clock().frame_interval(), Some(period));
+        assert!(!surface.is_flip_pending());
+
+        // nothing in flight, this has to fail instead of waiting forever
+        match gpu.wait_for_flip(crtc) {
+            Err(Error::NoFlipPending { .. }) => {}
+            _ => panic!("waited for a flip that was never queued"),
+        }
+
+        guard.dismiss();
+    }
+
+    #[test]
+    fn frame_clock_predicts_the_vblank_a_flip_lands_on() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        fake.add_connector(connector::Type::HDMIA, vec![modeline::cvt_reduced(1920, 1080, 60.0).unwrap()], 0b1);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.caps.dumb_buffer = true;
+        let display = gpu.displays().remove(0);
+        let mut surface = DumbSurface::new(&gpu, crtc, display.preferred_mode().unwrap()).unwrap();
+        let guard = gpu.modeset(gpu.crtcs[0], &[&display], &surface).unwrap();
+        surface.queue_present(&gpu).unwrap();
+        surface.page_flip_complete(gpu.wait_for_flip(crtc).unwrap());
+        let period = surface.clock().refresh_interval();
+
+        // right after a flip, and after sitting idle for a few frames
+        for &idle in [Duration::from_millis(0), period * 5 / 2].iter() {
+            fake.advance(idle);
+            let predicted = surface.clock().next_flip_time(fake.now());
+
+            surface.queue_present(&gpu).unwrap();
+            let timestamp = gpu.wait_for_flip(crtc).unwrap();
+            surface.page_flip_complete(timestamp);
+
+            assert_eq!(timestamp, predicted);
+            assert!(!surface.clock().missed_vblank());
+        }
+
+        guard.dismiss();
+    }
+
+    #[test]
+    fn copies_rows_between_strides() {
+        let fake = Rc::new(FakeDevice::new());
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.caps.dumb_buffer = true;
+
+        let mut dumb = DumbBuffer::new(&gpu, 2, 2).unwrap();
+        dumb.fill(0xffff_ffff);
+
+        // three pixels a row, the last one is padding that must not show up
+        let src = [1u32, 2, 0xdead, 3, 4, 0xdead].iter().flat_map(|p| p.to_ne_bytes().to_vec()).collect::<Vec<_>>();
+        dumb.copy_from(&src, 12);
+
+        assert_eq!(dumb.stride(), 2);
+        assert_eq!(dumb.pixels_mut(), &[1, 2, 3, 4]);
+        assert!(dumb.framebuffer().is_some());
+    }
+}
@@ -0,0 +1,435 @@
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+const BLOCK_SIZE: usize = 128;
+const CTA_EXTENSION: u8 = 0x02;
+
+// 720x400@70 down to 1152x870@75, in the bit order of bytes 35-37. 1024x768@87
+// is the interlaced 8514/A mode.
+const ESTABLISHED_TIMINGS: [(u32, u32, u32, bool); 17] = [
+    (720, 400, 70, false), (720, 400, 88, false), (640, 480, 60, false), (640, 480, 67, false),
+    (640, 480, 72, false), (640, 480, 75, false), (800, 600, 56, false), (800, 600, 60, false),
+    (800, 600, 72, false), (800, 600, 75, false), (832, 624, 75, false), (1024, 768, 87, true),
+    (1024, 768, 60, false), (1024, 768, 70, false), (1024, 768, 75, false), (1280, 1024, 75, false),
+    (1152, 870, 75, false),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingSource {
+    Established,
+    Standard,
+    Detailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub width: u32,
+    pub height: u32,
+    pub refresh: u32,
+    pub interlaced: bool,
+    pub source: TimingSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HdrStaticMetadata {
+    pub eotfs: u8,
+    pub descriptors: u8,
+    // cd/m², None when the sink leaves them out
+    pub max_luminance: Option<f32>,
+    pub max_frame_average: Option<f32>,
+    pub min_luminance: Option<f32>,
+}
+
+impl HdrStaticMetadata {
+    pub const EOTF_SDR: u8 = 1 << 0;
+    pub const EOTF_HDR: u8 = 1 << 1;
+    pub const EOTF_PQ: u8 = 1 << 2;
+    pub const EOTF_HLG: u8 = 1 << 3;
+
+    pub fn supports_eotf(&self, eotf: u8) -> bool {
+        self.eotfs & eotf != 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CtaExtension {
+    pub revision: u8,
+    pub underscan: bool,
+    pub basic_audio: bool,
+    pub ycbcr444: bool,
+    pub ycbcr422: bool,
+    // short video descriptors, VICs with the native bit stripped
+    pub video_codes: Vec<u8>,
+    pub native_video_codes: Vec<u8>,
+    pub colorimetry: u16,
+    pub hdr_static_metadata: Option<HdrStaticMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edid {
+    pub manufacturer: String,
+    pub product_code: u16,
+    pub serial: u32,
+    pub serial_string: Option<String>,
+    pub name: Option<String>,
+    pub year: u32,
+    pub version: (u8, u8),
+    pub size_mm: Option<(u32, u32)>,
+    pub timings: Vec<Timing>,
+    pub cta: Option<CtaExtension>,
+}
+
+impl Edid {
+    pub fn parse(data: &[u8]) -> Option<Edid> {
+        if data.len() < BLOCK_SIZE || data[..8] != HEADER || !checksum_ok(&data[..BLOCK_SIZE]) {
+            return None;
+        }
+
+        let id = u16::from(data[8]) << 8 | u16::from(data[9]);
+        let letter = |shift: u16| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char;
+        let manufacturer = [letter(10), letter(5), letter(0)].iter().collect();
+
+        let mut edid = Edid {
+            manufacturer,
+            product_code: u16::from(data[10]) | u16::from(data[11]) << 8,
+            serial: u32::from(data[12]) | u32::from(data[13]) << 8 | u32::from(data[14]) << 16 | u32::from(data[15]) << 24,
+            serial_string: None,
+            name: None,
+            year: 1990 + u32::from(data[17]),
+            version: (data[18], data[19]),
+            size_mm: None,
+            timings: Vec::new(),
+            cta: None,
+        };
+
+        let established = u32::from(data[35]) << 16 | u32::from(data[36]) << 8 | u32::from(data[37]);
+        for (bit, &(width, height, refresh, interlaced)) in ESTABLISHED_TIMINGS.iter().enumerate() {
+            if established & (1 << (23 - bit)) != 0 {
+                edid.timings.push(Timing { width, height, refresh, interlaced, source: TimingSource::Established });
+            }
+        }
+
+        for standard in data[38..54].chunks(2) {
+            if let Some(timing) = standard_timing(standard[0], standard[1], edid.version) {
+                edid.timings.push(timing);
+            }
+        }
+
+        for descriptor in data[54..126].chunks(18) {
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                let (timing, size) = detailed_timing(descriptor);
+                edid.timings.push(timing);
+                if edid.size_mm.is_none() && size.0 != 0 && size.1 != 0 {
+                    edid.size_mm = Some(size);
+                }
+                continue;
+            }
+
+            match descriptor[3] {
+                0xfc => edid.name = descriptor_text(&descriptor[5..]),
+                0xff => edid.serial_string = descriptor_text(&descriptor[5..]),
+                _ => {}
+            }
+        }
+
+        // the detailed timing is in mm, the basic block only has cm
+        if edid.size_mm.is_none() && data[21] != 0 && data[22] != 0 {
+            edid.size_mm = Some((u32::from(data[21]) * 10, u32::from(data[22]) * 10));
+        }
+
+        for block in data[BLOCK_SIZE..].chunks(BLOCK_SIZE) {
+            if block.len() == BLOCK_SIZE && block[0] == CTA_EXTENSION && checksum_ok(block) && edid.cta.is_none() {
+                edid.cta = Some(parse_cta(block, &mut edid.timings));
+            }
+        }
+
+        Some(edid)
+    }
+
+    // Survives connector reordering and replugging into another port
+    pub fn identifier(&self) -> String {
+        let model = match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("0x{:04x}", self.product_code),
+        };
+
+        match self.serial_string {
+            Some(ref serial) => format!("{} {} {}", self.manufacturer, model, serial),
+            None if self.serial != 0 => format!("{} {} 0x{:08x}", self.manufacturer, model, self.serial),
+            None => format!("{} {}", self.manufacturer, model),
+        }
+    }
+
+    pub fn hdr_static_metadata(&self) -> Option<&HdrStaticMetadata> {
+        self.cta.as_ref().and_then(|cta| cta.hdr_static_metadata.as_ref())
+    }
+}
+
+fn checksum_ok(block: &[u8]) -> bool {
+    block.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn standard_timing(first: u8, second: u8, version: (u8, u8)) -> Option<Timing> {
+    if (first == 0x01 && second == 0x01) || first == 0 {
+        return None;
+    }
+
+    let width = (u32::from(first) + 31) * 8;
+    let height = match second >> 6 {
+        // 16:10 only exists since EDID 1.3, older sinks meant 1:1
+        0 if version < (1, 3) => width,
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+
+    Some(Timing { width, height, refresh: u32::from(second & 0x3f) + 60, interlaced: false, source: TimingSource::Standard })
+}
+
+// The timing and the image size in mm the descriptor carries
+fn detailed_timing(d: &[u8]) -> (Timing, (u32, u32)) {
+    let clock = (u32::from(d[0]) | u32::from(d[1]) << 8) * 10_000;
+    let hactive = u32::from(d[2]) | u32::from(d[4] & 0xf0) << 4;
+    let hblank = u32::from(d[3]) | u32::from(d[4] & 0x0f) << 8;
+    let vactive = u32::from(d[5]) | u32::from(d[7] & 0xf0) << 4;
+    let vblank = u32::from(d[6]) | u32::from(d[7] & 0x0f) << 8;
+    let width_mm = u32::from(d[12]) | u32::from(d[14] & 0xf0) << 4;
+    let height_mm = u32::from(d[13]) | u32::from(d[14] & 0x0f) << 8;
+    let interlaced = d[17] & 0x80 != 0;
+
+    let total = (hactive + hblank) * (vactive + vblank);
+    let refresh = (clock + total / 2).checked_div(total).unwrap_or(0);
+    let height = if interlaced { vactive * 2 } else { vactive };
+
+    (Timing { width: hactive, height, refresh, interlaced, source: TimingSource::Detailed }, (width_mm, height_mm))
+}
+
+fn descriptor_text(text: &[u8]) -> Option<String> {
+    let end = text.iter().position(|&c| c == b'\n').unwrap_or(text.len());
+    let text = String::from_utf8_lossy(&text[..end]).trim().to_owned();
+    if text.is_empty() { None } else { Some(text) }
+}
+
+fn parse_cta(block: &[u8], timings: &mut Vec<Timing>) -> CtaExtension {
+    let mut cta = CtaExtension {
+        revision: block[1],
+        underscan: block[3] & 0x80 != 0,
+        basic_audio: block[3] & 0x40 != 0,
+        ycbcr444: block[3] & 0x20 != 0,
+        ycbcr422: block[3] & 0x10 != 0,
+        ..Default::default()
+    };
+
+    let dtd_start = (block[2] as usize).min(BLOCK_SIZE - 1);
+    let mut offset = 4;
+    while offset < dtd_start {
+        let tag = block[offset] >> 5;
+        let len = (block[offset] & 0x1f) as usize;
+        let payload = match block.get(offset + 1..offset + 1 + len) {
+            Some(payload) => payload,
+            None => break,
+        };
+
+        match tag {
+            2 => for &svd in payload {
+                // VICs 1-64 carry the native flag in the top bit, the others don't
+                if svd & 0x80 != 0 && svd & 0x7f != 0 && svd & 0x7f <= 64 {
+                    cta.video_codes.push(svd & 0x7f);
+                    cta.native_video_codes.push(svd & 0x7f);
+                } else {
+                    cta.video_codes.push(svd);
+                }
+            },
+            7 if !payload.is_empty() => match payload[0] {
+                5 if payload.len() >= 3 => cta.colorimetry = u16::from(payload[1]) | u16::from(payload[2]) << 8,
+                6 if payload.len() >= 3 => cta.hdr_static_metadata = Some(hdr_static_metadata(&payload[1..])),
+                _ => {}
+            },
+            _ => {}
+        }
+
+        offset += 1 + len;
+    }
+
+    if block[2] >= 4 {
+        let mut offset = block[2] as usize;
+        while offset + 18 < BLOCK_SIZE && (block[offset] != 0 || block[offset + 1] != 0) {
+            timings.push(detailed_timing(&block[offset..offset + 18]).0);
+            offset += 18;
+        }
+    }
+
+    cta
+}
+
+fn hdr_static_metadata(data: &[u8]) -> HdrStaticMetadata {
+    // CTA-861-G 7.5.13, luminance values are coded and 0 means not given
+    let luminance = |index: usize| data.get(index).filter(|&&cv| cv != 0).map(|&cv| 50.0 * 2f32.powf(f32::from(cv) / 32.0));
+    let max_luminance = luminance(2);
+    let min_luminance = match (max_luminance, data.get(4)) {
+        (Some(max), Some(&cv)) => Some(max * (f32::from(cv) / 255.0).powi(2) / 100.0),
+        _ => None,
+    };
+
+    HdrStaticMetadata {
+        eotfs: data[0] & 0x3f,
+        descriptors: data[1],
+        max_luminance,
+        max_frame_average: luminance(3),
+        min_luminance,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{standard_timing, Edid, HdrStaticMetadata, Timing, TimingSource, HEADER};
+
+    fn finish(mut block: Vec<u8>) -> Vec<u8> {
+        block.resize(128, 0);
+        let sum = block[..127].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        block[127] = 0u8.wrapping_sub(sum);
+        block
+    }
+
+    // clock in 10 kHz units like the descriptor stores it
+    fn dtd(clock: u32, h: (u32, u32), v: (u32, u32), size_mm: (u32, u32), flags: u8) -> Vec<u8> {
+        vec![
+            clock as u8, (clock >> 8) as u8,
+            h.0 as u8, h.1 as u8, ((h.0 >> 8) << 4 | h.1 >> 8) as u8,
+            v.0 as u8, v.1 as u8, ((v.0 >> 8) << 4 | v.1 >> 8) as u8,
+            0, 0, 0, 0,
+            size_mm.0 as u8, size_mm.1 as u8, ((size_mm.0 >> 8) << 4 | size_mm.1 >> 8) as u8,
+            0, 0, flags,
+        ]
+    }
+
+    fn text(tag: u8, text: &str) -> Vec<u8> {
+        let mut descriptor = vec![0, 0, 0, tag, 0];
+        descriptor.extend(text.bytes());
+        descriptor.push(b'\n');
+        descriptor.resize(18, b' ');
+        descriptor
+    }
+
+    // DEL 0xa0c4 from 2020, 640x480@60, 800x600@60, 1024x768@87i and @60
+    // established, 1920x1080@60 standard and detailed
+    fn base_block(extensions: u8) -> Vec<u8> {
+        let mut block = HEADER.to_vec();
+        block.extend(&[0x10, 0xac, 0xc4, 0xa0, 0x78, 0x56, 0x34, 0x12, 0, 30, 1, 4, 0x80, 60, 34]);
+        block.resize(35, 0);
+        block.extend(&[0x21, 0x18, 0x00, 0xd1, 0xc0]);
+        block.resize(54, 0x01);
+        block.extend(dtd(14850, (1920, 280), (1080, 45), (600, 340), 0x1e));
+        block.extend(text(0xfc, "DELL U2720Q"));
+        block.extend(text(0xff, "ABC123"));
+        block.extend(&[0, 0, 0, 0x10, 0]);
+        block.resize(126, 0);
+        block.push(extensions);
+        finish(block)
+    }
+
+    // video, colorimetry and HDR static metadata blocks, then 720p and 1080i
+    fn cta_block() -> Vec<u8> {
+        let mut block = vec![0x02, 3, 19, 0xf1];
+        block.extend(&[0x43, 0x90, 0x04, 0xc1]);
+        block.extend(&[0xe3, 5, 0x03, 0x00]);
+        block.extend(&[0xe6, 6, 0x05, 0x01, 0x60, 0x40, 0x80]);
+        block.extend(dtd(7425, (1280, 370), (720, 30), (0, 0), 0x1e));
+        block.extend(dtd(7425, (1920, 280), (540, 22), (0, 0), 0x9e));
+        finish(block)
+    }
+
+    fn timing(width: u32, height: u32, refresh: u32, interlaced: bool, source: TimingSource) -> Timing {
+        Timing { width, height, refresh, interlaced, source }
+    }
+
+    fn close(value: Option<f32>, expected: f32) -> bool {
+        value.is_some_and(|value| (value - expected).abs() < 0.01)
+    }
+
+    #[test]
+    fn parses_the_base_block() {
+        let edid = Edid::parse(&base_block(0)).unwrap();
+
+        assert_eq!(edid.manufacturer, "DEL");
+        assert_eq!(edid.product_code, 0xa0c4);
+        assert_eq!(edid.serial, 0x1234_5678);
+        assert_eq!(edid.name.as_deref(), Some("DELL U2720Q"));
+        assert_eq!(edid.serial_string.as_deref(), Some("ABC123"));
+        assert_eq!(edid.identifier(), "DEL DELL U2720Q ABC123");
+        assert_eq!((edid.year, edid.version), (2020, (1, 4)));
+        // the detailed timing's mm win over the basic block's cm
+        assert_eq!(edid.size_mm, Some((600, 340)));
+        assert_eq!(edid.cta, None);
+
+        assert_eq!(edid.timings, vec![
+            timing(640, 480, 60, false, TimingSource::Established),
+            timing(800, 600, 60, false, TimingSource::Established),
+            timing(1024, 768, 87, true, TimingSource::Established),
+            timing(1024, 768, 60, false, TimingSource::Established),
+            timing(1920, 1080, 60, false, TimingSource::Standard),
+            timing(1920, 1080, 60, false, TimingSource::Detailed),
+        ]);
+    }
+
+    #[test]
+    fn rejects_broken_base_blocks() {
+        let block = base_block(0);
+        assert_eq!(Edid::parse(&block[..127]), None);
+
+        let mut corrupted = block.clone();
+        corrupted[20] ^= 0xff;
+        assert_eq!(Edid::parse(&corrupted), None);
+
+        let mut header = block.clone();
+        header[0] = 0xff;
+        assert_eq!(Edid::parse(&finish(header)), None);
+    }
+
+    #[test]
+    fn parses_the_cta_extension() {
+        let mut data = base_block(1);
+        data.extend(cta_block());
+        let edid = Edid::parse(&data).unwrap();
+        let cta = edid.cta.as_ref().unwrap();
+
+        assert_eq!(cta.revision, 3);
+        assert!(cta.underscan && cta.basic_audio && cta.ycbcr444 && cta.ycbcr422);
+        // VIC 193 is above 64, its top bit isn't the native flag
+        assert_eq!(cta.video_codes, vec![16, 4, 193]);
+        assert_eq!(cta.native_video_codes, vec![16]);
+        assert_eq!(cta.colorimetry, 0x0003);
+
+        let hdr = edid.hdr_static_metadata().unwrap();
+        assert!(hdr.supports_eotf(HdrStaticMetadata::EOTF_PQ));
+        assert!(!hdr.supports_eotf(HdrStaticMetadata::EOTF_HLG));
+        assert_eq!(hdr.descriptors, 1);
+        assert!(close(hdr.max_luminance, 400.0));
+        assert!(close(hdr.max_frame_average, 200.0));
+        assert!(close(hdr.min_luminance, 1.008));
+
+        assert_eq!(&edid.timings[6..], &[
+            timing(1280, 720, 60, false, TimingSource::Detailed),
+            timing(1920, 1080, 60, true, TimingSource::Detailed),
+        ]);
+    }
+
+    #[test]
+    fn skips_extensions_with_a_bad_checksum() {
+        let mut data = base_block(1);
+        let mut cta = cta_block();
+        cta[127] ^= 0xff;
+        data.extend(cta);
+
+        let edid = Edid::parse(&data).unwrap();
+        assert_eq!(edid.cta, None);
+        assert_eq!(edid.timings.len(), 6);
+    }
+
+    #[test]
+    fn standard_timing_aspect_depends_on_the_version() {
+        assert_eq!(standard_timing(0x81, 0x00, (1, 2)).map(|t| (t.width, t.height)), Some((1280, 1280)));
+        assert_eq!(standard_timing(0x81, 0x00, (1, 3)).map(|t| (t.width, t.height)), Some((1280, 800)));
+        assert_eq!(standard_timing(0x81, 0x4f, (1, 4)).map(|t| (t.width, t.height, t.refresh)), Some((1280, 960, 75)));
+        assert_eq!(standard_timing(0x01, 0x01, (1, 4)), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+use drm::result::{Error as DrmError, ErrorKind as DrmErrorKind};
+use egl;
+use udev;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "failed to open {:?}: {}", path, error)]
+    Open { path: PathBuf, #[cause] error: io::Error },
+
+    #[fail(display = "[udev] {}", _0)]
+    Udev(#[cause] io::Error),
+
+    #[fail(display = "[gbm] {} failed: {}", operation, error)]
+    Gbm { operation: &'static str, #[cause] error: io::Error },
+
+    #[fail(display = "[gbm] failed to lock front buffer")]
+    LockFrontBuffer,
+
+    #[fail(display = "[drm] {} failed on object {} (errno {})", operation, object, errno)]
+    Drm { operation: &'static str, object: u32, errno: i32 },
+
+    #[fail(display = "[drm] object {} has no {} property", object, name)]
+    MissingProperty { object: u32, name: String },
+
+    #[fail(display = "[drm] property {} of object {} is immutable", name, object)]
+    ImmutableProperty { object: u32, name: String },
+
+    #[fail(display = "[drm] property {} of object {} doesn't accept {}", name, object, value)]
+    InvalidPropertyValue { object: u32, name: String, value: String },
+
+    #[fail(display = "[egl] {} failed with error 0x{:x}", operation, code)]
+    Egl { operation: &'static str, code: i32 },
+
+    #[fail(display = "[gbm] device was destroyed")]
+    DeviceDestroyed,
+
+    #[fail(display = "[gbm] device has no gbm device behind it")]
+    NoGbmDevice,
+
+    #[fail(display = "[drm] {} driver doesn't support {}", driver, feature)]
+    Unsupported { driver: String, feature: &'static str },
+
+    #[fail(display = "[drm] not DRM master, modesetting is suspended")]
+    NotMaster,
+
+    #[fail(display = "[session] {} failed: {}", operation, error)]
+    Session { operation: &'static str, #[cause] error: io::Error },
+
+    #[fail(display = "[gpu] display surface has no framebuffer")]
+    NoFramebuffer,
+
+    #[fail(display = "[gpu] crtc {} still has a page flip in flight", crtc)]
+    FlipPending { crtc: u32 },
+
+    #[fail(display = "[gpu] crtc {} has no page flip in flight", crtc)]
+    NoFlipPending { crtc: u32 },
+
+    #[fail(display = "[gpu] crtc {} has no primary plane", crtc)]
+    MissingPlane { crtc: u32 },
+
+    #[fail(display = "[gpu] no free crtc can drive connector {}", connector)]
+    NoCrtc { connector: u32 },
+
+    #[fail(display = "[gpu] connector {} doesn't report any modes", connector)]
+    NoModes { connector: u32 },
+
+    #[fail(display = "[gpu] connector {} isn't capable of variable refresh", connector)]
+    VrrUnsupported { connector: u32 },
+
+    #[fail(display = "[mode] can't parse modeline {:?}", _0)]
+    InvalidModeline(String),
+
+    #[fail(display = "[mode] no valid {}x{} timings at {} Hz", width, height, refresh)]
+    InvalidMode { width: u32, height: u32, refresh: f64 },
+
+    #[fail(display = "usage: {}", _0)]
+    Usage(&'static str),
+
+    #[fail(display = "[cursor] {}x{} image doesn't fit into {}x{}", width, height, max_width, max_height)]
+    CursorTooLarge { width: u32, height: u32, max_width: u32, max_height: u32 },
+
+    #[fail(display = "[cursor] {}x{} image comes with only {} pixels", width, height, pixels)]
+    InvalidCursorImage { width: u32, height: u32, pixels: usize },
+}
+
+impl Error {
+    pub fn drm<H: Into<u32>>(operation: &'static str, object: H, error: DrmError) -> Error {
+        let errno = match *error.kind() {
+            DrmErrorKind::Unix(ref err) => Some(err.errno() as i32),
+            DrmErrorKind::Io(ref err) => err.raw_os_error(),
+            _ => None,
+        };
+
+        Error::Drm { operation, object: object.into(), errno: errno.unwrap_or(0) }
+    }
+
+    // For the raw ioctls we issue ourselves, takes the nix error of the drm-sys
+    // wrappers or the io error of a plain libc call
+    pub fn ioctl<H: Into<u32>, E: Into<DrmError>>(operation: &'static str, object: H, error: E) -> Error {
+        Error::drm(operation, object, error.into())
+    }
+
+    pub fn egl(operation: &'static str) -> Error {
+        Error::Egl { operation, code: egl::get_error() }
+    }
+
+    pub fn gbm(operation: &'static str, error: io::Error) -> Error {
+        Error::Gbm { operation, error }
+    }
+
+    pub fn session(operation: &'static str, error: io::Error) -> Error {
+        Error::Session { operation, error }
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            Error::Drm { errno, .. } => Some(errno),
+            Error::Open { ref error, .. } | Error::Udev(ref error) | Error::Gbm { ref error, .. } => error.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl From<udev::Error> for Error {
+    fn from(error: udev::Error) -> Error {
+        Error::Udev(error.into())
+    }
+}
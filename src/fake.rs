@@ -0,0 +1,507 @@
+use std::cell::RefCell;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use drm::control::{connector, crtc, encoder, Mode};
+use drm::control::crtc::PageFlipEvent;
+use drm::control::framebuffer as drm_fb;
+use libc;
+
+use atomic::{AtomicRequest, CommitFlags};
+use bo::BufferLayout;
+use edid::Edid;
+use error::{Error, Result};
+use kms::{ConnectorInfo, CrtcInfo, DumbMapping, EncoderInfo, KmsDevice, Resources};
+use mode;
+use property::{PropertyInfo, PropertyKind};
+
+// In-memory stand in for a KMS device. Time only moves when a flip has to
+// complete, receive_events jumps straight to the vblank that finishes the
+// earliest pending flip instead of blocking.
+pub struct FakeDevice {
+    state: RefCell<State>,
+}
+
+struct State {
+    next_id: u32,
+    connectors: Vec<ConnectorInfo>,
+    encoders: Vec<EncoderInfo>,
+    crtcs: Vec<FakeCrtc>,
+    // backing memory of the dumb buffers by handle
+    dumb_buffers: Vec<(u32, Vec<u32>)>,
+    properties: Vec<PropertyInfo>,
+    // (property, value) pairs by object
+    property_values: Vec<(u32, Vec<(u32, u64)>)>,
+    // GETPROPERTY calls so far
+    property_lookups: usize,
+    // whether our client is master, there's only ever the one
+    master: bool,
+    hardware_cursor: bool,
+    cursors: Vec<(crtc::Handle, FakeCursor)>,
+    now: Duration,
+}
+
+// What the legacy cursor ioctls left on a crtc, a handle of 0 is hidden
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FakeCursor {
+    pub handle: u32,
+    pub hotspot: (i32, i32),
+    pub position: (i32, i32),
+}
+
+struct FakeCrtc {
+    info: CrtcInfo,
+    // vblanks are counted from the last modeset on
+    scanout_start: Duration,
+    pending_flip: Option<drm_fb::Handle>,
+}
+
+impl FakeCrtc {
+    fn refresh_interval(&self) -> Option<Duration> {
+        self.info.mode.map(|mode| Duration::from_nanos(1_000_000_000_000 / u64::from(mode::refresh_mhz(&mode).max(1))))
+    }
+
+    // First vblank strictly after now, and its sequence number
+    fn next_vblank(&self, now: Duration) -> Option<(Duration, u32)> {
+        let period = self.refresh_interval()?;
+        let elapsed = now.checked_sub(self.scanout_start).unwrap_or_default();
+        let frame = (elapsed.as_nanos() / period.as_nanos()) as u32 + 1;
+        Some((self.scanout_start + period * frame, frame))
+    }
+}
+
+impl Default for FakeDevice {
+    fn default() -> FakeDevice {
+        FakeDevice::new()
+    }
+}
+
+impl FakeDevice {
+    pub fn new() -> FakeDevice {
+        FakeDevice {
+            state: RefCell::new(State {
+                next_id: 1,
+                connectors: Vec::new(),
+                encoders: Vec::new(),
+                crtcs: Vec::new(),
+                dumb_buffers: Vec::new(),
+                properties: Vec::new(),
+                property_values: Vec::new(),
+                property_lookups: 0,
+                master: true,
+                hardware_cursor: true,
+                cursors: Vec::new(),
+                now: Duration::from_secs(1),
+            }),
+        }
+    }
+
+    pub fn add_crtc(&self) -> crtc::Handle {
+        let mut state = self.state.borrow_mut();
+        let handle = crtc::Handle::from(state.allocate_id());
+        let info = CrtcInfo { handle, position: (0, 0), mode: None, fb: drm_fb::Handle::from(0u32) };
+        state.crtcs.push(FakeCrtc { info, scanout_start: Duration::default(), pending_flip: None });
+        handle
+    }
+
+    // Connected, with a single encoder that can drive the crtcs in possible_crtcs
+    pub fn add_connector(&self, interface: connector::Type, modes: Vec<Mode>, possible_crtcs: u32) -> connector::Handle {
+        let mut state = self.state.borrow_mut();
+        let encoder = encoder::Handle::from(state.allocate_id());
+        let handle = connector::Handle::from(state.allocate_id());
+        let interface_id = state.connectors.iter().filter(|c| c.interface == interface).count() as u32 + 1;
+
+        state.encoders.push(EncoderInfo { handle: encoder, current_crtc: None, possible_crtcs });
+        state.connectors.push(ConnectorInfo {
+            handle,
+            interface,
+            interface_id,
+            state: connector::State::Connected,
+            modes,
+            current_encoder: None,
+            encoders: vec![encoder],
+            edid: None,
+        });
+        handle
+    }
+
+    pub fn set_connected(&self, connector: connector::Handle, connected: bool) {
+        let mut state = self.state.borrow_mut();
+        if let Some(info) = state.connectors.iter_mut().find(|c| c.handle == connector) {
+            info.state = if connected { connector::State::Connected } else { connector::State::Disconnected };
+        }
+    }
+
+    // Shows up in the connector info on the next probe, like a new sink
+    pub fn set_edid(&self, connector: connector::Handle, edid: Option<Edid>) {
+        let mut state = self.state.borrow_mut();
+        if let Some(info) = state.connectors.iter_mut().find(|c| c.handle == connector) {
+            info.edid = edid;
+        }
+    }
+
+    // Objects share the id of a property with the same name and kind, like
+    // they do in the kernel
+    pub fn add_property(&self, object: u32, name: &str, kind: PropertyKind, value: u64) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let id = match state.properties.iter().find(|p| p.name == name && p.kind == kind) {
+            Some(info) => info.id,
+            None => {
+                let id = state.allocate_id();
+                state.properties.push(PropertyInfo { id, name: name.to_owned(), kind, immutable: false, atomic_only: false });
+                id
+            }
+        };
+
+        match state.property_values.iter_mut().find(|&&mut (obj, _)| obj == object) {
+            Some(&mut (_, ref mut values)) => values.push((id, value)),
+            None => state.property_values.push((object, vec![(id, value)])),
+        }
+        id
+    }
+
+    pub fn set_property_value(&self, object: u32, property: u32, value: u64) {
+        self.state.borrow_mut().set_property_value(object, property, value);
+    }
+
+    pub fn property_lookups(&self) -> usize {
+        self.state.borrow().property_lookups
+    }
+
+    pub fn property_value(&self, object: u32, name: &str) -> Option<u64> {
+        let state = self.state.borrow();
+        let (_, values) = state.property_values.iter().find(|&&(obj, _)| obj == object)?;
+        values.iter()
+            .find(|&&(prop, _)| state.properties.iter().any(|p| p.id == prop && p.name == name))
+            .map(|&(_, value)| value)
+    }
+
+    // Without it the legacy cursor ioctls fail with ENXIO, like on drivers
+    // that have no cursor
+    pub fn set_hardware_cursor(&self, supported: bool) {
+        self.state.borrow_mut().hardware_cursor = supported;
+    }
+
+    pub fn cursor(&self, crtc: crtc::Handle) -> Option<FakeCursor> {
+        self.state.borrow().cursors.iter().find(|&&(c, _)| c == crtc).map(|&(_, cursor)| cursor)
+    }
+
+    pub fn now(&self) -> Duration {
+        self.state.borrow().now
+    }
+
+    pub fn advance(&self, time: Duration) {
+        self.state.borrow_mut().now += time;
+    }
+
+    pub fn scanout(&self, crtc: crtc::Handle) -> Option<CrtcInfo> {
+        self.state.borrow().crtcs.iter().find(|c| c.info.handle == crtc).map(|c| c.info)
+    }
+
+    pub fn is_flip_pending(&self, crtc: crtc::Handle) -> bool {
+        self.state.borrow().crtcs.iter().any(|c| c.info.handle == crtc && c.pending_flip.is_some())
+    }
+}
+
+impl State {
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn crtc_mut(&mut self, crtc: crtc::Handle, operation: &'static str) -> Result<&mut FakeCrtc> {
+        self.crtcs.iter_mut().find(|c| c.info.handle == crtc).ok_or_else(|| fail(operation, crtc, libc::ENOENT))
+    }
+
+    fn set_property_value(&mut self, object: u32, property: u32, value: u64) {
+        if let Some(&mut (_, ref mut values)) = self.property_values.iter_mut().find(|&&mut (obj, _)| obj == object) {
+            for entry in values.iter_mut().filter(|entry| entry.0 == property) {
+                entry.1 = value;
+            }
+        }
+    }
+
+    fn cursor_mut(&mut self, crtc: crtc::Handle, operation: &'static str) -> Result<&mut FakeCursor> {
+        if !self.master {
+            return Err(fail(operation, crtc, libc::EACCES));
+        }
+        self.crtc_mut(crtc, operation)?;
+        if !self.hardware_cursor {
+            return Err(fail(operation, crtc, libc::ENXIO));
+        }
+
+        let index = match self.cursors.iter().position(|&(c, _)| c == crtc) {
+            Some(index) => index,
+            None => {
+                self.cursors.push((crtc, FakeCursor::default()));
+                self.cursors.len() - 1
+            }
+        };
+        Ok(&mut self.cursors[index].1)
+    }
+}
+
+fn fail<H: Into<u32>>(operation: &'static str, object: H, errno: i32) -> Error {
+    Error::Drm { operation, object: object.into(), errno }
+}
+
+// There's no node behind the fake, anything going around KmsDevice fails with EBADF
+impl AsRawFd for FakeDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        -1
+    }
+}
+
+impl KmsDevice for FakeDevice {
+    fn resources(&self) -> Result<Resources> {
+        let state = self.state.borrow();
+        Ok(Resources {
+            connectors: state.connectors.iter().map(|c| c.handle).collect(),
+            encoders: state.encoders.iter().map(|e| e.handle).collect(),
+            crtcs: state.crtcs.iter().map(|c| c.info.handle).collect(),
+        })
+    }
+
+    fn connector_info(&self, connector: connector::Handle) -> Result<ConnectorInfo> {
+        self.state.borrow().connectors.iter()
+            .find(|c| c.handle == connector)
+            .cloned()
+            .ok_or_else(|| fail("load resource info", connector, libc::ENOENT))
+    }
+
+    fn encoder_info(&self, encoder: encoder::Handle) -> Result<EncoderInfo> {
+        self.state.borrow().encoders.iter()
+            .find(|e| e.handle == encoder)
+            .cloned()
+            .ok_or_else(|| fail("get encoder", encoder, libc::ENOENT))
+    }
+
+    fn crtc_info(&self, crtc: crtc::Handle) -> Result<CrtcInfo> {
+        self.scanout(crtc).ok_or_else(|| fail("load resource info", crtc, libc::ENOENT))
+    }
+
+    fn set_crtc(&self, crtc: crtc::Handle, fb: drm_fb::Handle, connectors: &[connector::Handle], position: (u32, u32), mode: Option<Mode>) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if !state.master {
+            return Err(fail("set crtc", crtc, libc::EACCES));
+        }
+        let index = state.crtcs.iter().position(|c| c.info.handle == crtc).ok_or_else(|| fail("set crtc", crtc, libc::ENOENT))?;
+
+        // same checks the kernel does before touching anything
+        if mode.is_some() && (u32::from(fb) == 0 || connectors.is_empty()) {
+            return Err(fail("set crtc", crtc, libc::EINVAL));
+        }
+        let mut routes = Vec::new();
+        for &connector in connectors {
+            let info = state.connectors.iter().find(|c| c.handle == connector).ok_or_else(|| fail("set crtc", crtc, libc::ENOENT))?;
+            let encoder = info.encoders.iter()
+                .filter_map(|&e| state.encoders.iter().position(|enc| enc.handle == e))
+                .find(|&e| state.encoders[e].possible_crtcs & (1 << index) != 0)
+                .ok_or_else(|| fail("set crtc", crtc, libc::EINVAL))?;
+            routes.push((connector, encoder));
+        }
+
+        // connectors driven by this crtc before are let go, the new ones are
+        // stolen from whatever crtc had them
+        for encoder in state.encoders.iter_mut().filter(|e| e.current_crtc == Some(crtc)) {
+            encoder.current_crtc = None;
+        }
+        for (connector, encoder) in routes {
+            state.encoders[encoder].current_crtc = Some(crtc);
+            let handle = state.encoders[encoder].handle;
+            if let Some(info) = state.connectors.iter_mut().find(|c| c.handle == connector) {
+                info.current_encoder = Some(handle);
+            }
+        }
+        let active = state.encoders.iter().filter_map(|e| e.current_crtc).collect::<Vec<_>>();
+        let encoders = state.encoders.clone();
+        for info in state.connectors.iter_mut() {
+            let driven = info.current_encoder
+                .and_then(|enc| encoders.iter().find(|e| e.handle == enc))
+                .is_some_and(|e| e.current_crtc.is_some());
+            if !driven {
+                info.current_encoder = None;
+            }
+        }
+        for other in state.crtcs.iter_mut().filter(|c| c.info.handle != crtc && !active.contains(&c.info.handle)) {
+            other.info.mode = None;
+            other.info.fb = drm_fb::Handle::from(0u32);
+            other.pending_flip = None;
+        }
+
+        let now = state.now;
+        let target = &mut state.crtcs[index];
+        target.info = CrtcInfo { handle: crtc, position, mode, fb: if mode.is_some() { fb } else { drm_fb::Handle::from(0u32) } };
+        target.scanout_start = now;
+        target.pending_flip = None;
+        Ok(())
+    }
+
+    fn page_flip(&self, crtc: crtc::Handle, fb: drm_fb::Handle) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if !state.master {
+            return Err(fail("page flip", crtc, libc::EACCES));
+        }
+        let target = state.crtc_mut(crtc, "page flip")?;
+
+        if target.info.mode.is_none() || u32::from(fb) == 0 {
+            return Err(fail("page flip", crtc, libc::EINVAL));
+        }
+        if target.pending_flip.is_some() {
+            return Err(fail("page flip", crtc, libc::EBUSY));
+        }
+
+        target.pending_flip = Some(fb);
+        Ok(())
+    }
+
+    fn receive_events(&self) -> Result<Vec<crtc::Event>> {
+        let mut state = self.state.borrow_mut();
+        let now = state.now;
+
+        let next = state.crtcs.iter()
+            .filter(|c| c.pending_flip.is_some())
+            .filter_map(|c| c.next_vblank(now))
+            .map(|(time, _)| time)
+            .min();
+        let time = match next {
+            Some(time) => time,
+            None => return Ok(Vec::new()),
+        };
+        state.now = time;
+
+        let mut events = Vec::new();
+        for fake in state.crtcs.iter_mut() {
+            let (vblank, frame) = match fake.next_vblank(now) {
+                Some(next) => next,
+                None => continue,
+            };
+            if vblank > time {
+                continue;
+            }
+            if let Some(fb) = fake.pending_flip.take() {
+                fake.info.fb = fb;
+                events.push(crtc::Event::PageFlip(PageFlipEvent { frame, duration: vblank, crtc: fake.info.handle }));
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn now(&self) -> Duration {
+        self.state.borrow().now
+    }
+
+    fn add_framebuffer(&self, layout: &BufferLayout, _with_modifier: bool) -> Result<drm_fb::Handle> {
+        if layout.handles[0] == 0 || layout.width == 0 || layout.height == 0 {
+            return Err(fail("add framebuffer", layout.handles[0], libc::EINVAL));
+        }
+
+        Ok(drm_fb::Handle::from(self.state.borrow_mut().allocate_id()))
+    }
+
+    // framebuffers are only ids to the fake, any handle will do
+    fn destroy_framebuffer(&self, _fb: drm_fb::Handle) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_dumb_buffer(&self, width: u32, height: u32) -> Result<DumbMapping> {
+        let mut state = self.state.borrow_mut();
+        let handle = state.allocate_id();
+        let mut memory = vec![0u32; width as usize * height as usize];
+        let map = memory.as_mut_ptr() as *mut u8;
+        state.dumb_buffers.push((handle, memory));
+
+        Ok(DumbMapping { handle, pitch: width * 4, size: width as usize * height as usize * 4, map })
+    }
+
+    fn destroy_dumb_buffer(&self, buffer: &DumbMapping) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.dumb_buffers.iter().position(|&(handle, _)| handle == buffer.handle)
+            .ok_or_else(|| fail("destroy dumb buffer", buffer.handle, libc::ENOENT))?;
+        state.dumb_buffers.remove(index);
+        Ok(())
+    }
+
+    fn object_properties(&self, object: u32, _object_type: u32) -> Result<Vec<(u32, u64)>> {
+        let state = self.state.borrow();
+        Ok(state.property_values.iter()
+            .find(|&&(obj, _)| obj == object)
+            .map(|(_, values)| values.clone())
+            .unwrap_or_default())
+    }
+
+    fn property_info(&self, property: u32) -> Result<PropertyInfo> {
+        let mut state = self.state.borrow_mut();
+        state.property_lookups += 1;
+        state.properties.iter()
+            .find(|p| p.id == property)
+            .cloned()
+            .ok_or_else(|| fail("get property", property, libc::ENOENT))
+    }
+
+    fn get_blob(&self, blob: u32) -> Result<Vec<u8>> {
+        Err(fail("get property blob", blob, libc::ENOENT))
+    }
+
+    // Flip events only come from page_flip, commits asking for one are refused
+    fn atomic_commit(&self, request: &AtomicRequest, flags: CommitFlags, _user_data: u64) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let first = request.properties().next().map_or(0, |(obj, _, _)| obj);
+        if !state.master {
+            return Err(fail("atomic commit", first, libc::EACCES));
+        }
+        if flags.bits() & CommitFlags::PAGE_FLIP_EVENT.bits() != 0 {
+            return Err(fail("atomic commit", first, libc::EINVAL));
+        }
+
+        // every property has to exist on its object before anything changes
+        for (object, property, _) in request.properties() {
+            let known = state.property_values.iter()
+                .any(|&(obj, ref values)| obj == object && values.iter().any(|&(prop, _)| prop == property));
+            if !known {
+                return Err(fail("atomic commit", object, libc::EINVAL));
+            }
+        }
+        for (object, property, value) in request.properties() {
+            state.set_property_value(object, property, value);
+        }
+        Ok(())
+    }
+
+    fn set_cursor(&self, crtc: crtc::Handle, handle: u32, _size: (u32, u32), hotspot: (i32, i32)) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if handle != 0 && !state.dumb_buffers.iter().any(|&(h, _)| h == handle) {
+            return Err(fail("set cursor", crtc, libc::ENOENT));
+        }
+
+        let cursor = state.cursor_mut(crtc, "set cursor")?;
+        cursor.handle = handle;
+        cursor.hotspot = hotspot;
+        Ok(())
+    }
+
+    fn move_cursor(&self, crtc: crtc::Handle, position: (i32, i32)) -> Result<()> {
+        self.state.borrow_mut().cursor_mut(crtc, "move cursor")?.position = position;
+        Ok(())
+    }
+
+    fn set_master(&self) -> Result<()> {
+        self.state.borrow_mut().master = true;
+        Ok(())
+    }
+
+    fn drop_master(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if !state.master {
+            return Err(fail("drop master", 0u32, libc::EINVAL));
+        }
+        state.master = false;
+        Ok(())
+    }
+
+    fn is_master(&self) -> bool {
+        self.state.borrow().master
+    }
+}
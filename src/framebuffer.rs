@@ -1,13 +1,43 @@
+use std::rc::Rc;
+
 use drm::control::framebuffer as dev_fb;
 
+use bo::BufferLayout;
+use device::Gpu;
+use error::Result;
+use kms::KmsDevice;
+
+// Removed from the device when dropped, any plane still showing it goes dark
 pub struct Framebuffer {
     pub drm_fb: dev_fb::Handle,
     pub width: u32,
     pub height: u32,
+    device: Rc<dyn KmsDevice>,
 }
 
 impl Framebuffer {
+    pub fn new(gpu: &Gpu, drm_fb: dev_fb::Handle, width: u32, height: u32) -> Framebuffer {
+        Framebuffer { drm_fb, width, height, device: gpu.kms() }
+    }
+
+    // ADDFB2 with an explicit fourcc and per-plane layout. The modifier only
+    // goes along when the driver takes them, tiled buffers can't be scanned
+    // out without it.
+    pub fn create(gpu: &Gpu, layout: &BufferLayout) -> Result<Framebuffer> {
+        let with_modifier = layout.has_modifier() && gpu.caps.addfb2_modifiers;
+        let drm_fb = gpu.kms().add_framebuffer(layout, with_modifier)?;
+        Ok(Framebuffer::new(gpu, drm_fb, layout.width, layout.height))
+    }
+
     pub fn handle(&self) -> dev_fb::Handle {
         self.drm_fb
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        if let Err(err) = self.device.destroy_framebuffer(self.drm_fb) {
+            eprintln!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use gbm;
+use gl;
+
+use error::{Error, Result};
+use render::RenderDevice;
+
+pub const USAGE: &str = "phoenix render [--surfaceless] [render node]";
+
+// `phoenix render`, one offscreen frame without DRM master or a VT. A smoke
+// test for CI and headless boxes, llvmpipe will do.
+pub fn run(args: &[String]) -> Result<()> {
+    let mut surfaceless = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--surfaceless" => surfaceless = true,
+            flag if flag.starts_with('-') => return Err(Error::Usage(USAGE)),
+            node if path.is_none() => path = Some(PathBuf::from(node)),
+            _ => return Err(Error::Usage(USAGE)),
+        }
+    }
+
+    let render = match path {
+        _ if surfaceless => RenderDevice::surfaceless()?,
+        Some(path) => RenderDevice::open(path)?,
+        None => RenderDevice::open_default()?,
+    };
+
+    let node = render.path().map_or_else(|| "surfaceless EGL".to_owned(), |path| path.display().to_string());
+    let renderer = render.gl_string(gl::RENDERER).unwrap_or_else(|| "an unknown renderer".to_owned());
+    println!("Rendering on {} with {}", node, renderer);
+
+    // there are no buffer objects without a gbm device
+    if render.gbm_device().is_none() {
+        return Ok(());
+    }
+
+    let (width, height) = (64, 64);
+    let target = render.create_target(width, height, gbm::Format::XRGB8888)?;
+    let mut pixel = [0u8; 4];
+    target.bind();
+    unsafe {
+        gl::ClearColor(1.0, 0.0, 0.0, 1.0);
+        gl::Clear(gl::COLOR_BUFFER_BIT);
+        gl::ReadPixels(0, 0, 1, 1, gl::RGBA, gl::UNSIGNED_BYTE, pixel.as_mut_ptr() as *mut _);
+    }
+    render.finish();
+    target.destroy(&render);
+
+    println!("Cleared a {}x{} buffer object to red, read back {:?}", width, height, pixel);
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use drm::control::connector;
+use libc;
+use udev;
+
+use device::Gpu;
+use display::Display;
+use error::Result;
+
+pub enum DisplayEvent {
+    DisplayAdded(Display),
+    DisplayRemoved(connector::Handle),
+    ModesChanged(Display),
+}
+
+// Listens for the kernel's connector hotplug uevents on every drm card
+pub struct HotplugMonitor {
+    socket: udev::MonitorSocket,
+}
+
+impl HotplugMonitor {
+    pub fn new() -> Result<HotplugMonitor> {
+        let context = udev::Context::new()?;
+        let mut builder = udev::MonitorBuilder::new(&context)?;
+        builder.match_subsystem_devtype("drm", "drm_minor")?;
+        let socket = builder.listen()?;
+
+        Ok(HotplugMonitor { socket })
+    }
+
+    // Drains pending uevents without blocking and re-probes the gpus they were
+    // sent for. Events are paired with the index of their gpu in gpus.
+    pub fn dispatch(&mut self, gpus: &mut [&mut Gpu]) -> Result<Vec<(usize, DisplayEvent)>> {
+        let devnums = gpus.iter().map(|gpu| gpu.devnum()).collect::<Result<Vec<_>>>()?;
+        let mut changed = Vec::new();
+
+        for event in &mut self.socket {
+            let hotplug = event.property_value("HOTPLUG").and_then(|v| v.to_str());
+            if let Some(index) = hotplug_gpu(event.event_type(), hotplug, event.devnum(), &devnums) {
+                if !changed.contains(&index) {
+                    changed.push(index);
+                }
+            }
+        }
+
+        let mut events = Vec::new();
+        for index in changed {
+            events.extend(gpus[index].reprobe()?.into_iter().map(|e| (index, e)));
+        }
+
+        Ok(events)
+    }
+}
+
+// Connector changes come as a change uevent with HOTPLUG=1 on the card, the
+// device number tells which of our gpus it was sent for
+fn hotplug_gpu(event_type: udev::EventType, hotplug: Option<&str>, devnum: Option<libc::dev_t>, gpus: &[libc::dev_t]) -> Option<usize> {
+    if event_type != udev::EventType::Change || hotplug != Some("1") {
+        return None;
+    }
+
+    let devnum = devnum?;
+    gpus.iter().position(|&gpu| gpu == devnum)
+}
+
+impl AsRawFd for HotplugMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udev::EventType;
+
+    use super::hotplug_gpu;
+
+    #[test]
+    fn only_hotplug_changes_of_our_gpus_count() {
+        let gpus = [0xe200, 0xe201];
+
+        assert_eq!(hotplug_gpu(EventType::Change, Some("1"), Some(0xe201), &gpus), Some(1));
+        assert_eq!(hotplug_gpu(EventType::Change, Some("1"), Some(0xe202), &gpus), None);
+        assert_eq!(hotplug_gpu(EventType::Change, Some("1"), None, &gpus), None);
+        // e.g. a lease or a property change, nothing was plugged
+        assert_eq!(hotplug_gpu(EventType::Change, None, Some(0xe200), &gpus), None);
+        assert_eq!(hotplug_gpu(EventType::Change, Some("0"), Some(0xe200), &gpus), None);
+        // a card coming or going isn't a connector hotplug
+        assert_eq!(hotplug_gpu(EventType::Add, Some("1"), Some(0xe200), &gpus), None);
+        assert_eq!(hotplug_gpu(EventType::Remove, Some("1"), Some(0xe200), &gpus), None);
+    }
+}
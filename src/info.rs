@@ -0,0 +1,588 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use drm::control::{connector, Mode};
+use drm::ffi;
+use gl;
+
+use caps::Capabilities;
+use device::{self, Gpu, ModesetBackend};
+use edid::{Edid, HdrStaticMetadata};
+use error::{Error, Result};
+use kms::{ConnectorInfo, CrtcInfo, EncoderInfo};
+use mode;
+use plane::{Plane, PlaneType, Zpos};
+use property::{ObjectType, Property, PropertyValue};
+use render::RenderDevice;
+
+pub const USAGE: &str = "phoenix info [--json] [device...]";
+
+// `phoenix info`, the KMS topology of every gpu on seat0 or of the given
+// device nodes, as indented text or as JSON for bug reports
+pub fn run(args: &[String]) -> Result<()> {
+    let mut json = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            flag if flag.starts_with('-') => return Err(Error::Usage(USAGE)),
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+
+    if paths.is_empty() {
+        paths = device::enumerate("seat0")?.into_iter().map(|gpu| gpu.devnode).collect();
+    }
+
+    let report = Value::Array(paths.iter().map(|path| describe_device(path)).collect());
+    let mut out = String::new();
+    if json {
+        report.write_json(&mut out, 0);
+        out.push('\n');
+    } else {
+        report.write_text(&mut out, 0);
+    }
+
+    print!("{}", out);
+    Ok(())
+}
+
+// A device that fails to open still shows up, with the reason
+fn describe_device(path: &Path) -> Value {
+    let mut gpu = match device::open(path) {
+        Ok(gpu) => gpu,
+        Err(err) => return object(vec![("device", path.display().to_string().into()), ("error", err.to_string().into())]),
+    };
+    // atomic only properties are hidden otherwise
+    gpu.select_backend(ModesetBackend::Atomic);
+
+    let mut fields = vec![
+        ("device", path.display().to_string().into()),
+        ("capabilities", describe_caps(&gpu.caps)),
+        ("connectors", gpu.connectors.iter().map(|c| describe_connector(&gpu, c)).collect::<Vec<_>>().into()),
+        ("encoders", gpu.encoders.iter().map(|e| describe_encoder(&gpu, e)).collect::<Vec<_>>().into()),
+        ("crtcs", gpu.crtcs.iter().enumerate().map(|(index, c)| describe_crtc(&gpu, index, c)).collect::<Vec<_>>().into()),
+        ("planes", gpu.planes.iter().map(|p| describe_plane(&gpu, p)).collect::<Vec<_>>().into()),
+        ("lessees", describe_lessees(&gpu)),
+    ];
+
+    match RenderDevice::open(path) {
+        Ok(render) => {
+            fields.push(("egl", object(vec![("extensions", render.egl_extensions().into())])));
+            fields.push(("gl", describe_gl(&render)));
+        }
+        Err(err) => fields.push(("egl", object(vec![("error", err.to_string().into())]))),
+    }
+
+    object(fields)
+}
+
+// Only masters may list them, an error is the usual answer under a compositor
+fn describe_lessees(gpu: &Gpu) -> Value {
+    match gpu.leases() {
+        Ok(lessees) => lessees.into(),
+        Err(err) => object(vec![("error", err.to_string().into())]),
+    }
+}
+
+fn describe_caps(caps: &Capabilities) -> Value {
+    let driver = &caps.driver;
+    object(vec![
+        ("driver", driver.name.clone().into()),
+        ("driver_version", format!("{}.{}.{}", driver.major, driver.minor, driver.patchlevel).into()),
+        ("driver_date", driver.date.clone().into()),
+        ("driver_description", driver.description.clone().into()),
+        ("dumb_buffer", caps.dumb_buffer.into()),
+        ("prime_import", caps.prime_import.into()),
+        ("prime_export", caps.prime_export.into()),
+        ("async_page_flip", caps.async_page_flip.into()),
+        ("addfb2_modifiers", caps.addfb2_modifiers.into()),
+        ("cursor_size", vec![caps.cursor_size.0, caps.cursor_size.1].into()),
+        ("crtc_in_vblank_event", caps.crtc_in_vblank_event.into()),
+        ("timestamp_monotonic", caps.timestamp_monotonic.into()),
+    ])
+}
+
+fn describe_connector(gpu: &Gpu, info: &ConnectorInfo) -> Value {
+    let state = match info.state {
+        connector::State::Connected => "connected",
+        connector::State::Disconnected => "disconnected",
+        connector::State::Unknown => "unknown",
+    };
+
+    object(vec![
+        ("id", u32::from(info.handle).into()),
+        ("name", ::connector::name(info.interface, info.interface_id).into()),
+        ("state", state.into()),
+        ("encoder", info.current_encoder.map(u32::from).into()),
+        ("encoders", info.encoders.iter().map(|&e| u32::from(e)).collect::<Vec<_>>().into()),
+        ("edid", info.edid.as_ref().map(describe_edid).into()),
+        ("modes", info.modes.iter().map(describe_mode).collect::<Vec<_>>().into()),
+        ("properties", describe_properties(gpu, u32::from(info.handle), ObjectType::Connector)),
+    ])
+}
+
+fn describe_edid(edid: &Edid) -> Value {
+    let serial = match edid.serial_string {
+        Some(ref serial) => serial.clone(),
+        None => edid.serial.to_string(),
+    };
+
+    object(vec![
+        ("identifier", edid.identifier().into()),
+        ("manufacturer", edid.manufacturer.clone().into()),
+        ("product_code", format!("0x{:04x}", edid.product_code).into()),
+        ("serial", serial.into()),
+        ("name", edid.name.clone().into()),
+        ("year", edid.year.into()),
+        ("version", format!("{}.{}", edid.version.0, edid.version.1).into()),
+        ("size_mm", edid.size_mm.map(|(width, height)| vec![width, height]).into()),
+        ("hdr", edid.hdr_static_metadata().map(describe_hdr).into()),
+    ])
+}
+
+fn describe_hdr(hdr: &HdrStaticMetadata) -> Value {
+    let eotfs = [
+        (HdrStaticMetadata::EOTF_SDR, "sdr"),
+        (HdrStaticMetadata::EOTF_HDR, "hdr"),
+        (HdrStaticMetadata::EOTF_PQ, "pq"),
+        (HdrStaticMetadata::EOTF_HLG, "hlg"),
+    ];
+    let luminance = |value: Option<f32>| value.map(|value| Value::Number(value.to_string()));
+
+    object(vec![
+        ("eotfs", eotfs.iter().filter(|&&(eotf, _)| hdr.supports_eotf(eotf)).map(|&(_, name)| name).collect::<Vec<_>>().into()),
+        ("max_luminance", luminance(hdr.max_luminance).into()),
+        ("max_frame_average", luminance(hdr.max_frame_average).into()),
+        ("min_luminance", luminance(hdr.min_luminance).into()),
+    ])
+}
+
+fn describe_mode(mode: &Mode) -> Value {
+    let raw = mode::as_raw(mode);
+    let refresh = mode::refresh_mhz(mode);
+    let name = raw.name.iter().take_while(|&&c| c != 0).map(|&c| c as u8 as char).collect::<String>();
+
+    let mut flags = Vec::new();
+    for &(bit, flag) in &[
+        (ffi::DRM_MODE_FLAG_PHSYNC, "phsync"),
+        (ffi::DRM_MODE_FLAG_NHSYNC, "nhsync"),
+        (ffi::DRM_MODE_FLAG_PVSYNC, "pvsync"),
+        (ffi::DRM_MODE_FLAG_NVSYNC, "nvsync"),
+        (ffi::DRM_MODE_FLAG_INTERLACE, "interlace"),
+        (ffi::DRM_MODE_FLAG_DBLSCAN, "dblscan"),
+    ] {
+        if raw.flags & bit != 0 {
+            flags.push(flag);
+        }
+    }
+    if mode::is_preferred(mode) {
+        flags.push("preferred");
+    }
+
+    object(vec![
+        ("name", name.into()),
+        ("refresh", format!("{}.{:03}", refresh / 1000, refresh % 1000).into()),
+        ("clock", raw.clock.into()),
+        ("horizontal", vec![raw.hdisplay, raw.hsync_start, raw.hsync_end, raw.htotal].into()),
+        ("vertical", vec![raw.vdisplay, raw.vsync_start, raw.vsync_end, raw.vtotal].into()),
+        ("flags", flags.into()),
+    ])
+}
+
+fn describe_encoder(gpu: &Gpu, info: &EncoderInfo) -> Value {
+    object(vec![
+        ("id", u32::from(info.handle).into()),
+        ("crtc", info.current_crtc.map(u32::from).into()),
+        ("possible_crtcs", crtc_ids(gpu, info.possible_crtcs)),
+    ])
+}
+
+fn describe_crtc(gpu: &Gpu, index: usize, info: &CrtcInfo) -> Value {
+    let fb = u32::from(info.fb);
+    object(vec![
+        ("id", u32::from(info.handle).into()),
+        ("index", index.into()),
+        ("position", vec![info.position.0, info.position.1].into()),
+        ("mode", info.mode.as_ref().map(describe_mode).into()),
+        ("framebuffer", if fb != 0 { Some(fb) } else { None }.into()),
+        ("properties", describe_properties(gpu, u32::from(info.handle), ObjectType::Crtc)),
+    ])
+}
+
+fn describe_plane(gpu: &Gpu, plane: &Plane) -> Value {
+    let plane_type = match plane.plane_type {
+        PlaneType::Primary => "primary",
+        PlaneType::Overlay => "overlay",
+        PlaneType::Cursor => "cursor",
+    };
+
+    let modifiers = plane.formats.iter()
+        .filter(|&&format| !plane.modifiers_for(format).is_empty())
+        .map(|&format| {
+            let modifiers = plane.modifiers_for(format).iter().map(|m| format!("0x{:016x}", m)).collect::<Vec<_>>();
+            (fourcc(format), modifiers.into())
+        })
+        .collect();
+
+    object(vec![
+        ("id", u32::from(plane.handle).into()),
+        ("type", plane_type.into()),
+        ("crtc", plane.crtc.map(u32::from).into()),
+        ("possible_crtcs", crtc_ids(gpu, plane.possible_crtcs)),
+        ("formats", plane.formats.iter().map(|&f| fourcc(f)).collect::<Vec<_>>().into()),
+        ("modifiers", Value::Object(modifiers)),
+        ("zpos", plane.zpos.as_ref().map(describe_zpos).into()),
+        ("properties", describe_properties(gpu, u32::from(plane.handle), ObjectType::Plane)),
+    ])
+}
+
+fn describe_zpos(zpos: &Zpos) -> Value {
+    object(vec![
+        ("current", zpos.current.into()),
+        ("range", vec![zpos.min, zpos.max].into()),
+        ("mutable", zpos.mutable.into()),
+    ])
+}
+
+fn describe_properties(gpu: &Gpu, object: u32, object_type: ObjectType) -> Value {
+    match gpu.properties(object, object_type) {
+        Ok(properties) => Value::Object(properties.iter().map(|p| (p.info.name.clone(), describe_property(p))).collect()),
+        Err(err) => self::object(vec![("error", err.to_string().into())]),
+    }
+}
+
+fn describe_property(property: &Property) -> Value {
+    match property.value {
+        PropertyValue::Unsigned(value) => value.into(),
+        PropertyValue::Signed(value) => Value::Number(value.to_string()),
+        PropertyValue::Enum(ref name) => name.clone().into(),
+        PropertyValue::Bitmask(ref names) => names.clone().into(),
+        // EDIDs and LUTs are unreadable dumped raw
+        PropertyValue::Blob(ref data) => data.as_ref().map(|data| format!("<{} byte blob>", data.len())).into(),
+        PropertyValue::Object(id) => id.into(),
+    }
+}
+
+fn describe_gl(render: &RenderDevice) -> Value {
+    let extensions = render.gl_string(gl::EXTENSIONS)
+        .map(|extensions| extensions.split_whitespace().map(String::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    object(vec![
+        ("version", render.gl_string(gl::VERSION).into()),
+        ("vendor", render.gl_string(gl::VENDOR).into()),
+        ("renderer", render.gl_string(gl::RENDERER).into()),
+        ("extensions", extensions.into()),
+    ])
+}
+
+fn crtc_ids(gpu: &Gpu, possible_crtcs: u32) -> Value {
+    gpu.resources.filter_crtcs(possible_crtcs).into_iter().map(u32::from).collect::<Vec<_>>().into()
+}
+
+// DRM_FORMAT_XRGB8888 reads XR24, the same as drm_info and modetest print
+fn fourcc(format: u32) -> String {
+    (0..4).map(|i| ((format >> (i * 8)) & 0xff) as u8 as char).collect()
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+// Just enough of a JSON tree to print the report both ways without pulling
+// in serde
+enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn is_scalar(&self) -> bool {
+        !matches!(*self, Value::Array(_) | Value::Object(_))
+    }
+
+    fn write_json(&self, out: &mut String, indent: usize) {
+        match *self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(value) => out.push_str(if value { "true" } else { "false" }),
+            Value::Number(ref value) => out.push_str(value),
+            Value::String(ref value) => write_json_string(out, value),
+            Value::Array(ref items) if items.is_empty() => out.push_str("[]"),
+            Value::Object(ref fields) if fields.is_empty() => out.push_str("{}"),
+            Value::Array(ref items) => {
+                out.push_str("[\n");
+                for (n, item) in items.iter().enumerate() {
+                    push_indent(out, indent + 2);
+                    item.write_json(out, indent + 2);
+                    out.push_str(if n + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                push_indent(out, indent);
+                out.push(']');
+            }
+            Value::Object(ref fields) => {
+                out.push_str("{\n");
+                for (n, (key, value)) in fields.iter().enumerate() {
+                    push_indent(out, indent + 2);
+                    write_json_string(out, key);
+                    out.push_str(": ");
+                    value.write_json(out, indent + 2);
+                    out.push_str(if n + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                push_indent(out, indent);
+                out.push('}');
+            }
+        }
+    }
+
+    // Indented key: value lines, short lists of scalars stay on one line
+    fn write_text(&self, out: &mut String, indent: usize) {
+        match *self {
+            Value::Array(ref items) => {
+                for item in items {
+                    let mut entry = String::new();
+                    if item.is_scalar() {
+                        push_indent(&mut entry, indent + 2);
+                        item.write_scalar(&mut entry);
+                        entry.push('\n');
+                    } else {
+                        item.write_text(&mut entry, indent + 2);
+                    }
+                    // the first line of the entry carries the list marker
+                    if entry.is_empty() {
+                        push_indent(&mut entry, indent + 2);
+                        item.write_inline(&mut entry);
+                        entry.push('\n');
+                    }
+                    entry.replace_range(indent..indent + 2, "- ");
+                    out.push_str(&entry);
+                }
+            }
+            Value::Object(ref fields) => {
+                for (key, value) in fields {
+                    push_indent(out, indent);
+                    out.push_str(key);
+                    out.push(':');
+                    if value.is_inline() {
+                        out.push(' ');
+                        value.write_inline(out);
+                        out.push('\n');
+                    } else {
+                        out.push('\n');
+                        value.write_text(out, indent + 2);
+                    }
+                }
+            }
+            _ => {
+                push_indent(out, indent);
+                self.write_scalar(out);
+                out.push('\n');
+            }
+        }
+    }
+
+    fn is_inline(&self) -> bool {
+        match *self {
+            Value::Array(ref items) => items.len() <= 8 && items.iter().all(Value::is_scalar),
+            Value::Object(ref fields) => fields.is_empty(),
+            _ => true,
+        }
+    }
+
+    fn write_inline(&self, out: &mut String) {
+        match *self {
+            Value::Array(ref items) => {
+                out.push('[');
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write_scalar(out);
+                }
+                out.push(']');
+            }
+            Value::Object(_) => out.push_str("{}"),
+            _ => self.write_scalar(out),
+        }
+    }
+
+    fn write_scalar(&self, out: &mut String) {
+        match *self {
+            Value::Null => out.push('-'),
+            Value::Bool(value) => out.push_str(if value { "yes" } else { "no" }),
+            Value::Number(ref value) | Value::String(ref value) => out.push_str(value),
+            _ => {}
+        }
+    }
+}
+
+fn push_indent(out: &mut String, indent: usize) {
+    for _ in 0..indent {
+        out.push(' ');
+    }
+}
+
+fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Value {
+        Value::Number(value.to_string())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Number(value.to_string())
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Value {
+        Value::Number(value.to_string())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Number(value.to_string())
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value::String(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{object, Value};
+
+    fn json(value: &Value) -> String {
+        let mut out = String::new();
+        value.write_json(&mut out, 0);
+        out
+    }
+
+    fn text(value: &Value) -> String {
+        let mut out = String::new();
+        value.write_text(&mut out, 0);
+        out
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json(&"a\"b\\c\nd\u{1}é".into()), "\"a\\\"b\\\\c\\nd\\u0001é\"");
+        assert_eq!(json(&object(vec![("tab\t", Value::Null)])), "{\n  \"tab\\u0009\": null\n}");
+    }
+
+    #[test]
+    fn json_nests_and_keeps_empty_containers_short() {
+        let value = object(vec![
+            ("empty", Value::Array(Vec::new())),
+            ("none", Value::Object(Vec::new())),
+            ("list", Value::Array(vec![1u32.into(), object(vec![("x", true.into())]), Value::Array(Vec::new())])),
+            ("null", Value::Null),
+        ]);
+
+        assert_eq!(json(&value), concat!(
+            "{\n",
+            "  \"empty\": [],\n",
+            "  \"none\": {},\n",
+            "  \"list\": [\n",
+            "    1,\n",
+            "    {\n",
+            "      \"x\": true\n",
+            "    },\n",
+            "    []\n",
+            "  ],\n",
+            "  \"null\": null\n",
+            "}",
+        ));
+    }
+
+    #[test]
+    fn text_marks_list_entries() {
+        let value = object(vec![
+            ("name", "card0".into()),
+            ("ok", true.into()),
+            ("missing", Value::Null),
+            ("sizes", vec![1u32, 2].into()),
+            ("empty", Value::Object(Vec::new())),
+            ("planes", Value::Array(vec![
+                object(vec![("id", 31u32.into()), ("type", "primary".into())]),
+                Value::Object(Vec::new()),
+                Value::Array(Vec::new()),
+                "x".into(),
+            ])),
+            ("many", (0..9u32).collect::<Vec<_>>().into()),
+        ]);
+
+        let mut expected = String::from(concat!(
+            "name: card0\n",
+            "ok: yes\n",
+            "missing: -\n",
+            "sizes: [1, 2]\n",
+            "empty: {}\n",
+            "planes:\n",
+            "  - id: 31\n",
+            "    type: primary\n",
+            "  - {}\n",
+            "  - []\n",
+            "  - x\n",
+            "many:\n",
+        ));
+        for n in 0..9 {
+            expected.push_str(&format!("  - {}\n", n));
+        }
+        assert_eq!(text(&value), expected);
+    }
+
+    #[test]
+    fn text_nests_lists_in_lists() {
+        let value = Value::Array(vec![vec![1u32, 2].into(), "y".into()]);
+        assert_eq!(text(&value), "- - 1\n  - 2\n- y\n");
+    }
+}
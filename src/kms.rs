@@ -0,0 +1,340 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::Duration;
+
+use drm::Device as DrmDevice;
+use drm::control::Device as DrmControlDevice;
+use drm::control::{connector, crtc, encoder, Mode, ResourceHandles};
+use drm::control::framebuffer as drm_fb;
+use drm::ffi;
+use libc;
+
+use atomic::{AtomicRequest, CommitFlags};
+use bo::BufferLayout;
+use device::DeviceFile;
+use edid::Edid;
+use error::{Error, Result};
+use property::{self, PropertyInfo};
+
+// drm-rs only hands out its resource infos from ioctls, these can be built by
+// anything implementing KmsDevice
+#[derive(Debug, Clone, Default)]
+pub struct Resources {
+    pub connectors: Vec<connector::Handle>,
+    pub encoders: Vec<encoder::Handle>,
+    pub crtcs: Vec<crtc::Handle>,
+}
+
+impl Resources {
+    // Bit n of a possible_crtcs mask stands for the nth crtc
+    pub fn filter_crtcs(&self, possible_crtcs: u32) -> Vec<crtc::Handle> {
+        self.crtcs.iter()
+            .enumerate()
+            .filter(|&(n, _)| n < 32 && possible_crtcs & (1 << n) != 0)
+            .map(|(_, &crtc)| crtc)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectorInfo {
+    pub handle: connector::Handle,
+    pub interface: connector::Type,
+    pub interface_id: u32,
+    pub state: connector::State,
+    pub modes: Vec<Mode>,
+    pub current_encoder: Option<encoder::Handle>,
+    pub encoders: Vec<encoder::Handle>,
+    // read along with the connector, the EDID only changes on hotplug
+    pub edid: Option<Edid>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderInfo {
+    pub handle: encoder::Handle,
+    pub current_crtc: Option<crtc::Handle>,
+    pub possible_crtcs: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrtcInfo {
+    pub handle: crtc::Handle,
+    pub position: (u32, u32),
+    pub mode: Option<Mode>,
+    pub fb: drm_fb::Handle,
+}
+
+// XRGB8888 dumb buffer and where it's mapped into our memory
+#[derive(Debug, Clone, Copy)]
+pub struct DumbMapping {
+    pub handle: u32,
+    pub pitch: u32,
+    pub size: usize,
+    pub map: *mut u8,
+}
+
+// The mode setting calls Gpu makes, the buffers it scans out, the property
+// reads and the cursor, so the connector, crtc, flip, property and cursor
+// logic can run against the in-memory fake in tests. Legacy property writes,
+// blobs and planes still talk to the device node directly.
+pub trait KmsDevice: AsRawFd {
+    fn resources(&self) -> Result<Resources>;
+    fn connector_info(&self, connector: connector::Handle) -> Result<ConnectorInfo>;
+    fn encoder_info(&self, encoder: encoder::Handle) -> Result<EncoderInfo>;
+    fn crtc_info(&self, crtc: crtc::Handle) -> Result<CrtcInfo>;
+    fn set_crtc(&self, crtc: crtc::Handle, fb: drm_fb::Handle, connectors: &[connector::Handle], position: (u32, u32), mode: Option<Mode>) -> Result<()>;
+    // Always asks for a flip event, the event comes back through receive_events
+    fn page_flip(&self, crtc: crtc::Handle, fb: drm_fb::Handle) -> Result<()>;
+    fn receive_events(&self) -> Result<Vec<crtc::Event>>;
+    // CLOCK_MONOTONIC, the clock flip events are stamped with
+    fn now(&self) -> Duration;
+    // ADDFB2, the modifier only goes along when with_modifier is set
+    fn add_framebuffer(&self, layout: &BufferLayout, with_modifier: bool) -> Result<drm_fb::Handle>;
+    fn destroy_framebuffer(&self, fb: drm_fb::Handle) -> Result<()>;
+    fn create_dumb_buffer(&self, width: u32, height: u32) -> Result<DumbMapping>;
+    // Unmaps the buffer too, the mapping is gone afterwards
+    fn destroy_dumb_buffer(&self, buffer: &DumbMapping) -> Result<()>;
+    // (property id, current value) pairs of the object
+    fn object_properties(&self, object: u32, object_type: u32) -> Result<Vec<(u32, u64)>>;
+    fn property_info(&self, property: u32) -> Result<PropertyInfo>;
+    fn get_blob(&self, blob: u32) -> Result<Vec<u8>>;
+    fn atomic_commit(&self, request: &AtomicRequest, flags: CommitFlags, user_data: u64) -> Result<()>;
+    // Legacy cursor ioctls, a handle of 0 takes the cursor off the crtc
+    fn set_cursor(&self, crtc: crtc::Handle, handle: u32, size: (u32, u32), hotspot: (i32, i32)) -> Result<()>;
+    fn move_cursor(&self, crtc: crtc::Handle, position: (i32, i32)) -> Result<()>;
+    fn set_master(&self) -> Result<()>;
+    fn drop_master(&self) -> Result<()>;
+    fn is_master(&self) -> bool;
+
+    fn load_connectors(&self, resources: &Resources) -> Result<Vec<ConnectorInfo>> {
+        resources.connectors.iter().map(|&c| self.connector_info(c)).collect()
+    }
+
+    fn load_encoders(&self, resources: &Resources) -> Result<Vec<EncoderInfo>> {
+        resources.encoders.iter().map(|&e| self.encoder_info(e)).collect()
+    }
+
+    fn load_crtcs(&self, resources: &Resources) -> Result<Vec<CrtcInfo>> {
+        resources.crtcs.iter().map(|&c| self.crtc_info(c)).collect()
+    }
+}
+
+impl KmsDevice for DeviceFile {
+    fn resources(&self) -> Result<Resources> {
+        let handles = ResourceHandles::load_from_device(self)
+            .map_err(|err| Error::drm("get resources", 0u32, err))?;
+
+        Ok(Resources {
+            connectors: handles.connectors().to_vec(),
+            encoders: handles.encoders().to_vec(),
+            crtcs: handles.crtcs().to_vec(),
+        })
+    }
+
+    fn connector_info(&self, connector: connector::Handle) -> Result<ConnectorInfo> {
+        let info: connector::Info = self.resource_info(connector)
+            .map_err(|err| Error::drm("load resource info", connector, err))?;
+        let interface_id = ::connector::interface_id(self, connector).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            0
+        });
+
+        Ok(ConnectorInfo {
+            handle: connector,
+            interface: info.connector_type(),
+            interface_id,
+            state: info.connection_state(),
+            modes: info.modes().to_vec(),
+            current_encoder: info.current_encoder(),
+            encoders: info.encoders().to_vec(),
+            edid: ::connector::edid(self, connector),
+        })
+    }
+
+    // drm-rs keeps the possible_crtcs mask to itself, so this one is raw
+    fn encoder_info(&self, encoder: encoder::Handle) -> Result<EncoderInfo> {
+        let mut raw = ffi::drm_mode_get_encoder { encoder_id: encoder.into(), ..Default::default() };
+
+        unsafe { ffi::ioctl_mode_getencoder(self.as_raw_fd(), &mut raw) }
+            .map_err(|err| Error::ioctl("get encoder", encoder, err))?;
+
+        let current_crtc = if raw.crtc_id != 0 { Some(crtc::Handle::from(raw.crtc_id)) } else { None };
+        Ok(EncoderInfo { handle: encoder, current_crtc, possible_crtcs: raw.possible_crtcs })
+    }
+
+    fn crtc_info(&self, crtc: crtc::Handle) -> Result<CrtcInfo> {
+        let info: crtc::Info = self.resource_info(crtc)
+            .map_err(|err| Error::drm("load resource info", crtc, err))?;
+
+        Ok(CrtcInfo { handle: crtc, position: info.position(), mode: info.mode(), fb: info.fb() })
+    }
+
+    fn set_crtc(&self, crtc: crtc::Handle, fb: drm_fb::Handle, connectors: &[connector::Handle], position: (u32, u32), mode: Option<Mode>) -> Result<()> {
+        crtc::set(self, crtc, fb, connectors, position, mode)
+            .map_err(|err| Error::drm("set crtc", crtc, err))
+    }
+
+    fn page_flip(&self, crtc: crtc::Handle, fb: drm_fb::Handle) -> Result<()> {
+        crtc::page_flip(self, crtc, fb, &[crtc::PageFlipFlags::PageFlipEvent])
+            .map_err(|err| Error::drm("page flip", crtc, err))
+    }
+
+    fn receive_events(&self) -> Result<Vec<crtc::Event>> {
+        crtc::receive_events(self)
+            .map(|events| events.collect())
+            .map_err(|err| Error::drm("receive events", 0u32, err))
+    }
+
+    fn now(&self) -> Duration {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+
+    fn add_framebuffer(&self, layout: &BufferLayout, with_modifier: bool) -> Result<drm_fb::Handle> {
+        let mut raw = ffi::drm_mode_fb_cmd2 {
+            width: layout.width,
+            height: layout.height,
+            pixel_format: layout.format,
+            handles: layout.handles,
+            pitches: layout.pitches,
+            offsets: layout.offsets,
+            ..Default::default()
+        };
+
+        if with_modifier {
+            raw.flags = ffi::DRM_MODE_FB_MODIFIERS;
+            for plane in 0..layout.planes() {
+                raw.modifier[plane] = layout.modifier;
+            }
+        }
+
+        unsafe { ffi::ioctl_mode_addfb2(self.as_raw_fd(), &mut raw) }
+            .map_err(|err| Error::ioctl("add framebuffer", layout.handles[0], err))?;
+
+        Ok(drm_fb::Handle::from(raw.fb_id))
+    }
+
+    fn destroy_framebuffer(&self, fb: drm_fb::Handle) -> Result<()> {
+        drm_fb::destroy(self, fb)
+            .map_err(|err| Error::drm("destroy framebuffer", fb, err))
+    }
+
+    fn create_dumb_buffer(&self, width: u32, height: u32) -> Result<DumbMapping> {
+        let mut create = ffi::drm_mode_create_dumb { width, height, bpp: 32, ..Default::default() };
+        unsafe { ffi::ioctl_mode_create_dumb(self.as_raw_fd(), &mut create) }
+            .map_err(|err| Error::ioctl("create dumb buffer", 0u32, err))?;
+
+        let mut buffer = DumbMapping { handle: create.handle, pitch: create.pitch, size: create.size as usize, map: ptr::null_mut() };
+        let mut map = ffi::drm_mode_map_dumb { handle: create.handle, ..Default::default() };
+        let mapped = unsafe { ffi::ioctl_mode_map_dumb(self.as_raw_fd(), &mut map) }
+            .map_err(|err| Error::ioctl("map dumb buffer", create.handle, err))
+            .and_then(|_| {
+                let addr = unsafe {
+                    libc::mmap(ptr::null_mut(), buffer.size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, self.as_raw_fd(), map.offset as libc::off_t)
+                };
+                if addr == libc::MAP_FAILED {
+                    return Err(Error::ioctl("mmap dumb buffer", create.handle, io::Error::last_os_error()));
+                }
+                Ok(addr as *mut u8)
+            });
+
+        match mapped {
+            Ok(map) => {
+                buffer.map = map;
+                Ok(buffer)
+            }
+            Err(err) => {
+                if let Err(err) = self.destroy_dumb_buffer(&buffer) {
+                    eprintln!("{}", err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn destroy_dumb_buffer(&self, buffer: &DumbMapping) -> Result<()> {
+        if !buffer.map.is_null() {
+            unsafe { libc::munmap(buffer.map as *mut libc::c_void, buffer.size) };
+        }
+
+        let mut destroy = ffi::drm_mode_destroy_dumb { handle: buffer.handle };
+        unsafe { ffi::ioctl_mode_destroy_dumb(self.as_raw_fd(), &mut destroy) }
+            .map_err(|err| Error::ioctl("destroy dumb buffer", buffer.handle, err))?;
+
+        Ok(())
+    }
+
+    fn object_properties(&self, object: u32, object_type: u32) -> Result<Vec<(u32, u64)>> {
+        property::object_properties(self, object, object_type)
+    }
+
+    fn property_info(&self, property: u32) -> Result<PropertyInfo> {
+        property::property_info(self, property)
+    }
+
+    fn get_blob(&self, blob: u32) -> Result<Vec<u8>> {
+        property::get_blob(self, blob)
+    }
+
+    fn atomic_commit(&self, request: &AtomicRequest, flags: CommitFlags, user_data: u64) -> Result<()> {
+        request.commit(self, flags, user_data)
+    }
+
+    // CURSOR2 carries the hotspot for virtual machine drivers, older kernels
+    // only know the plain ioctl
+    fn set_cursor(&self, crtc: crtc::Handle, handle: u32, size: (u32, u32), hotspot: (i32, i32)) -> Result<()> {
+        let mut raw = ffi::drm_mode_cursor2 {
+            flags: ffi::DRM_MODE_CURSOR_BO,
+            crtc_id: crtc.into(),
+            width: size.0,
+            height: size.1,
+            handle,
+            hot_x: hotspot.0,
+            hot_y: hotspot.1,
+            ..Default::default()
+        };
+        if unsafe { ffi::ioctl_mode_cursor2(self.as_raw_fd(), &mut raw) }.is_ok() {
+            return Ok(());
+        }
+
+        let mut raw = ffi::drm_mode_cursor {
+            flags: ffi::DRM_MODE_CURSOR_BO,
+            crtc_id: crtc.into(),
+            width: size.0,
+            height: size.1,
+            handle,
+            ..Default::default()
+        };
+        unsafe { ffi::ioctl_mode_cursor(self.as_raw_fd(), &mut raw) }
+            .map_err(|err| Error::ioctl("set cursor", crtc, err))?;
+
+        Ok(())
+    }
+
+    fn move_cursor(&self, crtc: crtc::Handle, position: (i32, i32)) -> Result<()> {
+        crtc::move_cursor(self, crtc, position)
+            .map_err(|err| Error::drm("move cursor", crtc, err))
+    }
+
+    fn set_master(&self) -> Result<()> {
+        DrmDevice::set_master(self)
+            .map_err(|err| Error::drm("set master", 0u32, err))
+    }
+
+    fn drop_master(&self) -> Result<()> {
+        DrmDevice::drop_master(self)
+            .map_err(|err| Error::drm("drop master", 0u32, err))
+    }
+
+    // A magic of 0 is rejected with EINVAL for masters and with EACCES for
+    // everyone else
+    fn is_master(&self) -> bool {
+        let auth = ffi::drm_auth { magic: 0 };
+        match unsafe { ffi::ioctl_auth_magic(self.as_raw_fd(), &auth) } {
+            Ok(_) => true,
+            Err(err) => err.errno() as i32 != libc::EACCES,
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use libc;
+
+use error::{Error, Result};
+
+// drm-rs predates leases, these mirror the drm_mode_*_lease structs of
+// include/uapi/drm/drm_mode.h
+#[repr(C)]
+#[derive(Default)]
+struct CreateLease {
+    object_ids: u64,
+    object_count: u32,
+    flags: u32,
+    lessee_id: u32,
+    fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ListLessees {
+    count_lessees: u32,
+    pad: u32,
+    lessees_ptr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RevokeLease {
+    lessee_id: u32,
+}
+
+// DRM_IOWR('d', nr, T)
+const fn iowr<T>(nr: u32) -> libc::c_ulong {
+    ((3 << 30) | ((mem::size_of::<T>() as u32) << 16) | (0x64 << 8) | nr) as libc::c_ulong
+}
+
+const CREATE_LEASE: libc::c_ulong = iowr::<CreateLease>(0xC6);
+const LIST_LESSEES: libc::c_ulong = iowr::<ListLessees>(0xC7);
+const REVOKE_LEASE: libc::c_ulong = iowr::<RevokeLease>(0xC9);
+
+// A lessee fd sees only the leased connectors, crtcs and planes and is
+// master over them. The lease ends when every copy of the fd is closed or
+// the lessor revokes it.
+pub struct Lease {
+    pub lessee_id: u32,
+    pub objects: Vec<u32>,
+    fd: File,
+}
+
+impl AsRawFd for Lease {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+// Hands the fd out, e.g. to pass it to a VR runtime over a unix socket
+impl IntoRawFd for Lease {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+pub fn create_lease<D: AsRawFd>(device: &D, objects: &[u32]) -> Result<Lease> {
+    let mut raw = CreateLease {
+        object_ids: objects.as_ptr() as u64,
+        object_count: objects.len() as u32,
+        flags: libc::O_CLOEXEC as u32,
+        ..Default::default()
+    };
+
+    if unsafe { libc::ioctl(device.as_raw_fd(), CREATE_LEASE, &mut raw) } < 0 {
+        return Err(Error::ioctl("create lease", objects.first().cloned().unwrap_or(0), io::Error::last_os_error()));
+    }
+
+    let fd = unsafe { File::from_raw_fd(raw.fd as RawFd) };
+    Ok(Lease { lessee_id: raw.lessee_id, objects: objects.to_vec(), fd })
+}
+
+pub fn lessees<D: AsRawFd>(device: &D) -> Result<Vec<u32>> {
+    let mut raw = ListLessees::default();
+    if unsafe { libc::ioctl(device.as_raw_fd(), LIST_LESSEES, &mut raw) } < 0 {
+        return Err(Error::ioctl("list lessees", 0u32, io::Error::last_os_error()));
+    }
+
+    let mut lessees = vec![0u32; raw.count_lessees as usize];
+    raw.lessees_ptr = lessees.as_mut_ptr() as u64;
+    if unsafe { libc::ioctl(device.as_raw_fd(), LIST_LESSEES, &mut raw) } < 0 {
+        return Err(Error::ioctl("list lessees", 0u32, io::Error::last_os_error()));
+    }
+
+    // a lease may have ended in between the two calls
+    lessees.truncate(raw.count_lessees as usize);
+    Ok(lessees)
+}
+
+pub fn revoke_lease<D: AsRawFd>(device: &D, lessee_id: u32) -> Result<()> {
+    let mut raw = RevokeLease { lessee_id };
+    if unsafe { libc::ioctl(device.as_raw_fd(), REVOKE_LEASE, &mut raw) } < 0 {
+        return Err(Error::ioctl("revoke lease", lessee_id, io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use libc;
+
+    use device::Gpu;
+    use error::Error;
+    use fake::FakeDevice;
+    use super::{CREATE_LEASE, LIST_LESSEES, REVOKE_LEASE};
+
+    // libdrm's DRM_IOCTL_MODE_*_LEASE, the size bits catch a struct laid out wrong
+    #[test]
+    fn ioctl_numbers_match_the_kernel() {
+        assert_eq!(CREATE_LEASE, 0xc018_64c6);
+        assert_eq!(LIST_LESSEES, 0xc010_64c7);
+        assert_eq!(REVOKE_LEASE, 0xc004_64c9);
+    }
+
+    #[test]
+    fn lease_errors_name_the_operation() {
+        let gpu = Gpu::new(Rc::new(FakeDevice::new()), None).unwrap();
+
+        match gpu.leases() {
+            Err(Error::Drm { operation, errno, .. }) => assert_eq!((operation, errno), ("list lessees", libc::EBADF)),
+            _ => panic!("listed lessees of a device without an fd"),
+        }
+        match gpu.revoke_lease(7) {
+            Err(Error::Drm { operation, object, .. }) => assert_eq!((operation, object), ("revoke lease", 7)),
+            _ => panic!("revoked a lease of a device without an fd"),
+        }
+    }
+}
@@ -11,35 +11,200 @@ extern crate input;
 extern crate dbus;
 extern crate libc;
 
-use drm::control::ResourceInfo;
-
 use input::Event;
 use input::event::KeyboardEvent;
 
+use std::os::unix::io::AsRawFd;
+
+use display::Scanout;
+use output::OutputSurface;
+
+mod atomic;
+mod bo;
+mod caps;
+mod clock;
+mod color;
+mod connector;
+mod cursor;
 mod device;
 mod display;
+mod dumb;
+mod edid;
+mod error;
+#[cfg(test)]
+mod fake;
 mod framebuffer;
+mod headless;
+mod hotplug;
+mod info;
+mod kms;
+mod lease;
+mod mode;
+mod modeline;
+mod output;
+mod plane;
+mod prime;
+mod property;
+mod render;
+mod session;
 //mod input_interface;
 //mod input_manager;
 
 //use self::input_interface::InputInterface;
 
 fn main() {
-    let gpu = device::open("/dev/dri/card0");
-    let displays = gpu.displays();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let subcommand = match args.first().map(String::as_str) {
+        Some("info") => Some(info::run(&args[1..])),
+        Some("render") => Some(headless::run(&args[1..])),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let option = |name: &str| args.iter().position(|arg| arg == name).and_then(|n| args.get(n + 1));
+
+    let gpus = device::enumerate("seat0").expect("[udev] failed to enumerate gpus");
+    let primary_gpu = device::primary_gpu(&gpus).expect("No gpus are available on seat0");
+    let pci_id = primary_gpu.pci_id.map(|id| id.to_string()).unwrap_or_else(|| "not on pci".to_owned());
+    println!("Using {} ({}, {}) at {}", primary_gpu.devnode.display(), primary_gpu.driver.as_deref().unwrap_or("unknown driver"), pci_id, primary_gpu.syspath.display());
+
+    let mut gpu = device::open(&primary_gpu.devnode).expect("[gpu] failed to open primary gpu");
+    gpu.select_backend(device::ModesetBackend::Atomic);
+
+    let mut outputs = output::OutputManager::new(gbm::Format::XRGB8888);
+    // render somewhere else, the frames come over PRIME
+    if let Some(node) = option("--render-node") {
+        match render::RenderDevice::open(node) {
+            Ok(render) => outputs.set_render_device(std::rc::Rc::new(render)),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    // --mode WxH[@Hz] picks among the connector's modes, --modeline forces one
+    if let Some(spec) = option("--mode") {
+        match modeline::parse_resolution(spec) {
+            Some((width, height, refresh)) => outputs.set_mode_request(output::ModeRequest::Closest {
+                width, height, refresh_mhz: refresh.map(|hz| (hz * 1000.0).round() as u32),
+            }),
+            None => eprintln!("[mode] can't parse {:?}, expected WxH[@Hz]", spec),
+        }
+    }
+    if let Some(spec) = option("--modeline") {
+        match modeline::from_spec(spec) {
+            Ok(mode) => outputs.set_mode_request(output::ModeRequest::Exact(mode)),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    for err in outputs.enable_all(&gpu) {
+        eprintln!("{}", err);
+    }
 
-    let display = displays.first().expect("No displays are attached");
-    let crtc = gpu.crtcs.first().expect("No crtc is available").to_owned();
-    let previous_crtc = display.current_crtc(&gpu);
+    if outputs.outputs().is_empty() {
+        panic!("No displays could be lit up");
+    }
+
+    if args.iter().any(|arg| arg == "--vrr") {
+        let connectors = outputs.outputs().iter().map(|o| o.display.connector).collect::<Vec<_>>();
+        for connector in connectors {
+            if let Err(err) = outputs.set_vrr(&gpu, connector, true) {
+                eprintln!("{}", err);
+            }
+        }
+    }
+
+    // --connector-property "Broadcast RGB=Full", numbers are set as they are,
+    // anything else by enum entry or by bitmask bit names joined with '|'
+    if let Some((name, value)) = option("--connector-property").and_then(|spec| spec.split_once('=')) {
+        for connector in outputs.outputs().iter().map(|o| o.display.connector) {
+            let result = match value.parse::<u64>() {
+                Ok(raw) => gpu.set_property(connector, property::ObjectType::Connector, name, raw),
+                Err(_) => gpu.set_property_by_name(connector, property::ObjectType::Connector, name, &value.split('|').collect::<Vec<_>>()),
+            };
+            if let Err(err) = result {
+                eprintln!("{}", err);
+            }
+        }
+    }
+
+    // --night-light KELVIN[:BRIGHTNESS]
+    let night_light = option("--night-light").map(|value| {
+        let mut parts = value.splitn(2, ':');
+        let temperature = parts.next().and_then(|t| t.parse().ok()).unwrap_or(color::NEUTRAL_TEMPERATURE);
+        let brightness = parts.next().and_then(|b| b.parse().ok()).unwrap_or(1.0);
+        (temperature, brightness)
+    });
+    if let Some((temperature, brightness)) = night_light {
+        for connector in outputs.outputs().iter().map(|o| o.display.connector) {
+            if let Err(err) = outputs.set_color_temperature(&gpu, connector, temperature, brightness, 1.0) {
+                eprintln!("{}", err);
+            }
+        }
+    }
+
+    for output in outputs.outputs() {
+        let (width, height) = output.surface.mode().size();
+        let refresh = mode::refresh_mhz(&output.surface.mode());
+        let vrr = if output.surface.clock().vrr_enabled() { " with VRR" } else { "" };
+        let prime = match output.surface {
+            OutputSurface::Prime(ref surface, _) if surface.is_direct() => ", rendered on another gpu",
+            OutputSurface::Prime(..) => ", rendered on another gpu and copied over",
+            _ => "",
+        };
+        println!("Lit up {} ({}) at {}x{}@{}.{:03}Hz{}{}", output.display.name, output.display.identifier, width, height, refresh / 1000, refresh % 1000, vrr, prime);
+    }
 
-    let native_mode = display.modes.first().expect("display doesn't support any modes").to_owned();
-    let (_display_w, _display_h) = native_mode.size();
+    // non-desktop displays (VR headsets and the like) go to whoever asked for them
+    let lessee = option("--lease-to");
+    let mut leases: Vec<lease::Lease> = Vec::new();
+    for display in gpu.non_desktop_displays() {
+        let command = match lessee {
+            Some(command) => command,
+            None => {
+                println!("Left {} ({}) dark, non-desktop displays are only available through leases", display.name, display.identifier);
+                continue;
+            }
+        };
 
-    let mut surface = gpu.initialize_display(display, crtc.handle(), gbm::Format::XRGB8888, native_mode);
-    surface.make_current();
+        let mut taken = outputs.used_crtcs();
+        taken.extend(gpu.crtcs.iter().map(|c| c.handle).filter(|&c| leases.iter().any(|l| l.objects.contains(&u32::from(c)))));
+        let lease = match gpu.lease_display(&display, &taken) {
+            Ok(lease) => lease,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
 
-    surface.swap_buffers(&gpu);
-    gpu.modeset(crtc, &[display], &surface);
+        match spawn_lessee(command, &lease) {
+            Ok(_) => println!("Leased {} ({}) to {} as lessee {}", display.name, display.identifier, command, lease.lessee_id),
+            Err(err) => eprintln!("[drm] failed to start {}: {}", command, err),
+        }
+        leases.push(lease);
+    }
+
+    for err in outputs.set_cursor(&gpu, cursor::CursorImage::arrow()) {
+        eprintln!("{}", err);
+    }
+
+    let mut hotplug = hotplug::HotplugMonitor::new().expect("[udev] failed to monitor drm hotplug");
+
+    // under a display manager or over ssh the VT isn't ours to switch
+    let session = match session::Session::open() {
+        Ok(session) => Some(session),
+        Err(err) => {
+            eprintln!("[session] VT switching is disabled: {}", err);
+            None
+        }
+    };
+    let mut wake = vec![hotplug.as_raw_fd()];
+    if let Some(ref session) = session {
+        wake.push(session.as_raw_fd());
+    }
 
     // start input system
 //    let udev_ctx = udev::Context::new().expect("[udev] failed to create context");
@@ -84,23 +249,162 @@ fn main() {
 //            }
 //        }
 
-        unsafe {
-            gl::ClearColor(1.0 - ((i % 255) as f32 / 255.0), 1.0, 1.0, 1.0);
-            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        // sweep the cursor across the desktop, one row every frame
+        let desktop_width = outputs.outputs().iter().map(|o| i64::from(o.surface.mode().size().0)).sum::<i64>().max(1);
+        for err in outputs.move_cursor(&gpu, (i * 8 % desktop_width) as i32, (i % 256) as i32 * 2) {
+            eprintln!("{}", err);
+        }
+
+        // another session owns the screens, wait for the master to come back
+        let idle = outputs.outputs_mut().iter_mut().filter(|o| o.is_idle() && gpu.ensure_master().is_ok());
+        for output in idle {
+            match output.surface {
+                OutputSurface::Egl(ref surface) => {
+                    if let Err(err) = surface.make_current() {
+                        eprintln!("{}", err);
+                        continue;
+                    }
+
+                    // EGL flips window surfaces, GL's bottom row is the last scanline
+                    unsafe { draw_frame(i, surface.cursor(), Some(i32::from(surface.mode.size().1))) };
+                }
+                OutputSurface::Prime(ref surface, ref render) => {
+                    if let Err(err) = render.make_current() {
+                        eprintln!("{}", err);
+                        continue;
+                    }
+
+                    surface.back().bind();
+                    unsafe { draw_frame(i, surface.cursor(), None) };
+                }
+                OutputSurface::Dumb(ref mut surface) => {
+                    let red = 255 - (i % 255) as u32;
+                    surface.back_mut().fill(red << 16 | 0x00_ffff);
+                    surface.draw_cursor();
+                }
+            }
+
+            if let Err(err) = output.surface.queue_present(&gpu) {
+                eprintln!("{}", err);
+                break 'mainloop;
+            }
         }
 
 //        if let Err(err) = input_ctx.dispatch() {
 //            println!("[libinput] failed to dispatch: {}", err);
 //        }
-        surface.present(&gpu);
+        let finished = match outputs.dispatch(&gpu, &wake, 1000) {
+            Ok(finished) => finished,
+            Err(err) => {
+                eprintln!("{}", err);
+                break 'mainloop;
+            }
+        };
+
+        for output in outputs.outputs().iter().filter(|o| finished.contains(&o.display.connector)) {
+            let clock = output.surface.clock();
+            if let (true, Some(interval)) = (clock.missed_vblank(), clock.frame_interval()) {
+                eprintln!("[drm] {} missed a vblank, {:?} between its last two frames instead of {:?}", output.display.name, interval, clock.refresh_interval());
+            }
+        }
+
+        if let Some(ref session) = session {
+            match session.dispatch() {
+                Ok(events) => for event in events {
+                    // flips in flight finish and the crtcs are saved on the way out, put back on return
+                    let result = match event {
+                        session::SessionEvent::Pause => outputs.finish_flips(&gpu).and_then(|_| gpu.release_master()),
+                        session::SessionEvent::Resume => gpu.acquire_master(),
+                    };
+                    if let Err(err) = result {
+                        eprintln!("{}", err);
+                    }
+                    if let Err(err) = session.acknowledge(event) {
+                        eprintln!("{}", err);
+                    }
+                },
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+
+        match hotplug.dispatch(&mut [&mut gpu]) {
+            Ok(events) => for (_, event) in events {
+                if let Err(err) = outputs.handle_event(&gpu, event) {
+                    eprintln!("{}", err);
+                }
+            },
+            Err(err) => eprintln!("{}", err),
+        }
         i += 1;
     }
 
+    // the lessees lose their displays with us
+    for lease in leases {
+        if let Err(err) = gpu.revoke_lease(lease.lessee_id) {
+            eprintln!("{}", err);
+        }
+    }
+
+    if night_light.is_some() {
+        for connector in outputs.outputs().iter().map(|o| o.display.connector) {
+            if let Err(err) = outputs.reset_color(&gpu, connector) {
+                eprintln!("{}", err);
+            }
+        }
+    }
 
-    // TODO: handle surface and framebuffer destroy
-    //    drm::control::framebuffer::destroy(gpu.deref(), front_fb.handle()).unwrap();
+    // outputs go before the gpu, restoring the crtcs on their way out
+    drop(outputs);
+}
+
+// The lessee finds its fd in DRM_LEASE_FD
+fn spawn_lessee(command: &str, lease: &lease::Lease) -> std::io::Result<std::process::Child> {
+    // dup'd fds aren't close-on-exec, the child inherits this one
+    let fd = unsafe { libc::dup(lease.as_raw_fd()) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
 
-    if let Some(crtc_info) = previous_crtc {
-        gpu.modeset_by_crtc(display.connector, crtc_info);
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("DRM_LEASE_FD", fd.to_string())
+        .spawn();
+    unsafe { libc::close(fd) };
+    child
+}
+
+// Fades from white to cyan and back, the cursor goes on top when the
+// hardware has none. Without a height GL's rows are the scanlines as they are.
+unsafe fn draw_frame(frame: i64, cursor: Option<&cursor::Cursor>, flipped_height: Option<i32>) {
+    gl::ClearColor(1.0 - ((frame % 255) as f32 / 255.0), 1.0, 1.0, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+    if let Some((image, position)) = cursor.and_then(|c| c.composited()) {
+        draw_cursor(image, position, flipped_height);
+    }
+}
+
+// Software cursor for outputs without a hardware one. Every run of opaque
+// pixels in a row is a scissored clear.
+unsafe fn draw_cursor(image: &cursor::CursorImage, (x, y): (i32, i32), flipped_height: Option<i32>) {
+    gl::Enable(gl::SCISSOR_TEST);
+    for row in 0..image.height {
+        let line = &image.pixels[(row * image.width) as usize..((row + 1) * image.width) as usize];
+        let mut start = 0;
+        while start < line.len() {
+            let pixel = line[start];
+            let end = line[start..].iter().position(|&p| p != pixel).map_or(line.len(), |n| start + n);
+
+            if pixel >> 24 >= 0x80 {
+                let channel = |shift: u32| ((pixel >> shift) & 0xff) as f32 / 255.0;
+                let scanline = y + row as i32;
+                gl::Scissor(x + start as i32, flipped_height.map_or(scanline, |height| height - scanline - 1), (end - start) as i32, 1);
+                gl::ClearColor(channel(16), channel(8), channel(0), 1.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+            }
+            start = end;
+        }
     }
+    gl::Disable(gl::SCISSOR_TEST);
 }
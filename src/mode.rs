@@ -0,0 +1,59 @@
+use std::mem;
+
+use drm::control::Mode;
+use drm::ffi::{self, drm_mode_modeinfo};
+
+// drm-rs keeps the kernel struct as the only (private) field of Mode, so both
+// share the same layout. transmute refuses to compile if that ever changes.
+pub fn as_raw(mode: &Mode) -> drm_mode_modeinfo {
+    unsafe { mem::transmute(*mode) }
+}
+
+pub fn from_raw(raw: drm_mode_modeinfo) -> Mode {
+    unsafe { mem::transmute(raw) }
+}
+
+// Same timings and flags, the name and type bits don't matter to the hardware
+pub fn same_timings(a: &Mode, b: &Mode) -> bool {
+    let (a, b) = (as_raw(a), as_raw(b));
+    (a.clock, a.hdisplay, a.hsync_start, a.hsync_end, a.htotal, a.hskew, a.flags)
+        == (b.clock, b.hdisplay, b.hsync_start, b.hsync_end, b.htotal, b.hskew, b.flags)
+        && (a.vdisplay, a.vsync_start, a.vsync_end, a.vtotal, a.vscan)
+            == (b.vdisplay, b.vsync_start, b.vsync_end, b.vtotal, b.vscan)
+}
+
+pub fn same_modes(a: &[Mode], b: &[Mode]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_timings(a, b))
+}
+
+pub fn is_preferred(mode: &Mode) -> bool {
+    as_raw(mode).type_ & ffi::DRM_MODE_TYPE_PREFERRED != 0
+}
+
+pub fn is_interlaced(mode: &Mode) -> bool {
+    as_raw(mode).flags & ffi::DRM_MODE_FLAG_INTERLACE != 0
+}
+
+pub fn is_doublescan(mode: &Mode) -> bool {
+    as_raw(mode).flags & ffi::DRM_MODE_FLAG_DBLSCAN != 0
+}
+
+// Vertical refresh in mHz worked out from the timings, vrefresh is rounded to
+// whole Hz and 59.94 and 60 Hz modes look the same through it
+pub fn refresh_mhz(mode: &Mode) -> u32 {
+    let raw = as_raw(mode);
+    let mut refresh = u64::from(raw.clock) * 1_000_000;
+    let mut total = u64::from(raw.htotal) * u64::from(raw.vtotal);
+
+    if raw.flags & ffi::DRM_MODE_FLAG_INTERLACE != 0 {
+        refresh *= 2;
+    }
+    if raw.flags & ffi::DRM_MODE_FLAG_DBLSCAN != 0 {
+        total *= 2;
+    }
+    if raw.vscan > 1 {
+        total *= u64::from(raw.vscan);
+    }
+
+    (refresh + total / 2).checked_div(total).unwrap_or(0) as u32
+}
@@ -0,0 +1,379 @@
+use drm::control::Mode;
+use drm::ffi::{self, drm_mode_modeinfo};
+
+use error::{Error, Result};
+use mode;
+
+const H_GRANULARITY: u32 = 8;
+const MIN_V_PORCH: u32 = 3;
+const MIN_V_BPORCH: u32 = 6;
+const MIN_VSYNC_BP: f64 = 550.0;
+const HSYNC_PERCENTAGE: f64 = 8.0;
+// blanking formula gradient and offset, scaled by the weighting factor as
+// CVT and GTF both do (M = 600, C = 40, K = 128, J = 20)
+const M_PRIME: f64 = 600.0 * 128.0 / 256.0;
+const C_PRIME: f64 = (40.0 - 20.0) * 128.0 / 256.0 + 20.0;
+
+const RB_MIN_VBLANK: f64 = 460.0;
+const RB_H_SYNC: u32 = 32;
+const RB_H_BLANK: u32 = 160;
+const RB_V_FPORCH: u32 = 3;
+const RB2_H_BLANK: u32 = 80;
+const RB2_H_FPORCH: u32 = 8;
+const RB2_VSYNC: u32 = 8;
+
+struct Timings {
+    clock: u32,
+    h: [u32; 4],
+    v: [u32; 4],
+    flags: u32,
+}
+
+// VESA CVT 1.2 with the regular blanking, what cvt(1) prints by default
+pub fn cvt(width: u32, height: u32, refresh: f64) -> Result<Mode> {
+    let invalid = || Error::InvalidMode { width, height, refresh };
+    let hdisplay = width - width % H_GRANULARITY;
+    let vsync = cvt_vsync(hdisplay, height);
+
+    let hperiod = (1_000_000.0 / refresh - MIN_VSYNC_BP) / f64::from(height + MIN_V_PORCH);
+    if hdisplay == 0 || height == 0 || hperiod.is_nan() || hperiod <= 0.0 {
+        return Err(invalid());
+    }
+    let vsync_bp = ((MIN_VSYNC_BP / hperiod) as u32 + 1).max(vsync + MIN_V_BPORCH);
+    let vtotal = height + vsync_bp + MIN_V_PORCH;
+
+    let blank_percentage = (C_PRIME - M_PRIME * hperiod / 1000.0).max(20.0);
+    let mut hblank = (f64::from(hdisplay) * blank_percentage / (100.0 - blank_percentage)) as u32;
+    hblank -= hblank % (2 * H_GRANULARITY);
+    let htotal = hdisplay + hblank;
+
+    let mut hsync = (f64::from(htotal) * HSYNC_PERCENTAGE / 100.0) as u32;
+    hsync -= hsync % H_GRANULARITY;
+    let hsync_end = hdisplay + hblank / 2;
+
+    build(width, height, Timings {
+        clock: cvt_clock(htotal, hperiod, 250),
+        h: [hdisplay, hsync_end - hsync, hsync_end, htotal],
+        v: [height, height + MIN_V_PORCH, height + MIN_V_PORCH + vsync, vtotal],
+        flags: ffi::DRM_MODE_FLAG_NHSYNC | ffi::DRM_MODE_FLAG_PVSYNC,
+    }).ok_or_else(invalid)
+}
+
+// CVT reduced blanking v1, for digital sinks that don't need the CRT retrace time
+pub fn cvt_reduced(width: u32, height: u32, refresh: f64) -> Result<Mode> {
+    let invalid = || Error::InvalidMode { width, height, refresh };
+    let hdisplay = width - width % H_GRANULARITY;
+    let vsync = cvt_vsync(hdisplay, height);
+
+    let hperiod = (1_000_000.0 / refresh - RB_MIN_VBLANK) / f64::from(height);
+    if hdisplay == 0 || height == 0 || hperiod.is_nan() || hperiod <= 0.0 {
+        return Err(invalid());
+    }
+    let vblank = ((RB_MIN_VBLANK / hperiod) as u32 + 1).max(RB_V_FPORCH + vsync + MIN_V_BPORCH);
+    let htotal = hdisplay + RB_H_BLANK;
+    let hsync_end = hdisplay + RB_H_BLANK / 2;
+
+    build(width, height, Timings {
+        clock: cvt_clock(htotal, hperiod, 250),
+        h: [hdisplay, hsync_end - RB_H_SYNC, hsync_end, htotal],
+        v: [height, height + RB_V_FPORCH, height + RB_V_FPORCH + vsync, height + vblank],
+        flags: ffi::DRM_MODE_FLAG_PHSYNC | ffi::DRM_MODE_FLAG_NVSYNC,
+    }).ok_or_else(invalid)
+}
+
+// CVT reduced blanking v2: fixed 80 pixel blank, 1 kHz clock steps and no
+// pixel granularity, so odd panel widths come out exact
+pub fn cvt_reduced_v2(width: u32, height: u32, refresh: f64) -> Result<Mode> {
+    let invalid = || Error::InvalidMode { width, height, refresh };
+    let hperiod = (1_000_000.0 / refresh - RB_MIN_VBLANK) / f64::from(height);
+    if width == 0 || height == 0 || hperiod.is_nan() || hperiod <= 0.0 {
+        return Err(invalid());
+    }
+    let vblank = ((RB_MIN_VBLANK / hperiod) as u32 + 1).max(RB2_VSYNC + MIN_V_BPORCH);
+    let htotal = width + RB2_H_BLANK;
+    let vtotal = height + vblank;
+    let clock = (refresh * f64::from(vtotal) * f64::from(htotal) / 1000.0) as u32;
+
+    build(width, height, Timings {
+        clock,
+        h: [width, width + RB2_H_FPORCH, width + RB2_H_FPORCH + RB_H_SYNC, htotal],
+        v: [height, vtotal - RB2_VSYNC - MIN_V_BPORCH, vtotal - MIN_V_BPORCH, vtotal],
+        flags: ffi::DRM_MODE_FLAG_PHSYNC | ffi::DRM_MODE_FLAG_NVSYNC,
+    }).ok_or_else(invalid)
+}
+
+// VESA GTF with the default blanking formula, what gtf(1) prints
+pub fn gtf(width: u32, height: u32, refresh: f64) -> Result<Mode> {
+    let invalid = || Error::InvalidMode { width, height, refresh };
+    let cell = f64::from(H_GRANULARITY);
+    let hdisplay = ((f64::from(width) / cell).round() * cell) as u32;
+
+    let hperiod_est = (1.0 / refresh - MIN_VSYNC_BP / 1_000_000.0) / f64::from(height + 1) * 1_000_000.0;
+    if hdisplay == 0 || height == 0 || hperiod_est.is_nan() || hperiod_est <= 0.0 {
+        return Err(invalid());
+    }
+    let vsync_bp = (MIN_VSYNC_BP / hperiod_est).round() as u32;
+    let vtotal = height + vsync_bp + 1;
+    let field_rate_est = 1.0 / hperiod_est / f64::from(vtotal) * 1_000_000.0;
+    let hperiod = hperiod_est / (refresh / field_rate_est);
+
+    let duty_cycle = C_PRIME - M_PRIME * hperiod / 1000.0;
+    let hblank = ((f64::from(hdisplay) * duty_cycle / (100.0 - duty_cycle) / (2.0 * cell)).round() * 2.0 * cell) as u32;
+    let htotal = hdisplay + hblank;
+    let hsync = ((HSYNC_PERCENTAGE / 100.0 * f64::from(htotal) / cell).round() * cell) as u32;
+    let hsync_start = hdisplay + hblank / 2 - hsync;
+
+    build(width, height, Timings {
+        clock: (f64::from(htotal) / hperiod * 1000.0) as u32,
+        h: [hdisplay, hsync_start, hsync_start + hsync, htotal],
+        v: [height, height + 1, height + 1 + MIN_V_PORCH, vtotal],
+        flags: ffi::DRM_MODE_FLAG_NHSYNC | ffi::DRM_MODE_FLAG_PVSYNC,
+    }).ok_or_else(invalid)
+}
+
+// Takes the X11 form, with or without the Modeline keyword and the name:
+// Modeline "1920x1080_60.00" 173.00 1920 2048 2248 2576 1080 1083 1088 1120 -hsync +vsync
+pub fn parse(modeline: &str) -> Result<Mode> {
+    let invalid = || Error::InvalidModeline(modeline.to_owned());
+
+    let mut rest = modeline.trim();
+    if rest.get(..8).is_some_and(|w| w.eq_ignore_ascii_case("modeline")) {
+        rest = rest[8..].trim_start();
+    }
+
+    let mut name = None;
+    if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted.find('"').ok_or_else(invalid)?;
+        name = Some(&quoted[..end]);
+        rest = &quoted[end + 1..];
+    }
+
+    let mut words = rest.split_whitespace();
+    let clock_mhz: f64 = words.next().and_then(|w| w.parse().ok()).ok_or_else(invalid)?;
+    // the kernel keeps the timings in 16 bits
+    let mut numbers = [0u32; 8];
+    for number in numbers.iter_mut() {
+        *number = words.next().and_then(|w| w.parse::<u16>().ok()).map(u32::from).ok_or_else(invalid)?;
+    }
+
+    let mut flags = 0;
+    for word in words {
+        flags |= match word.to_lowercase().as_str() {
+            "+hsync" => ffi::DRM_MODE_FLAG_PHSYNC,
+            "-hsync" => ffi::DRM_MODE_FLAG_NHSYNC,
+            "+vsync" => ffi::DRM_MODE_FLAG_PVSYNC,
+            "-vsync" => ffi::DRM_MODE_FLAG_NVSYNC,
+            "+csync" => ffi::DRM_MODE_FLAG_PCSYNC,
+            "-csync" => ffi::DRM_MODE_FLAG_NCSYNC,
+            "composite" | "csync" => ffi::DRM_MODE_FLAG_CSYNC,
+            "interlace" => ffi::DRM_MODE_FLAG_INTERLACE,
+            "doublescan" => ffi::DRM_MODE_FLAG_DBLSCAN,
+            _ => return Err(invalid()),
+        };
+    }
+
+    let (h, v) = ([numbers[0], numbers[1], numbers[2], numbers[3]], [numbers[4], numbers[5], numbers[6], numbers[7]]);
+    if !h.windows(2).all(|w| w[0] <= w[1]) || !v.windows(2).all(|w| w[0] <= w[1]) || h[0] == 0 || v[0] == 0 {
+        return Err(invalid());
+    }
+
+    let mut mode = build(h[0], v[0], Timings { clock: (clock_mhz * 1000.0).round() as u32, h, v, flags }).ok_or_else(invalid)?;
+    if let Some(name) = name {
+        let mut raw = mode::as_raw(&mode);
+        set_name(&mut raw, name);
+        mode = mode::from_raw(raw);
+    }
+
+    Ok(mode)
+}
+
+// What --modeline takes: an X11 modeline, or a resolution and the formula to
+// generate its timings with, e.g. cvt-rb:2560x1440@144
+pub fn from_spec(spec: &str) -> Result<Mode> {
+    let (formula, resolution) = match spec.split_once(':') {
+        Some(split) => split,
+        None => return parse(spec),
+    };
+    let invalid = || Error::InvalidModeline(spec.to_owned());
+
+    let generate: fn(u32, u32, f64) -> Result<Mode> = match formula {
+        "cvt" => cvt,
+        "cvt-rb" => cvt_reduced,
+        "cvt-rb2" => cvt_reduced_v2,
+        "gtf" => gtf,
+        _ => return Err(invalid()),
+    };
+    let (width, height, refresh) = parse_resolution(resolution).ok_or_else(invalid)?;
+    generate(width, height, refresh.unwrap_or(60.0))
+}
+
+// WxH with an optional @Hz
+pub fn parse_resolution(spec: &str) -> Option<(u32, u32, Option<f64>)> {
+    let (size, refresh) = match spec.split_once('@') {
+        Some((size, refresh)) => (size, Some(refresh.parse().ok()?)),
+        None => (spec, None),
+    };
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?, refresh))
+}
+
+fn cvt_vsync(width: u32, height: u32) -> u32 {
+    let matches = |w: u32, h: u32| height * w == width * h;
+
+    if matches(4, 3) {
+        4
+    } else if matches(16, 9) {
+        5
+    } else if matches(16, 10) {
+        6
+    } else if matches(5, 4) || matches(15, 9) {
+        7
+    } else {
+        10
+    }
+}
+
+// Pixel clock in kHz, rounded down to the clock step
+fn cvt_clock(htotal: u32, hperiod: f64, step: u32) -> u32 {
+    let clock = (f64::from(htotal) * 1000.0 / hperiod) as u32;
+    clock - clock % step
+}
+
+// None when a timing doesn't fit into the kernel's 16 bits
+fn build(width: u32, height: u32, timings: Timings) -> Option<Mode> {
+    if timings.h.iter().chain(timings.v.iter()).any(|&t| t > u32::from(u16::MAX)) {
+        return None;
+    }
+
+    let mut raw = drm_mode_modeinfo {
+        clock: timings.clock,
+        hdisplay: timings.h[0] as u16,
+        hsync_start: timings.h[1] as u16,
+        hsync_end: timings.h[2] as u16,
+        htotal: timings.h[3] as u16,
+        vdisplay: timings.v[0] as u16,
+        vsync_start: timings.v[1] as u16,
+        vsync_end: timings.v[2] as u16,
+        vtotal: timings.v[3] as u16,
+        flags: timings.flags,
+        type_: ffi::DRM_MODE_TYPE_USERDEF,
+        ..Default::default()
+    };
+
+    let interlace = if timings.flags & ffi::DRM_MODE_FLAG_INTERLACE != 0 { "i" } else { "" };
+    set_name(&mut raw, &format!("{}x{}{}", width, height, interlace));
+
+    let refresh = mode::refresh_mhz(&mode::from_raw(raw));
+    raw.vrefresh = (refresh + 500) / 1000;
+    Some(mode::from_raw(raw))
+}
+
+fn set_name(raw: &mut drm_mode_modeinfo, name: &str) {
+    raw.name = [0; 32];
+    for (dst, &src) in raw.name.iter_mut().zip(name.as_bytes().iter().take(31)) {
+        *dst = src as _;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use drm::control::Mode;
+    use drm::ffi;
+
+    use mode;
+    use super::{cvt, cvt_reduced, cvt_reduced_v2, from_spec, gtf, parse, parse_resolution};
+
+    fn timings(mode: &Mode) -> (u32, [u16; 4], [u16; 4], u32) {
+        let raw = mode::as_raw(mode);
+        (raw.clock, [raw.hdisplay, raw.hsync_start, raw.hsync_end, raw.htotal],
+         [raw.vdisplay, raw.vsync_start, raw.vsync_end, raw.vtotal], raw.flags)
+    }
+
+    const NH_PV: u32 = ffi::DRM_MODE_FLAG_NHSYNC | ffi::DRM_MODE_FLAG_PVSYNC;
+    const PH_NV: u32 = ffi::DRM_MODE_FLAG_PHSYNC | ffi::DRM_MODE_FLAG_NVSYNC;
+
+    // references below are what cvt(1) and gtf(1) print
+
+    #[test]
+    fn cvt_matches_reference() {
+        assert_eq!(timings(&cvt(1920, 1080, 60.0).unwrap()), (173_000, [1920, 2048, 2248, 2576], [1080, 1083, 1088, 1120], NH_PV));
+        assert_eq!(timings(&cvt(1024, 768, 60.0).unwrap()), (63_500, [1024, 1072, 1176, 1328], [768, 771, 775, 798], NH_PV));
+    }
+
+    #[test]
+    fn cvt_reduced_matches_reference() {
+        assert_eq!(timings(&cvt_reduced(1920, 1080, 60.0).unwrap()), (138_500, [1920, 1968, 2000, 2080], [1080, 1083, 1088, 1111], PH_NV));
+        assert_eq!(timings(&cvt_reduced(2560, 1440, 60.0).unwrap()), (241_500, [2560, 2608, 2640, 2720], [1440, 1443, 1448, 1481], PH_NV));
+    }
+
+    #[test]
+    fn cvt_reduced_v2_matches_reference() {
+        // front porch 17, sync 8, back porch 6
+        assert_eq!(timings(&cvt_reduced_v2(1920, 1080, 60.0).unwrap()), (133_320, [1920, 1928, 1960, 2000], [1080, 1097, 1105, 1111], PH_NV));
+    }
+
+    #[test]
+    fn gtf_matches_reference() {
+        // gtf(1) rounds the clock to 172.80 MHz when printing
+        assert_eq!(timings(&gtf(1920, 1080, 60.0).unwrap()), (172_798, [1920, 2040, 2248, 2576], [1080, 1081, 1084, 1118], NH_PV));
+    }
+
+    #[test]
+    fn parse_reads_x11_modelines() {
+        let mode = parse(r#"Modeline "1920x1080_60.00"  173.00  1920 2048 2248 2576  1080 1083 1088 1120 -hsync +vsync"#).unwrap();
+        assert!(mode::same_timings(&mode, &cvt(1920, 1080, 60.0).unwrap()));
+        assert_eq!(mode.name().to_str(), Ok("1920x1080_60.00"));
+        assert_eq!(mode::refresh_mhz(&mode), 59_963);
+
+        let mode = parse("138.50 1920 1968 2000 2080 1080 1083 1088 1111 +HSync -VSync").unwrap();
+        assert!(mode::same_timings(&mode, &cvt_reduced(1920, 1080, 60.0).unwrap()));
+    }
+
+    #[test]
+    fn parse_rejects_bad_modelines() {
+        for modeline in &[
+            "",
+            "173.00 1920 2048 2248 2576 1080 1083 1088",
+            "173.00 1920 2048 2248 2576 1080 1083 1088 1120 +wobble",
+            "173.00 1920 2048 2248 2000 1080 1083 1088 1120",
+            "173.00 1920 2048 2248 70000 1080 1083 1088 1120",
+            r#""unterminated 173.00 1920 2048 2248 2576 1080 1083 1088 1120"#,
+        ] {
+            assert!(parse(modeline).is_err(), "{}", modeline);
+        }
+    }
+
+    #[test]
+    fn generators_reject_timings_the_kernel_cant_hold() {
+        // htotal, vtotal and a vertical blank past 16 bits
+        assert!(cvt(65_000, 1080, 60.0).is_err());
+        assert!(cvt_reduced_v2(65_500, 1080, 60.0).is_err());
+        assert!(cvt_reduced(1920, 65_000, 60.0).is_err());
+        assert!(gtf(1920, 65_535, 60.0).is_err());
+
+        // no time left for the active lines
+        assert!(cvt(1920, 1080, 5000.0).is_err());
+        assert!(cvt_reduced(1920, 1080, 0.0).is_err());
+        assert!(gtf(0, 1080, 60.0).is_err());
+        assert!(cvt_reduced_v2(1920, 0, 60.0).is_err());
+
+        assert!(cvt_reduced_v2(7680, 4320, 60.0).is_ok());
+    }
+
+    #[test]
+    fn specs_pick_the_formula_or_parse_a_modeline() {
+        assert_eq!(timings(&from_spec("cvt-rb:1920x1080@60").unwrap()), timings(&cvt_reduced(1920, 1080, 60.0).unwrap()));
+        assert_eq!(timings(&from_spec("gtf:1024x768").unwrap()), timings(&gtf(1024, 768, 60.0).unwrap()));
+        assert_eq!(timings(&from_spec("173.00 1920 2048 2248 2576 1080 1083 1088 1120 -hsync +vsync").unwrap()).0, 173_000);
+        assert!(from_spec("vesa:1920x1080").is_err());
+        assert!(from_spec("cvt:1920").is_err());
+    }
+
+    #[test]
+    fn resolutions_take_an_optional_refresh() {
+        assert_eq!(parse_resolution("2560x1440@144"), Some((2560, 1440, Some(144.0))));
+        assert_eq!(parse_resolution("800x600"), Some((800, 600, None)));
+        assert_eq!(parse_resolution("800x600@"), None);
+        assert_eq!(parse_resolution("800"), None);
+    }
+}
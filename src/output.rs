@@ -0,0 +1,536 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use drm::control::{connector, crtc, Mode};
+use gbm;
+use libc;
+
+use clock::FrameClock;
+use color::{self, Ctm, Lut};
+use cursor::{Cursor, CursorImage};
+use device::{Gpu, ModesetGuard};
+use display::{Display, Scanout, Surface};
+use dumb::DumbSurface;
+use error::{Error, Result};
+use framebuffer::Framebuffer;
+use hotplug::DisplayEvent;
+use mode;
+use prime::PrimeSurface;
+use render::RenderDevice;
+
+// EGL when the gpu has a gbm device, dumb buffers drawn on the cpu otherwise.
+// With a render device every output renders there and gets its frames over PRIME.
+pub enum OutputSurface {
+    Egl(Surface),
+    Dumb(DumbSurface),
+    Prime(PrimeSurface, Rc<RenderDevice>),
+}
+
+impl OutputSurface {
+    // PRIME surfaces free their GL objects on the render device
+    pub fn destroy(self) {
+        if let OutputSurface::Prime(surface, render) = self {
+            surface.destroy(&render);
+        }
+    }
+
+    pub fn queue_present(&mut self, gpu: &Gpu) -> Result<()> {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.queue_present(gpu),
+            OutputSurface::Dumb(ref mut surface) => surface.queue_present(gpu),
+            OutputSurface::Prime(ref mut surface, ref render) => surface.queue_present(render, gpu),
+        }
+    }
+
+    pub fn is_flip_pending(&self) -> bool {
+        match *self {
+            OutputSurface::Egl(ref surface) => surface.is_flip_pending(),
+            OutputSurface::Dumb(ref surface) => surface.is_flip_pending(),
+            OutputSurface::Prime(ref surface, _) => surface.is_flip_pending(),
+        }
+    }
+
+    pub fn page_flip_complete(&mut self, timestamp: Duration) {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.page_flip_complete(timestamp),
+            OutputSurface::Dumb(ref mut surface) => surface.page_flip_complete(timestamp),
+            OutputSurface::Prime(ref mut surface, _) => surface.page_flip_complete(timestamp),
+        }
+    }
+
+    pub fn clock(&self) -> &FrameClock {
+        match *self {
+            OutputSurface::Egl(ref surface) => surface.clock(),
+            OutputSurface::Dumb(ref surface) => surface.clock(),
+            OutputSurface::Prime(ref surface, _) => surface.clock(),
+        }
+    }
+
+    pub fn set_vrr(&mut self, gpu: &Gpu, enabled: bool) -> Result<()> {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.set_vrr(gpu, enabled),
+            OutputSurface::Dumb(ref mut surface) => surface.set_vrr(gpu, enabled),
+            OutputSurface::Prime(ref mut surface, _) => surface.set_vrr(gpu, enabled),
+        }
+    }
+
+    pub fn set_cursor(&mut self, gpu: &Gpu, image: CursorImage) -> Result<()> {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.set_cursor(gpu, image),
+            OutputSurface::Dumb(ref mut surface) => surface.set_cursor(gpu, image),
+            OutputSurface::Prime(ref mut surface, _) => surface.set_cursor(gpu, image),
+        }
+    }
+
+    pub fn move_cursor(&mut self, gpu: &Gpu, x: i32, y: i32) -> Result<()> {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.move_cursor(gpu, x, y),
+            OutputSurface::Dumb(ref mut surface) => surface.move_cursor(gpu, x, y),
+            OutputSurface::Prime(ref mut surface, _) => surface.move_cursor(gpu, x, y),
+        }
+    }
+
+    pub fn hide_cursor(&mut self, gpu: &Gpu) -> Result<()> {
+        match *self {
+            OutputSurface::Egl(ref mut surface) => surface.hide_cursor(gpu),
+            OutputSurface::Dumb(ref mut surface) => surface.hide_cursor(gpu),
+            OutputSurface::Prime(ref mut surface, _) => surface.hide_cursor(gpu),
+        }
+    }
+}
+
+impl Scanout for OutputSurface {
+    fn mode(&self) -> Mode {
+        match *self {
+            OutputSurface::Egl(ref surface) => surface.mode,
+            OutputSurface::Dumb(ref surface) => surface.mode,
+            OutputSurface::Prime(ref surface, _) => surface.mode,
+        }
+    }
+
+    fn framebuffer(&self) -> Option<&Framebuffer> {
+        match *self {
+            OutputSurface::Egl(ref surface) => surface.framebuffer(),
+            OutputSurface::Dumb(ref surface) => surface.framebuffer(),
+            OutputSurface::Prime(ref surface, _) => surface.framebuffer(),
+        }
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        match *self {
+            OutputSurface::Egl(ref surface) => surface.cursor(),
+            OutputSurface::Dumb(ref surface) => surface.cursor(),
+            OutputSurface::Prime(ref surface, _) => surface.cursor(),
+        }
+    }
+}
+
+// The guard is dropped first, so the crtc is restored before the surface's
+// framebuffers go away
+pub struct Output {
+    pub display: Display,
+    pub crtc: crtc::Handle,
+    pub guard: ModesetGuard,
+    pub surface: OutputSurface,
+}
+
+impl Output {
+    pub fn destroy(self) {
+        drop(self.guard);
+        self.surface.destroy();
+    }
+
+    // Idle outputs can render their next frame, the others wait for their flip
+    pub fn is_idle(&self) -> bool {
+        !self.surface.is_flip_pending()
+    }
+}
+
+// Outputs sit side by side from left to right, in the order they were lit up
+// Mode every output gets instead of its preferred one
+#[derive(Debug, Clone, Copy)]
+pub enum ModeRequest {
+    // the connector's mode nearest to this, refresh in mHz
+    Closest { width: u32, height: u32, refresh_mhz: Option<u32> },
+    // forced timings for sinks whose EDID is missing or wrong
+    Exact(Mode),
+}
+
+pub struct OutputManager {
+    format: gbm::Format,
+    outputs: Vec<Output>,
+    cursor: Option<CursorImage>,
+    render: Option<Rc<RenderDevice>>,
+    mode: Option<ModeRequest>,
+}
+
+impl OutputManager {
+    pub fn new(format: gbm::Format) -> OutputManager {
+        OutputManager { format, outputs: Vec::new(), cursor: None, render: None, mode: None }
+    }
+
+    // Outputs lit up from now on render on another gpu, e.g. the iGPU for
+    // ports wired to the dGPU
+    pub fn set_render_device(&mut self, render: Rc<RenderDevice>) {
+        self.render = Some(render);
+    }
+
+    // Applies to outputs lit up from now on, hotplugged ones included
+    pub fn set_mode_request(&mut self, request: ModeRequest) {
+        self.mode = Some(request);
+    }
+
+    // Lights up every connected display, each one on a crtc of its own
+    pub fn enable_all(&mut self, gpu: &Gpu) -> Vec<Error> {
+        let displays = gpu.displays();
+        let connectors = displays.iter().map(|d| d.connector).collect::<Vec<_>>();
+        let used = self.used_crtcs();
+
+        let crtcs = match gpu.assign_crtcs(&connectors, &used) {
+            Some(crtcs) => crtcs.into_iter().map(Some).collect(),
+            // not enough crtcs for everyone, light up as many as we can
+            None => {
+                let mut taken = used.clone();
+                connectors.iter().map(|&c| {
+                    let crtc = gpu.find_crtc(c, &taken);
+                    taken.extend(crtc);
+                    crtc
+                }).collect::<Vec<_>>()
+            }
+        };
+
+        let mut errors = Vec::new();
+        for (display, crtc) in displays.into_iter().zip(crtcs) {
+            let result = match crtc {
+                Some(crtc) => self.add_output(gpu, display, crtc),
+                None => Err(Error::NoCrtc { connector: display.connector.into() }),
+            };
+
+            if let Err(err) = result {
+                errors.push(err);
+            }
+        }
+
+        errors
+    }
+
+    pub fn add_output(&mut self, gpu: &Gpu, display: Display, crtc: crtc::Handle) -> Result<()> {
+        let mode = match self.mode {
+            Some(ModeRequest::Closest { width, height, refresh_mhz }) => display.closest_mode(width, height, refresh_mhz),
+            Some(ModeRequest::Exact(mode)) => Some(mode),
+            None => display.preferred_mode(),
+        };
+        let mode = mode.ok_or(Error::NoModes { connector: display.connector.into() })?;
+        self.add_output_with_mode(gpu, display, crtc, mode)
+    }
+
+    // The mode doesn't have to be one the connector reports, e.g. a generated
+    // or parsed modeline for sinks with a broken EDID
+    pub fn add_output_with_mode(&mut self, gpu: &Gpu, display: Display, crtc: crtc::Handle, mode: Mode) -> Result<()> {
+        let crtc_info = gpu.get_crtc(crtc).ok_or(Error::NoCrtc { connector: display.connector.into() })?;
+
+        let surface = match (self.render.clone(), gpu.gbm_device()) {
+            (Some(render), _) => OutputSurface::Prime(PrimeSurface::new(&render, gpu, crtc, mode)?, render),
+            (None, Ok(_)) => {
+                let mut surface = gpu.initialize_display(&display, crtc, self.format, mode)?;
+                surface.make_current()?;
+                surface.swap_buffers(gpu)?;
+                OutputSurface::Egl(surface)
+            }
+            // no Mesa driver for the display controller, draw on the cpu
+            (None, Err(_)) => OutputSurface::Dumb(DumbSurface::new(gpu, crtc, mode)?),
+        };
+        let guard = gpu.modeset(crtc_info, &[&display], &surface)?;
+
+        self.outputs.push(Output { display, crtc, guard, surface });
+        Ok(())
+    }
+
+    pub fn remove_output(&mut self, connector: connector::Handle) -> Option<Output> {
+        let index = self.outputs.iter().position(|o| o.display.connector == connector)?;
+        Some(self.outputs.remove(index))
+    }
+
+    // Turns the crtc off instead of restoring it, the connector may be gone.
+    // The surface's framebuffers go away afterwards.
+    pub fn teardown(&mut self, gpu: &Gpu, connector: connector::Handle) -> Result<()> {
+        let output = match self.remove_output(connector) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+
+        output.guard.dismiss();
+        let result = gpu.disable_crtc(output.crtc);
+        output.surface.destroy();
+        result
+    }
+
+    pub fn handle_event(&mut self, gpu: &Gpu, event: DisplayEvent) -> Result<()> {
+        match event {
+            DisplayEvent::DisplayAdded(display) => {
+                let crtc = gpu.find_crtc(display.connector, &self.used_crtcs())
+                    .ok_or(Error::NoCrtc { connector: display.connector.into() })?;
+                self.add_output(gpu, display, crtc)
+            }
+            DisplayEvent::DisplayRemoved(connector) => self.teardown(gpu, connector),
+            DisplayEvent::ModesChanged(display) => {
+                let (crtc, keep_mode, previous) = match self.outputs.iter().find(|o| o.display.connector == display.connector) {
+                    Some(output) => (output.crtc, display.modes.iter().any(|m| mode::same_timings(m, &output.surface.mode())), output.guard.previous()),
+                    None => return Ok(()),
+                };
+
+                if keep_mode {
+                    if let Some(output) = self.outputs.iter_mut().find(|o| o.display.connector == display.connector) {
+                        output.display = display;
+                    }
+                    return Ok(());
+                }
+
+                self.teardown(gpu, display.connector)?;
+                self.add_output(gpu, display, crtc)?;
+
+                // the crtc shows our old framebuffer by now, restore what was there before it
+                if let Some(output) = self.outputs.last_mut() {
+                    output.guard.set_previous(previous);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn set_vrr(&mut self, gpu: &Gpu, connector: connector::Handle, enabled: bool) -> Result<()> {
+        let output = self.outputs.iter_mut().find(|o| o.display.connector == connector)
+            .ok_or(Error::NoCrtc { connector: connector.into() })?;
+
+        if enabled && !output.display.vrr_capable {
+            return Err(Error::VrrUnsupported { connector: connector.into() });
+        }
+        output.surface.set_vrr(gpu, enabled)
+    }
+
+    // Night light. The white point goes in the CTM where the crtc has one,
+    // brightness and gamma in the LUT. Without a CTM the LUT takes all three.
+    pub fn set_color_temperature(&self, gpu: &Gpu, connector: connector::Handle, temperature: u32, brightness: f64, gamma: f64) -> Result<()> {
+        let crtc = self.crtc_of(connector)?;
+        let size = gpu.gamma_size(crtc)?;
+
+        // a degamma curve left behind by the last master would skew the CTM
+        match gpu.set_degamma(crtc, None) {
+            Ok(()) | Err(Error::MissingProperty { .. }) => {}
+            Err(err) => return Err(err),
+        }
+
+        let (r, g, b) = color::temperature_to_rgb(temperature);
+        match gpu.set_ctm(crtc, Some(&Ctm::scale(r, g, b))) {
+            Ok(()) => gpu.set_gamma(crtc, &Lut::from_curve(size, color::NEUTRAL_TEMPERATURE, brightness, gamma)),
+            Err(Error::MissingProperty { .. }) => gpu.set_gamma(crtc, &Lut::from_curve(size, temperature, brightness, gamma)),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The crtc keeps its LUT after we exit, the console would come back tinted
+    pub fn reset_color(&self, gpu: &Gpu, connector: connector::Handle) -> Result<()> {
+        let crtc = self.crtc_of(connector)?;
+        match gpu.set_ctm(crtc, None) {
+            Ok(()) | Err(Error::MissingProperty { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        gpu.set_gamma(crtc, &Lut::identity(gpu.gamma_size(crtc)?))
+    }
+
+    fn crtc_of(&self, connector: connector::Handle) -> Result<crtc::Handle> {
+        self.outputs.iter()
+            .find(|o| o.display.connector == connector)
+            .map(|o| o.crtc)
+            .ok_or(Error::NoCrtc { connector: connector.into() })
+    }
+
+    // Outputs pick the image up once the cursor is moved onto them
+    pub fn set_cursor(&mut self, gpu: &Gpu, image: CursorImage) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for output in &mut self.outputs {
+            if !output.surface.cursor().is_some_and(Cursor::is_visible) {
+                continue;
+            }
+            if let Err(err) = output.surface.set_cursor(gpu, image.clone()) {
+                errors.push(err);
+            }
+        }
+
+        self.cursor = Some(image);
+        errors
+    }
+
+    // Takes a position on the whole desktop, the output under it shows the
+    // cursor and the others hide theirs
+    pub fn move_cursor(&mut self, gpu: &Gpu, x: i32, y: i32) -> Vec<Error> {
+        let image = match self.cursor {
+            Some(ref image) => image,
+            None => return Vec::new(),
+        };
+
+        let mut errors = Vec::new();
+        let mut left = 0;
+        for output in &mut self.outputs {
+            let (width, height) = output.surface.mode().size();
+            let inside = x >= left && x < left + i32::from(width) && y >= 0 && y < i32::from(height);
+            let visible = output.surface.cursor().is_some_and(Cursor::is_visible);
+
+            let result = match (inside, visible) {
+                (true, true) => output.surface.move_cursor(gpu, x - left, y),
+                (true, false) => output.surface.move_cursor(gpu, x - left, y)
+                    .and_then(|_| output.surface.set_cursor(gpu, image.clone())),
+                (false, true) => output.surface.hide_cursor(gpu),
+                (false, false) => Ok(()),
+            };
+
+            if let Err(err) = result {
+                errors.push(err);
+            }
+            left += i32::from(width);
+        }
+
+        errors
+    }
+
+    // Waits for every flip in flight, e.g. before the screens go to another session
+    pub fn finish_flips(&mut self, gpu: &Gpu) -> Result<()> {
+        for output in self.outputs.iter_mut().filter(|o| o.surface.is_flip_pending()) {
+            let timestamp = gpu.wait_for_flip(output.crtc)?;
+            output.surface.page_flip_complete(timestamp);
+        }
+
+        Ok(())
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    pub fn outputs_mut(&mut self) -> &mut [Output] {
+        &mut self.outputs
+    }
+
+    pub fn used_crtcs(&self) -> Vec<crtc::Handle> {
+        self.outputs.iter().map(|o| o.crtc).collect()
+    }
+
+    // Waits up to timeout_ms (-1 blocks) for flip events, or for any of the
+    // wake fds to become readable, and marks the matching outputs idle again.
+    // Returns the connectors that finished a frame.
+    pub fn dispatch(&mut self, gpu: &Gpu, wake: &[RawFd], timeout_ms: i32) -> Result<Vec<connector::Handle>> {
+        // flips a present() waited past are already in, the fd may never wake up for them
+        if !gpu.has_queued_events() {
+            let mut fds = Some(gpu.as_raw_fd()).into_iter()
+                .chain(wake.iter().cloned())
+                .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+                .collect::<Vec<_>>();
+
+            loop {
+                if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) } >= 0 {
+                    break;
+                }
+
+                // a signal came in before any event, go back to waiting
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(Error::ioctl("poll", 0u32, error));
+                }
+            }
+            // reading the device blocks until there's an event
+            if fds[0].revents & libc::POLLIN == 0 {
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut finished = Vec::new();
+        for event in gpu.receive_events()? {
+            if let crtc::Event::PageFlip(flip) = event {
+                if let Some(output) = self.outputs.iter_mut().find(|o| o.crtc == flip.crtc) {
+                    output.surface.page_flip_complete(flip.duration);
+                    finished.push(output.display.connector);
+                }
+            }
+        }
+
+        Ok(finished)
+    }
+}
+
+// Takes the outputs apart like dropping them would, but lets the PRIME
+// surfaces free their render targets
+impl Drop for OutputManager {
+    fn drop(&mut self) {
+        for output in self.outputs.drain(..) {
+            output.destroy();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use drm::control::connector;
+    use gbm;
+
+    use device::Gpu;
+    use display::Scanout;
+    use fake::FakeDevice;
+    use modeline;
+    use super::{ModeRequest, OutputManager, OutputSurface};
+
+    #[test]
+    fn outputs_fall_back_to_dumb_buffers_without_gbm() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        fake.add_connector(connector::Type::Virtual, vec![modeline::cvt(1024, 768, 60.0).unwrap()], 0b1);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.caps.dumb_buffer = true;
+
+        let mut outputs = OutputManager::new(gbm::Format::XRGB8888);
+        assert!(outputs.enable_all(&gpu).is_empty());
+        let output = &mut outputs.outputs_mut()[0];
+        match output.surface {
+            OutputSurface::Dumb(ref mut surface) => surface.back_mut().fill(0x00ff_0000),
+            _ => panic!("got an EGL surface without a gbm device"),
+        }
+        assert_eq!(fake.scanout(crtc).unwrap().fb, output.surface.framebuffer().unwrap().handle());
+
+        output.surface.queue_present(&gpu).unwrap();
+        outputs.finish_flips(&gpu).unwrap();
+        let output = &outputs.outputs()[0];
+        assert!(output.is_idle());
+        assert_eq!(fake.scanout(crtc).unwrap().fb, output.surface.framebuffer().unwrap().handle());
+
+        outputs.teardown(&gpu, output.display.connector).unwrap();
+        assert!(outputs.outputs().is_empty());
+    }
+
+    #[test]
+    fn mode_requests_override_the_preferred_mode() {
+        let fake = Rc::new(FakeDevice::new());
+        let crtc = fake.add_crtc();
+        let modes = vec![modeline::cvt(1024, 768, 60.0).unwrap(), modeline::cvt(800, 600, 60.0).unwrap(), modeline::cvt(800, 600, 75.0).unwrap()];
+        fake.add_connector(connector::Type::Virtual, modes.clone(), 0b1);
+
+        let mut gpu = Gpu::new(fake.clone(), None).unwrap();
+        gpu.caps.dumb_buffer = true;
+
+        let mut outputs = OutputManager::new(gbm::Format::XRGB8888);
+        outputs.set_mode_request(ModeRequest::Closest { width: 800, height: 600, refresh_mhz: Some(74_000) });
+        assert!(outputs.enable_all(&gpu).is_empty());
+        assert_eq!(outputs.outputs()[0].surface.mode(), modes[2]);
+        assert_eq!(fake.scanout(crtc).unwrap().mode, Some(modes[2]));
+        drop(outputs);
+
+        let forced = modeline::cvt_reduced(640, 480, 60.0).unwrap();
+        let mut outputs = OutputManager::new(gbm::Format::XRGB8888);
+        outputs.set_mode_request(ModeRequest::Exact(forced));
+        assert!(outputs.enable_all(&gpu).is_empty());
+        assert_eq!(fake.scanout(crtc).unwrap().mode, Some(forced));
+    }
+}
@@ -0,0 +1,226 @@
+use std::os::unix::io::AsRawFd;
+
+use drm::control::Device as DrmControlDevice;
+use drm::control::{crtc, plane};
+use drm::ffi;
+
+use error::{Error, Result};
+use property::{self, PropertyKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneType {
+    Overlay,
+    Primary,
+    Cursor,
+}
+
+impl PlaneType {
+    fn from_raw(value: u64) -> PlaneType {
+        match value {
+            1 => PlaneType::Primary,
+            2 => PlaneType::Cursor,
+            _ => PlaneType::Overlay,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatModifier {
+    pub format: u32,
+    pub modifier: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zpos {
+    pub min: u64,
+    pub max: u64,
+    pub current: u64,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Plane {
+    pub handle: plane::Handle,
+    pub plane_type: PlaneType,
+    pub possible_crtcs: u32,
+    pub crtc: Option<crtc::Handle>,
+    pub formats: Vec<u32>,
+    pub modifiers: Vec<FormatModifier>,
+    pub zpos: Option<Zpos>,
+}
+
+impl Plane {
+    pub fn can_drive(&self, crtc_index: usize) -> bool {
+        self.possible_crtcs & (1 << crtc_index) != 0
+    }
+
+    pub fn supports_format(&self, format: u32) -> bool {
+        self.formats.contains(&format)
+    }
+
+    // Without IN_FORMATS the driver only takes implicit modifiers
+    pub fn modifiers_for(&self, format: u32) -> Vec<u64> {
+        self.modifiers
+            .iter()
+            .filter(|m| m.format == format)
+            .map(|m| m.modifier)
+            .collect()
+    }
+}
+
+pub fn load_planes<D: DrmControlDevice>(device: &D) -> Result<Vec<Plane>> {
+    let handles = device.plane_handles()
+        .map_err(|err| Error::drm("get plane resources", 0u32, err))?;
+
+    handles.planes().iter().map(|&handle| load_plane(device, handle)).collect()
+}
+
+fn load_plane<D: AsRawFd>(device: &D, handle: plane::Handle) -> Result<Plane> {
+    let id: u32 = handle.into();
+
+    let mut raw = ffi::drm_mode_get_plane { plane_id: id, ..Default::default() };
+    unsafe { ffi::ioctl_mode_getplane(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get plane", id, err))?;
+
+    let mut formats = vec![0u32; raw.count_format_types as usize];
+    raw.format_type_ptr = formats.as_mut_ptr() as u64;
+    unsafe { ffi::ioctl_mode_getplane(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get plane", id, err))?;
+
+    let mut plane_type = PlaneType::Overlay;
+    let mut modifiers = Vec::new();
+    let mut zpos = None;
+
+    for (prop, value) in property::object_properties(device, id, ffi::DRM_MODE_OBJECT_PLANE)? {
+        match property::property_name(device, prop)?.as_str() {
+            "type" => plane_type = PlaneType::from_raw(value),
+            "IN_FORMATS" if value != 0 => {
+                let blob = property::get_blob(device, value as u32)?;
+                modifiers = parse_in_formats(&blob);
+            }
+            "zpos" => {
+                // drivers stacking planes in a fixed order make it immutable
+                let info = property::property_info(device, prop)?;
+                if let PropertyKind::Range { min, max } = info.kind {
+                    zpos = Some(Zpos { min, max, current: value, mutable: !info.immutable });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let crtc = if raw.crtc_id != 0 { Some(crtc::Handle::from(raw.crtc_id)) } else { None };
+
+    Ok(Plane {
+        handle,
+        plane_type,
+        possible_crtcs: raw.possible_crtcs,
+        crtc,
+        formats,
+        modifiers,
+        zpos,
+    })
+}
+
+// struct drm_format_modifier_blob followed by the format list and an array of
+// struct drm_format_modifier, each one carrying a 64 bit mask over the formats
+fn parse_in_formats(blob: &[u8]) -> Vec<FormatModifier> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        blob.get(offset..offset + 4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let read_u64 = |offset: usize| -> Option<u64> {
+        blob.get(offset..offset + 8).map(|b| u64::from_ne_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    };
+
+    let header = (read_u32(8), read_u32(12), read_u32(16), read_u32(20));
+    let (count_formats, formats_offset, count_modifiers, modifiers_offset) = match header {
+        (Some(a), Some(b), Some(c), Some(d)) => (a as usize, b as usize, c as usize, d as usize),
+        _ => return Vec::new(),
+    };
+
+    let formats = (0..count_formats)
+        .filter_map(|i| read_u32(formats_offset + i * 4))
+        .collect::<Vec<_>>();
+
+    let mut result = Vec::new();
+    for i in 0..count_modifiers {
+        let entry = modifiers_offset + i * 24;
+        let (mask, offset, modifier) = match (read_u64(entry), read_u32(entry + 8), read_u64(entry + 16)) {
+            (Some(mask), Some(offset), Some(modifier)) => (mask, offset as usize, modifier),
+            _ => break,
+        };
+
+        for bit in 0..64 {
+            if mask & (1 << bit) == 0 {
+                continue;
+            }
+
+            if let Some(&format) = formats.get(offset + bit) {
+                result.push(FormatModifier { format, modifier });
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_in_formats, FormatModifier};
+
+    const XRGB8888: u32 = 0x3432_5258;
+    const ARGB8888: u32 = 0x3432_5241;
+    const NV12: u32 = 0x3231_564e;
+    const LINEAR: u64 = 0;
+    const X_TILED: u64 = 0x0100_0000_0000_0001;
+    const Y_TILED: u64 = 0x0100_0000_0000_0002;
+
+    // drm_format_modifier_blob header, the formats at 24 and the modifiers at 40
+    fn in_formats(formats: &[u32], modifiers: &[(u64, u32, u64)]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        for &word in &[1, 0, formats.len() as u32, 24, modifiers.len() as u32, 40] {
+            blob.extend_from_slice(&word.to_ne_bytes());
+        }
+        for &format in formats {
+            blob.extend_from_slice(&format.to_ne_bytes());
+        }
+        blob.resize(40, 0);
+        for &(mask, offset, modifier) in modifiers {
+            blob.extend_from_slice(&mask.to_ne_bytes());
+            blob.extend_from_slice(&offset.to_ne_bytes());
+            blob.extend_from_slice(&[0; 4]);
+            blob.extend_from_slice(&modifier.to_ne_bytes());
+        }
+        blob
+    }
+
+    fn pair(format: u32, modifier: u64) -> FormatModifier {
+        FormatModifier { format, modifier }
+    }
+
+    #[test]
+    fn masks_select_formats_from_the_offset() {
+        let blob = in_formats(&[XRGB8888, ARGB8888, NV12], &[(0b111, 0, LINEAR), (0b011, 0, X_TILED), (0b1, 1, Y_TILED)]);
+
+        assert_eq!(parse_in_formats(&blob), vec![
+            pair(XRGB8888, LINEAR), pair(ARGB8888, LINEAR), pair(NV12, LINEAR),
+            pair(XRGB8888, X_TILED), pair(ARGB8888, X_TILED),
+            pair(ARGB8888, Y_TILED),
+        ]);
+    }
+
+    #[test]
+    fn modifier_high_bits_survive() {
+        let blob = in_formats(&[XRGB8888], &[(0b1, 0, 0x0200_0000_dead_beef)]);
+        assert_eq!(parse_in_formats(&blob), vec![pair(XRGB8888, 0x0200_0000_dead_beef)]);
+    }
+
+    #[test]
+    fn truncated_blobs_keep_the_complete_entries() {
+        let mut blob = in_formats(&[XRGB8888, NV12], &[(0b01, 0, LINEAR), (0b10, 0, X_TILED)]);
+        blob.truncate(blob.len() - 4);
+        assert_eq!(parse_in_formats(&blob), vec![pair(XRGB8888, LINEAR)]);
+
+        assert!(parse_in_formats(&blob[..16]).is_empty());
+    }
+}
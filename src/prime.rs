@@ -0,0 +1,336 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+use drm::control::{crtc, Mode};
+use drm::ffi;
+use gbm;
+
+use bo::{self, BufferLayout};
+use caps::Capabilities;
+use clock::FrameClock;
+use cursor::{Cursor, CursorImage};
+use device::Gpu;
+use display::Scanout;
+use dumb::DumbBuffer;
+use error::{Error, Result};
+use framebuffer::Framebuffer;
+use render::{RenderDevice, RenderTarget};
+
+// A buffer object shared as a dma-buf, the fd is closed on drop
+pub struct DmaBuf {
+    fd: File,
+    pub layout: BufferLayout,
+}
+
+impl AsRawFd for DmaBuf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub fn export<T: 'static>(bo: &gbm::BufferObject<T>) -> Result<DmaBuf> {
+    // gbm hands out a new fd on every call
+    let fd = bo.as_raw_fd();
+    if fd < 0 {
+        return Err(Error::gbm("export buffer object", io::Error::last_os_error()));
+    }
+
+    Ok(DmaBuf { fd: unsafe { File::from_raw_fd(fd) }, layout: bo::bo_layout(bo) })
+}
+
+// Framebuffer on gpu scanning out the dma-buf. The GEM handle is only needed
+// until the framebuffer holds its own reference.
+pub fn import(gpu: &Gpu, dmabuf: &DmaBuf) -> Result<Framebuffer> {
+    gpu.require(gpu.caps.prime_import, "PRIME import")?;
+    // without the modifier the display gpu would take a tiled buffer for linear
+    let mut layout = dmabuf.layout;
+    let tiled = layout.has_modifier() && layout.modifier != bo::MOD_LINEAR;
+    gpu.require(!tiled || gpu.caps.addfb2_modifiers, "scanout of tiled PRIME buffers")?;
+
+    let mut raw = ffi::drm_prime_handle { fd: dmabuf.as_raw_fd(), ..Default::default() };
+    unsafe { ffi::ioctl_prime_fd_to_handle(gpu.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("import dma-buf", 0u32, err))?;
+
+    // all planes of a gbm buffer object live in the one dma-buf
+    for plane in 0..layout.planes() {
+        layout.handles[plane] = raw.handle;
+    }
+    let framebuffer = Framebuffer::create(gpu, &layout);
+
+    let close = ffi::drm_gem_close { handle: raw.handle, pad: 0 };
+    if let Err(err) = unsafe { ffi::ioctl_gem_close(gpu.as_raw_fd(), &close) } {
+        eprintln!("{}", Error::ioctl("close gem handle", raw.handle, err));
+    }
+
+    framebuffer
+}
+
+enum PrimeBuffer {
+    // the display gpu scans out the render gpu's memory
+    Direct(Framebuffer),
+    // it can't, every frame gets copied over by the cpu
+    Copy(DumbBuffer),
+}
+
+// Double buffered output rendered on one gpu and shown on another, e.g. the
+// iGPU rendering for ports wired to the dGPU. Draw into back(), then present.
+pub struct PrimeSurface {
+    targets: Vec<RenderTarget>,
+    buffers: Vec<PrimeBuffer>,
+    front: usize,
+    crtc: crtc::Handle,
+    pub mode: Mode,
+    cursor: Option<Cursor>,
+    flip_pending: bool,
+    clock: FrameClock,
+}
+
+impl PrimeSurface {
+    pub fn new(render: &RenderDevice, gpu: &Gpu, crtc: crtc::Handle, mode: Mode) -> Result<PrimeSurface> {
+        let (width, height) = mode.size();
+        let render_caps = render.gbm_device().map(Capabilities::query).unwrap_or_default();
+
+        let mut surface = PrimeSurface { targets: Vec::new(), buffers: Vec::new(), front: 0, crtc, mode, cursor: None, flip_pending: false, clock: FrameClock::new(&mode) };
+        for _ in 0..2 {
+            let result = render.create_target(u32::from(width), u32::from(height), gbm::Format::XRGB8888)
+                .and_then(|target| {
+                    let buffer = scanout_buffer(gpu, &target, &render_caps);
+                    surface.targets.push(target);
+                    buffer
+                });
+
+            match result {
+                Ok(buffer) => surface.buffers.push(buffer),
+                Err(err) => {
+                    surface.destroy(render);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(surface)
+    }
+
+    // The target that isn't on screen, bind it and draw while no flip is pending
+    pub fn back(&self) -> &RenderTarget {
+        &self.targets[1 - self.front]
+    }
+
+    // False when the frames take the detour through a copy
+    pub fn is_direct(&self) -> bool {
+        self.buffers.iter().all(|buffer| match *buffer {
+            PrimeBuffer::Direct(_) => true,
+            PrimeBuffer::Copy(_) => false,
+        })
+    }
+
+    pub fn queue_present(&mut self, render: &RenderDevice, gpu: &Gpu) -> Result<()> {
+        if self.flip_pending {
+            return Err(Error::FlipPending { crtc: self.crtc.into() });
+        }
+
+        // nothing fences the display gpu against the render gpu, the frame
+        // has to be done before it's scanned out or copied
+        render.finish();
+
+        let back = 1 - self.front;
+        if let PrimeBuffer::Copy(ref mut dumb) = self.buffers[back] {
+            copy(render, &self.targets[back], dumb)?;
+        }
+
+        self.front = back;
+        let now = gpu.now();
+        if let Err(err) = gpu.page_flip(self.crtc, self) {
+            self.front = 1 - self.front;
+            return Err(err);
+        }
+
+        self.clock.flip_queued(now);
+        self.flip_pending = true;
+        Ok(())
+    }
+
+    pub fn is_flip_pending(&self) -> bool {
+        self.flip_pending
+    }
+
+    pub fn page_flip_complete(&mut self, timestamp: Duration) {
+        self.flip_pending = false;
+        self.clock.flip_complete(timestamp);
+    }
+
+    pub fn clock(&self) -> &FrameClock {
+        &self.clock
+    }
+
+    pub fn set_vrr(&mut self, gpu: &Gpu, enabled: bool) -> Result<()> {
+        gpu.set_vrr(self.crtc, enabled)?;
+        self.clock.set_vrr(enabled);
+        Ok(())
+    }
+
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+
+    // The cursor lives on the display gpu, a composited one has to be drawn
+    // into back() on the render gpu
+    pub fn set_cursor(&mut self, gpu: &Gpu, image: CursorImage) -> Result<()> {
+        let crtc = self.crtc;
+        self.cursor
+            .get_or_insert_with(|| Cursor::new(gpu, crtc))
+            .set_image(gpu, image)
+    }
+
+    pub fn move_cursor(&mut self, gpu: &Gpu, x: i32, y: i32) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.move_to(gpu, x, y),
+            None => Ok(()),
+        }
+    }
+
+    pub fn hide_cursor(&mut self, gpu: &Gpu) -> Result<()> {
+        match self.cursor {
+            Some(ref mut cursor) => cursor.hide(gpu),
+            None => Ok(()),
+        }
+    }
+
+    // The GL objects belong to the render device's context
+    pub fn destroy(mut self, render: &RenderDevice) {
+        self.buffers.clear();
+        for target in self.targets.drain(..) {
+            target.destroy(render);
+        }
+    }
+}
+
+// Only the render device's context can delete the GL objects, without
+// destroy() they stay around until the context goes
+impl Drop for PrimeSurface {
+    fn drop(&mut self) {
+        if !self.targets.is_empty() {
+            eprintln!("[gpu] PRIME surface of crtc {} dropped without destroy(), leaking its render targets", u32::from(self.crtc));
+        }
+    }
+}
+
+impl Scanout for PrimeSurface {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn framebuffer(&self) -> Option<&Framebuffer> {
+        match *self.buffers.get(self.front)? {
+            PrimeBuffer::Direct(ref framebuffer) => Some(framebuffer),
+            PrimeBuffer::Copy(ref dumb) => dumb.framebuffer(),
+        }
+    }
+
+    fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+}
+
+// The targets are linear, but VRAM only buffers usually still can't be
+// imported. Those and drivers without PRIME get a copy.
+fn scanout_buffer(gpu: &Gpu, target: &RenderTarget, render_caps: &Capabilities) -> Result<PrimeBuffer> {
+    let direct = if render_caps.prime_export {
+        export(target.buffer_object()).and_then(|dmabuf| import(gpu, &dmabuf))
+    } else {
+        Err(Error::Unsupported { driver: render_caps.driver.name.clone(), feature: "PRIME export" })
+    };
+
+    match direct {
+        Ok(framebuffer) => Ok(PrimeBuffer::Direct(framebuffer)),
+        Err(err) => {
+            eprintln!("[gpu] falling back to copying frames between gpus: {}", err);
+            Ok(PrimeBuffer::Copy(DumbBuffer::new(gpu, target.width, target.height)?))
+        }
+    }
+}
+
+fn copy(render: &RenderDevice, target: &RenderTarget, dumb: &mut DumbBuffer) -> Result<()> {
+    let device = render.gbm_device().ok_or(Error::NoGbmDevice)?;
+    let (width, height) = (target.width, target.height);
+
+    target.buffer_object()
+        .map(device, 0, 0, width, height, |mapped| dumb.copy_from(mapped.buffer(), mapped.stride() as usize))
+        .map_err(|_| Error::DeviceDestroyed)?
+        .map_err(|err| Error::gbm("map buffer object", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::rc::Rc;
+
+    use libc;
+
+    use bo::{self, BufferLayout};
+    use device::Gpu;
+    use error::{Error, Result};
+    use fake::FakeDevice;
+    use super::{import, DmaBuf};
+
+    const XRGB8888: u32 = 0x3432_5258;
+    const X_TILED: u64 = 0x0100_0000_0000_0001;
+
+    fn dmabuf(modifier: u64) -> DmaBuf {
+        let layout = BufferLayout {
+            width: 64,
+            height: 64,
+            format: XRGB8888,
+            modifier,
+            handles: [1, 0, 0, 0],
+            pitches: [256, 0, 0, 0],
+            offsets: [0; 4],
+        };
+        DmaBuf { fd: File::open("/dev/null").unwrap(), layout }
+    }
+
+    fn import_error(gpu: &Gpu, modifier: u64) -> Error {
+        let result: Result<_> = import(gpu, &dmabuf(modifier));
+        match result {
+            Ok(_) => panic!("imported a dma-buf into the fake"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn import_needs_prime_import() {
+        let gpu = Gpu::new(Rc::new(FakeDevice::new()), None).unwrap();
+
+        match import_error(&gpu, bo::MOD_LINEAR) {
+            Error::Unsupported { feature, .. } => assert_eq!(feature, "PRIME import"),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn tiled_buffers_need_modifier_support() {
+        let mut gpu = Gpu::new(Rc::new(FakeDevice::new()), None).unwrap();
+        gpu.caps.prime_import = true;
+
+        match import_error(&gpu, X_TILED) {
+            Error::Unsupported { feature, .. } => assert_eq!(feature, "scanout of tiled PRIME buffers"),
+            err => panic!("unexpected error: {}", err),
+        }
+
+        // linear and implicit layouts get as far as the kernel, which the fake doesn't have
+        for &modifier in &[bo::MOD_LINEAR, bo::MOD_INVALID] {
+            match import_error(&gpu, modifier) {
+                Error::Drm { operation, errno, .. } => assert_eq!((operation, errno), ("import dma-buf", libc::EBADF)),
+                err => panic!("unexpected error: {}", err),
+            }
+        }
+
+        gpu.caps.addfb2_modifiers = true;
+        match import_error(&gpu, X_TILED) {
+            Error::Drm { operation, .. } => assert_eq!(operation, "import dma-buf"),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+}
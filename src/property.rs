@@ -0,0 +1,327 @@
+use std::ffi::CStr;
+use std::os::unix::io::AsRawFd;
+
+use drm::ffi;
+
+use error::{Error, Result};
+use kms::KmsDevice;
+
+pub fn object_properties<D: AsRawFd>(device: &D, object: u32, object_type: u32) -> Result<Vec<(u32, u64)>> {
+    let mut raw = ffi::drm_mode_obj_get_properties { obj_id: object, obj_type: object_type, ..Default::default() };
+
+    unsafe { ffi::ioctl_mode_obj_getproperties(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get object properties", object, err))?;
+
+    let mut ids = vec![0u32; raw.count_props as usize];
+    let mut values = vec![0u64; raw.count_props as usize];
+    raw.props_ptr = ids.as_mut_ptr() as u64;
+    raw.prop_values_ptr = values.as_mut_ptr() as u64;
+
+    unsafe { ffi::ioctl_mode_obj_getproperties(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get object properties", object, err))?;
+
+    Ok(ids.into_iter().zip(values).collect())
+}
+
+pub fn property_name<D: AsRawFd>(device: &D, property: u32) -> Result<String> {
+    let mut raw = ffi::drm_mode_get_property { prop_id: property, ..Default::default() };
+
+    unsafe { ffi::ioctl_mode_getproperty(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get property", property, err))?;
+
+    let name = unsafe { CStr::from_ptr(raw.name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+// Returns the property id and its current value on the object
+pub fn find_property<D: AsRawFd>(device: &D, object: u32, object_type: u32, name: &str) -> Result<Option<(u32, u64)>> {
+    for (property, value) in object_properties(device, object, object_type)? {
+        if property_name(device, property)? == name {
+            return Ok(Some((property, value)));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn create_blob<D: AsRawFd>(device: &D, data: &[u8]) -> Result<u32> {
+    let mut raw = ffi::drm_mode_create_blob {
+        data: data.as_ptr() as u64,
+        length: data.len() as u32,
+        ..Default::default()
+    };
+
+    unsafe { ffi::ioctl_mode_createpropblob(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("create property blob", 0u32, err))?;
+
+    Ok(raw.blob_id)
+}
+
+pub fn destroy_blob<D: AsRawFd>(device: &D, blob: u32) -> Result<()> {
+    let mut raw = ffi::drm_mode_destroy_blob { blob_id: blob };
+
+    unsafe { ffi::ioctl_mode_destroypropblob(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("destroy property blob", blob, err))?;
+
+    Ok(())
+}
+
+pub fn get_blob<D: AsRawFd>(device: &D, blob: u32) -> Result<Vec<u8>> {
+    let mut raw = ffi::drm_mode_get_blob { blob_id: blob, ..Default::default() };
+
+    unsafe { ffi::ioctl_mode_getpropblob(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get property blob", blob, err))?;
+
+    let mut data = vec![0u8; raw.length as usize];
+    raw.data = data.as_mut_ptr() as u64;
+
+    unsafe { ffi::ioctl_mode_getpropblob(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get property blob", blob, err))?;
+
+    Ok(data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Connector,
+    Crtc,
+    Plane,
+}
+
+impl ObjectType {
+    pub fn raw(self) -> u32 {
+        match self {
+            ObjectType::Connector => ffi::DRM_MODE_OBJECT_CONNECTOR,
+            ObjectType::Crtc => ffi::DRM_MODE_OBJECT_CRTC,
+            ObjectType::Plane => ffi::DRM_MODE_OBJECT_PLANE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyKind {
+    Range { min: u64, max: u64 },
+    SignedRange { min: i64, max: i64 },
+    // (value, name) pairs, for bitmasks the value is the bit index
+    Enum(Vec<(u64, String)>),
+    Bitmask(Vec<(u64, String)>),
+    Blob,
+    Object(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Unsigned(u64),
+    Signed(i64),
+    Enum(Option<String>),
+    Bitmask(Vec<String>),
+    Blob(Option<Vec<u8>>),
+    Object(Option<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyInfo {
+    pub id: u32,
+    pub name: String,
+    pub kind: PropertyKind,
+    pub immutable: bool,
+    pub atomic_only: bool,
+}
+
+impl PropertyInfo {
+    pub fn decode(&self, raw: u64) -> PropertyValue {
+        match self.kind {
+            PropertyKind::Range { .. } => PropertyValue::Unsigned(raw),
+            PropertyKind::SignedRange { .. } => PropertyValue::Signed(raw as i64),
+            PropertyKind::Enum(ref entries) => {
+                PropertyValue::Enum(entries.iter().find(|&&(v, _)| v == raw).map(|(_, name)| name.clone()))
+            }
+            PropertyKind::Bitmask(ref entries) => PropertyValue::Bitmask(
+                entries.iter().filter(|&&(bit, _)| bit < 64 && raw & (1 << bit) != 0).map(|(_, name)| name.clone()).collect()
+            ),
+            // blobs are fetched separately, see Property::load
+            PropertyKind::Blob => PropertyValue::Blob(None),
+            PropertyKind::Object(_) => PropertyValue::Object(if raw == 0 { None } else { Some(raw as u32) }),
+        }
+    }
+
+    // Raw value of an enum entry, or of a bitmask with the named bits set
+    pub fn encode(&self, names: &[&str]) -> Option<u64> {
+        match self.kind {
+            PropertyKind::Enum(ref entries) if names.len() == 1 => {
+                entries.iter().find(|(_, name)| name == names[0]).map(|&(v, _)| v)
+            }
+            PropertyKind::Bitmask(ref entries) => names.iter().try_fold(0u64, |mask, wanted| {
+                entries.iter().find(|&&(bit, ref name)| bit < 64 && name == wanted).map(|&(bit, _)| mask | 1 << bit)
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn accepts(&self, raw: u64) -> bool {
+        match self.kind {
+            PropertyKind::Range { min, max } => raw >= min && raw <= max,
+            PropertyKind::SignedRange { min, max } => raw as i64 >= min && raw as i64 <= max,
+            PropertyKind::Enum(ref entries) => entries.iter().any(|&(v, _)| v == raw),
+            PropertyKind::Bitmask(ref entries) => {
+                let all = entries.iter().filter(|&&(bit, _)| bit < 64).fold(0u64, |mask, &(bit, _)| mask | 1 << bit);
+                raw & !all == 0
+            }
+            PropertyKind::Blob | PropertyKind::Object(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub info: PropertyInfo,
+    pub raw: u64,
+    pub value: PropertyValue,
+}
+
+impl Property {
+    pub fn load(kms: &dyn KmsDevice, id: u32, raw: u64) -> Result<Property> {
+        let info = kms.property_info(id)?;
+        let value = match info.kind {
+            PropertyKind::Blob if raw != 0 => PropertyValue::Blob(Some(kms.get_blob(raw as u32)?)),
+            _ => info.decode(raw),
+        };
+
+        Ok(Property { info, raw, value })
+    }
+}
+
+pub fn property_info<D: AsRawFd>(device: &D, property: u32) -> Result<PropertyInfo> {
+    let mut raw = ffi::drm_mode_get_property { prop_id: property, ..Default::default() };
+
+    unsafe { ffi::ioctl_mode_getproperty(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get property", property, err))?;
+
+    let mut values = vec![0u64; raw.count_values as usize];
+    let mut enums = vec![ffi::drm_mode_property_enum::default(); raw.count_enum_blobs as usize];
+    let is_enum = raw.flags & (ffi::DRM_MODE_PROP_ENUM | ffi::DRM_MODE_PROP_BITMASK) != 0;
+    raw.values_ptr = values.as_mut_ptr() as u64;
+    // blob properties report blob ids in there on old kernels, we don't want them
+    if is_enum {
+        raw.enum_blob_ptr = enums.as_mut_ptr() as u64;
+    } else {
+        raw.count_enum_blobs = 0;
+    }
+
+    unsafe { ffi::ioctl_mode_getproperty(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("get property", property, err))?;
+
+    let entries = || {
+        enums.iter()
+            .map(|e| (e.value, unsafe { CStr::from_ptr(e.name.as_ptr()) }.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>()
+    };
+    let value = |index: usize| values.get(index).cloned().unwrap_or(0);
+
+    let kind = match raw.flags & (ffi::DRM_MODE_PROP_LEGACY_TYPE | ffi::DRM_MODE_PROP_EXTENDED_TYPE) {
+        ffi::DRM_MODE_PROP_RANGE => PropertyKind::Range { min: value(0), max: value(1) },
+        ffi::DRM_MODE_PROP_SIGNED_RANGE => PropertyKind::SignedRange { min: value(0) as i64, max: value(1) as i64 },
+        ffi::DRM_MODE_PROP_ENUM => PropertyKind::Enum(entries()),
+        ffi::DRM_MODE_PROP_BITMASK => PropertyKind::Bitmask(entries()),
+        ffi::DRM_MODE_PROP_BLOB => PropertyKind::Blob,
+        ffi::DRM_MODE_PROP_OBJECT => PropertyKind::Object(value(0) as u32),
+        _ => PropertyKind::Range { min: 0, max: u64::MAX },
+    };
+
+    let name = unsafe { CStr::from_ptr(raw.name.as_ptr()) };
+    Ok(PropertyInfo {
+        id: property,
+        name: name.to_string_lossy().into_owned(),
+        kind,
+        immutable: raw.flags & ffi::DRM_MODE_PROP_IMMUTABLE != 0,
+        atomic_only: raw.flags & ffi::DRM_MODE_PROP_ATOMIC != 0,
+    })
+}
+
+// Legacy path, works without the atomic client cap but only for properties
+// the driver exposes to non-atomic clients
+pub fn set_property<D: AsRawFd>(device: &D, object: u32, object_type: u32, property: u32, value: u64) -> Result<()> {
+    let mut raw = ffi::drm_mode_obj_set_property { obj_id: object, obj_type: object_type, prop_id: property, value };
+
+    unsafe { ffi::ioctl_mode_obj_setproperty(device.as_raw_fd(), &mut raw) }
+        .map_err(|err| Error::ioctl("set property", object, err))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PropertyInfo, PropertyKind, PropertyValue};
+
+    fn info(kind: PropertyKind) -> PropertyInfo {
+        PropertyInfo { id: 1, name: "test".to_owned(), kind, immutable: false, atomic_only: false }
+    }
+
+    fn entries(names: &[(u64, &str)]) -> Vec<(u64, String)> {
+        names.iter().map(|&(value, name)| (value, name.to_owned())).collect()
+    }
+
+    #[test]
+    fn decode_follows_the_kind() {
+        assert_eq!(info(PropertyKind::Range { min: 0, max: 16 }).decode(8), PropertyValue::Unsigned(8));
+        assert_eq!(info(PropertyKind::SignedRange { min: -10, max: 10 }).decode(-3i64 as u64), PropertyValue::Signed(-3));
+        assert_eq!(info(PropertyKind::Object(0)).decode(0), PropertyValue::Object(None));
+        assert_eq!(info(PropertyKind::Object(0)).decode(42), PropertyValue::Object(Some(42)));
+        assert_eq!(info(PropertyKind::Blob).decode(7), PropertyValue::Blob(None));
+
+        let dpms = info(PropertyKind::Enum(entries(&[(0, "On"), (3, "Off")])));
+        assert_eq!(dpms.decode(3), PropertyValue::Enum(Some("Off".to_owned())));
+        assert_eq!(dpms.decode(1), PropertyValue::Enum(None));
+    }
+
+    #[test]
+    fn bitmasks_decode_by_bit_index() {
+        let rotation = info(PropertyKind::Bitmask(entries(&[(0, "rotate-0"), (2, "rotate-180"), (4, "reflect-x"), (63, "top"), (64, "bogus")])));
+
+        assert_eq!(rotation.decode(0), PropertyValue::Bitmask(Vec::new()));
+        assert_eq!(rotation.decode(0b10100), PropertyValue::Bitmask(vec!["rotate-180".to_owned(), "reflect-x".to_owned()]));
+        assert_eq!(rotation.decode(1 << 63 | 0b10), PropertyValue::Bitmask(vec!["top".to_owned()]));
+
+        assert_eq!(rotation.encode(&["rotate-180", "reflect-x"]), Some(0b10100));
+        assert_eq!(rotation.encode(&["top"]), Some(1 << 63));
+        assert_eq!(rotation.encode(&[]), Some(0));
+        // bits past 63 can't be expressed, the kernel never hands them out
+        assert_eq!(rotation.encode(&["bogus"]), None);
+        assert_eq!(rotation.encode(&["rotate-0", "rotate-90"]), None);
+
+        assert!(rotation.accepts(0b10101));
+        assert!(rotation.accepts(1 << 63));
+        assert!(!rotation.accepts(0b10));
+    }
+
+    #[test]
+    fn enums_encode_a_single_entry() {
+        let scaling = info(PropertyKind::Enum(entries(&[(0, "None"), (1, "Full"), (3, "Full aspect")])));
+
+        assert_eq!(scaling.encode(&["Full aspect"]), Some(3));
+        assert_eq!(scaling.encode(&["None", "Full"]), None);
+        assert_eq!(scaling.encode(&["Center"]), None);
+        assert_eq!(info(PropertyKind::Range { min: 0, max: 1 }).encode(&["1"]), None);
+
+        assert!(scaling.accepts(3));
+        assert!(!scaling.accepts(2));
+    }
+
+    #[test]
+    fn ranges_accept_their_bounds() {
+        let bpc = info(PropertyKind::Range { min: 6, max: 16 });
+        assert!(bpc.accepts(6) && bpc.accepts(16));
+        assert!(!bpc.accepts(5) && !bpc.accepts(17));
+
+        // signed values come in two's complement
+        let offset = info(PropertyKind::SignedRange { min: -10, max: 10 });
+        assert!(offset.accepts(-10i64 as u64) && offset.accepts(10));
+        assert!(!offset.accepts(-11i64 as u64) && !offset.accepts(11));
+        assert!(!offset.accepts(u64::MAX / 2 + 1));
+
+        let any = info(PropertyKind::SignedRange { min: i64::MIN, max: i64::MAX });
+        assert!(any.accepts(u64::MAX) && any.accepts(1 << 63));
+
+        assert!(info(PropertyKind::Blob).accepts(u64::MAX));
+    }
+}
@@ -0,0 +1,280 @@
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use cognitive_graphics::attributes::DmabufAttributes;
+use cognitive_graphics::egl_tools::{self, HwImage};
+use egl;
+use gbm;
+use gbm::AsRaw;
+use gl;
+use libc;
+use udev;
+
+use device::DeviceFile;
+use error::{Error, Result};
+
+const PLATFORM_SURFACELESS_MESA: egl::EGLenum = 0x31DD;
+const SURFACELESS_CONTEXT_EXT: &str = "EGL_KHR_surfaceless_context";
+const DMA_BUF_IMPORT_EXT: &str = "EGL_EXT_image_dma_buf_import";
+
+// Render nodes need neither DRM master nor a VT, anyone in the render group
+// can open them
+pub fn render_nodes() -> Result<Vec<PathBuf>> {
+    let context = udev::Context::new()?;
+    let mut enumerator = udev::Enumerator::new(&context)?;
+    enumerator.match_subsystem("drm")?;
+    enumerator.match_sysname("renderD[0-9]*")?;
+
+    let mut nodes = enumerator.scan_devices()?
+        .filter_map(|device| device.devnode().map(|path| path.to_owned()))
+        .collect::<Vec<_>>();
+
+    nodes.sort();
+    Ok(nodes)
+}
+
+// GL context without any KMS objects, for offscreen jobs and CI
+pub struct RenderDevice {
+    gbm_device: Option<gbm::Device<DeviceFile>>,
+    path: Option<PathBuf>,
+    egl_display: egl::EGLDisplay,
+    egl_context: egl::EGLContext,
+}
+
+impl RenderDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RenderDevice> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).open(path)
+            .map_err(|error| Error::Open { path: path.to_owned(), error })?;
+        let gbm_device = gbm::Device::new(DeviceFile::from(file))
+            .map_err(|err| Error::gbm("create device", err))?;
+
+        let egl_display = egl_tools::get_gbm_display(gbm_device.as_raw() as _)
+            .map_err(|_| Error::egl("get gbm display"))?;
+
+        let (egl_display, egl_context) = create_context(egl_display)?;
+        Ok(RenderDevice { gbm_device: Some(gbm_device), path: Some(path.to_owned()), egl_display, egl_context })
+    }
+
+    // Mesa's surfaceless platform, ends up on llvmpipe when there's no gpu.
+    // There's no gbm device behind it, so only plain GL framebuffers work.
+    pub fn surfaceless() -> Result<RenderDevice> {
+        let get_platform_display = egl_tools::get_proc_addr_of_get_platform_display()
+            .ok_or_else(|| unsupported(egl::EGL_NO_DISPLAY, "eglGetPlatformDisplayEXT"))?;
+
+        let egl_display = get_platform_display(PLATFORM_SURFACELESS_MESA, egl::EGL_DEFAULT_DISPLAY, ptr::null());
+        if egl_display.is_null() {
+            return Err(Error::egl("get surfaceless display"));
+        }
+
+        let (egl_display, egl_context) = create_context(egl_display)?;
+        Ok(RenderDevice { gbm_device: None, path: None, egl_display, egl_context })
+    }
+
+    // First render node that works, surfaceless when none does
+    pub fn open_default() -> Result<RenderDevice> {
+        for node in render_nodes()? {
+            match RenderDevice::open(&node) {
+                Ok(device) => return Ok(device),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+
+        RenderDevice::surfaceless()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn gbm_device(&self) -> Option<&gbm::Device<DeviceFile>> {
+        self.gbm_device.as_ref()
+    }
+
+    pub fn make_current(&self) -> Result<()> {
+        if !egl::make_current(self.egl_display, egl::EGL_NO_SURFACE, egl::EGL_NO_SURFACE, self.egl_context) {
+            return Err(Error::egl("make current"));
+        }
+
+        Ok(())
+    }
+
+    // A gbm buffer object bound as the color buffer of a GL framebuffer. It's
+    // linear, slower to render into but another gpu can scan it out and the
+    // cpu can read it without knowing this one's tiling.
+    pub fn create_target(&self, width: u32, height: u32, format: gbm::Format) -> Result<RenderTarget> {
+        let gbm_device = self.gbm_device.as_ref().ok_or(Error::NoGbmDevice)?;
+        if !egl_tools::has_extension(self.egl_display, DMA_BUF_IMPORT_EXT) {
+            return Err(unsupported(self.egl_display, DMA_BUF_IMPORT_EXT));
+        }
+
+        let flags = gbm::BufferObjectFlags::RENDERING | gbm::BufferObjectFlags::LINEAR;
+        let bo = gbm_device.create_buffer_object::<()>(width, height, format, flags)
+            .map_err(|err| Error::gbm("create buffer object", err))?;
+        let stride = bo.stride().map_err(|_| Error::DeviceDestroyed)?;
+
+        // gbm hands out a new dma-buf fd every time, EGL keeps its own copy
+        let fd = bo.as_raw_fd();
+        if fd < 0 {
+            return Err(Error::gbm("export buffer object", ::std::io::Error::last_os_error()));
+        }
+
+        let mut attributes = DmabufAttributes::new();
+        attributes.create(width as i32, height as i32, format.as_ffi(), 0);
+        attributes.add(0, fd, 0, stride, 0, 0);
+        let image = egl_tools::import_dmabuf(self.egl_display, &attributes);
+        unsafe { libc::close(fd) };
+        let image = image.ok_or_else(|| Error::egl("create image"))?;
+
+        self.make_current()?;
+        let image_target_storage = egl_tools::get_proc_addr_of_image_target_render_storage_oes()
+            .ok_or_else(|| unsupported(self.egl_display, "glEGLImageTargetRenderbufferStorageOES"))?;
+
+        let (mut renderbuffer, mut framebuffer) = (0, 0);
+        let status = unsafe {
+            gl::GenRenderbuffers(1, &mut renderbuffer);
+            gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+            image_target_storage(gl::RENDERBUFFER, image.as_raw());
+
+            gl::GenFramebuffers(1, &mut framebuffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+            gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, renderbuffer);
+            gl::CheckFramebufferStatus(gl::FRAMEBUFFER)
+        };
+
+        let target = RenderTarget { bo, image, renderbuffer, framebuffer, width, height };
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            target.destroy(self);
+            // the driver can't render into this kind of image
+            return Err(unsupported(self.egl_display, "rendering into dma-buf images"));
+        }
+
+        Ok(target)
+    }
+
+    pub fn egl_extensions(&self) -> Vec<String> {
+        egl::query_string(self.egl_display, egl::EGL_EXTENSIONS)
+            .map(|extensions| extensions.to_string_lossy().split_whitespace().map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    // GL_VERSION, GL_RENDERER, GL_EXTENSIONS and so on of our context
+    pub fn gl_string(&self, name: gl::types::GLenum) -> Option<String> {
+        self.make_current().ok()?;
+        let raw = unsafe { gl::GetString(name) };
+        if raw.is_null() {
+            return None;
+        }
+
+        Some(unsafe { CStr::from_ptr(raw as _) }.to_string_lossy().into_owned())
+    }
+
+    // Blocks until the gpu is done, the buffer object holds the frame after this
+    pub fn finish(&self) {
+        unsafe { gl::Finish() };
+    }
+}
+
+impl Drop for RenderDevice {
+    fn drop(&mut self) {
+        egl::make_current(self.egl_display, egl::EGL_NO_SURFACE, egl::EGL_NO_SURFACE, egl::EGL_NO_CONTEXT);
+        egl::destroy_context(self.egl_display, self.egl_context);
+        egl::terminate(self.egl_display);
+    }
+}
+
+pub struct RenderTarget {
+    bo: gbm::BufferObject<()>,
+    image: HwImage,
+    renderbuffer: gl::types::GLuint,
+    framebuffer: gl::types::GLuint,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RenderTarget {
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+            gl::Viewport(0, 0, self.width as i32, self.height as i32);
+        }
+    }
+
+    pub fn buffer_object(&self) -> &gbm::BufferObject<()> {
+        &self.bo
+    }
+
+    pub fn destroy(self, device: &RenderDevice) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteRenderbuffers(1, &self.renderbuffer);
+        }
+
+        if egl_tools::destroy_image(device.egl_display, self.image).is_err() {
+            eprintln!("{}", Error::egl("destroy image"));
+        }
+    }
+}
+
+// EGL features are up to the EGL vendor, not the kernel driver
+fn unsupported(egl_display: egl::EGLDisplay, feature: &'static str) -> Error {
+    let driver = egl::query_string(egl_display, egl::EGL_VENDOR)
+        .map(|vendor| vendor.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "EGL".to_owned());
+    Error::Unsupported { driver, feature }
+}
+
+// The display is terminated again when no context can be made on it
+fn create_context(egl_display: egl::EGLDisplay) -> Result<(egl::EGLDisplay, egl::EGLContext)> {
+    let mut maj: egl::EGLint = 0;
+    let mut min: egl::EGLint = 0;
+    if !egl::initialize(egl_display, &mut maj, &mut min) {
+        return Err(Error::egl("initialize"));
+    }
+
+    match make_context(egl_display) {
+        Ok(egl_context) => Ok((egl_display, egl_context)),
+        Err(err) => {
+            egl::terminate(egl_display);
+            Err(err)
+        }
+    }
+}
+
+fn make_context(egl_display: egl::EGLDisplay) -> Result<egl::EGLContext> {
+    if !egl_tools::has_extension(egl_display, SURFACELESS_CONTEXT_EXT) {
+        return Err(unsupported(egl_display, SURFACELESS_CONTEXT_EXT));
+    }
+
+    if !egl::bind_api(egl::EGL_OPENGL_API) {
+        return Err(Error::egl("bind OpenGL api"));
+    }
+
+    const CONFIG_ATTRIBS: [egl::EGLint; 3] = [
+        egl::EGL_RENDERABLE_TYPE, egl::EGL_OPENGL_BIT,
+        egl::EGL_NONE
+    ];
+
+    let config = egl::choose_config(egl_display, &CONFIG_ATTRIBS, 1)
+        .ok_or_else(|| Error::egl("choose config"))?;
+
+    const CONTEXT_ATTRIB_LIST: [egl::EGLint; 3] = [
+        egl::EGL_CONTEXT_CLIENT_VERSION, 2,
+        egl::EGL_NONE
+    ];
+
+    let egl_context = egl::create_context(egl_display, config, egl::EGL_NO_CONTEXT, &CONTEXT_ATTRIB_LIST)
+        .ok_or_else(|| Error::egl("create context"))?;
+
+    if !egl::make_current(egl_display, egl::EGL_NO_SURFACE, egl::EGL_NO_SURFACE, egl_context) {
+        let err = Error::egl("make current");
+        egl::destroy_context(egl_display, egl_context);
+        return Err(err);
+    }
+    gl::load_with(|s| egl::get_proc_address(s) as *const ::std::os::raw::c_void);
+
+    Ok(egl_context)
+}
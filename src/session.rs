@@ -0,0 +1,138 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use libc;
+
+use error::{Error, Result};
+
+// linux/vt.h, the libc crate doesn't carry the VT ioctls
+#[repr(C)]
+#[derive(Default)]
+struct VtMode {
+    mode: libc::c_char,
+    waitv: libc::c_char,
+    relsig: libc::c_short,
+    acqsig: libc::c_short,
+    frsig: libc::c_short,
+}
+
+const VT_GETMODE: libc::c_ulong = 0x5601;
+const VT_SETMODE: libc::c_ulong = 0x5602;
+const VT_RELDISP: libc::c_ulong = 0x5605;
+const VT_AUTO: libc::c_char = 0;
+const VT_PROCESS: libc::c_char = 1;
+const VT_ACKACQ: libc::c_int = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    // switching away, master has to be dropped before acknowledging
+    Pause,
+    // our VT is in front again
+    Resume,
+}
+
+// VT switching when we run straight on a VT. The kernel asks with SIGUSR1
+// before switching away and waits until we let it, SIGUSR2 says we're back.
+// Both only ever reach us through a signalfd.
+pub struct Session {
+    tty: File,
+    signals: File,
+}
+
+impl Session {
+    // Fails when the controlling terminal isn't a VT, e.g. over ssh
+    pub fn open() -> Result<Session> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")
+            .map_err(|err| Error::session("open /dev/tty", err))?;
+
+        let mut mode = VtMode::default();
+        if unsafe { libc::ioctl(tty.as_raw_fd(), VT_GETMODE, &mut mode) } < 0 {
+            return Err(Error::session("get VT mode", io::Error::last_os_error()));
+        }
+
+        let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+        let fd = unsafe {
+            libc::sigemptyset(&mut mask);
+            libc::sigaddset(&mut mask, libc::SIGUSR1);
+            libc::sigaddset(&mut mask, libc::SIGUSR2);
+            libc::sigprocmask(libc::SIG_BLOCK, &mask, ptr::null_mut());
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(Error::session("create signalfd", io::Error::last_os_error()));
+        }
+        let signals = unsafe { File::from_raw_fd(fd) };
+
+        let mode = VtMode {
+            mode: VT_PROCESS,
+            relsig: libc::SIGUSR1 as libc::c_short,
+            acqsig: libc::SIGUSR2 as libc::c_short,
+            ..Default::default()
+        };
+        if unsafe { libc::ioctl(tty.as_raw_fd(), VT_SETMODE, &mode) } < 0 {
+            return Err(Error::session("set VT mode", io::Error::last_os_error()));
+        }
+
+        Ok(Session { tty, signals })
+    }
+
+    // Drains the signals that came in without blocking
+    pub fn dispatch(&self) -> Result<Vec<SessionEvent>> {
+        let mut events = Vec::new();
+
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::signalfd_siginfo>();
+            let read = unsafe { libc::read(self.signals.as_raw_fd(), &mut info as *mut _ as *mut libc::c_void, size) };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(Error::session("read signalfd", error)),
+                }
+            }
+
+            match info.ssi_signo as libc::c_int {
+                libc::SIGUSR1 => events.push(SessionEvent::Pause),
+                libc::SIGUSR2 => events.push(SessionEvent::Resume),
+                _ => {}
+            }
+        }
+
+        Ok(events)
+    }
+
+    // Lets the kernel finish the switch, for a Pause only once master is gone
+    pub fn acknowledge(&self, event: SessionEvent) -> Result<()> {
+        let reply = match event {
+            SessionEvent::Pause => 1,
+            SessionEvent::Resume => VT_ACKACQ,
+        };
+
+        if unsafe { libc::ioctl(self.tty.as_raw_fd(), VT_RELDISP, reply) } < 0 {
+            return Err(Error::session("release VT", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+// Readable when a switch is waiting to be dispatched
+impl AsRawFd for Session {
+    fn as_raw_fd(&self) -> RawFd {
+        self.signals.as_raw_fd()
+    }
+}
+
+// Hands VT switching back to the kernel
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mode = VtMode { mode: VT_AUTO, ..Default::default() };
+        if unsafe { libc::ioctl(self.tty.as_raw_fd(), VT_SETMODE, &mode) } < 0 {
+            eprintln!("{}", Error::session("reset VT mode", io::Error::last_os_error()));
+        }
+    }
+}